@@ -0,0 +1,704 @@
+use crate::encoder::encode;
+use crate::parse::is_label;
+use crate::tokenizer::{parse_trap, Flags, Instruction, Operand, Operation};
+use std::collections::HashMap;
+use ux::{i3, i7, u3};
+
+// result of assembling one source file
+pub struct Program {
+    pub origin: u16,
+    pub words: Vec<u16>,
+    // labels in the order they were defined
+    pub symbols: Vec<(String, u16)>,
+    // the address of every statement that assembled to words, with its source line
+    pub lines: Vec<(u16, usize)>,
+}
+
+impl Program {
+    // the image format read by the loader, the origin followed by the words, all big endian
+    pub fn to_image_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.words.len() * 2 + 2);
+        out.extend_from_slice(&self.origin.to_be_bytes());
+        for word in &self.words {
+            out.extend_from_slice(&word.to_be_bytes());
+        }
+        out
+    }
+
+    // one `LABEL 0x3000` line per symbol
+    pub fn symbol_table(&self) -> String {
+        self.symbols
+            .iter()
+            .map(|(name, addr)| format!("{} 0x{:04x}\n", name, addr))
+            .collect()
+    }
+
+    // one `0x3000 prog.asm:12` line per statement, source is the path the map refers to
+    pub fn line_map(&self, source: &str) -> String {
+        self.lines
+            .iter()
+            .map(|(addr, line)| format!("0x{:04x} {}:{}\n", addr, source, line))
+            .collect()
+    }
+}
+
+// a source line split into its parts
+struct Statement {
+    line: usize,
+    mnemonic: String,
+    operands: Vec<String>,
+}
+
+// assemble LC-4 source into an image, errors carry the line number
+pub fn assemble(source: &str) -> Result<Program, String> {
+    let mut origin: Option<u16> = None;
+    let mut addr: u32 = 0;
+    let mut statements: Vec<Statement> = Vec::new();
+    let mut symbols: Vec<(String, u16)> = Vec::new();
+    let mut lines: Vec<(u16, usize)> = Vec::new();
+    let mut table: HashMap<String, u16> = HashMap::new();
+
+    // first pass, find the address of every statement and label
+    for (index, text) in source.lines().enumerate() {
+        let line = index + 1;
+        let error = |message: String| format!("line {}: {}", line, message);
+        let (label, mnemonic, operands) = split_line(text).map_err(error)?;
+        if let Some(label) = label {
+            let here = match origin {
+                Some(_) => addr as u16,
+                None => return Err(error("label before .ORIG".to_owned())),
+            };
+            if table.insert(label.clone(), here).is_some() {
+                return Err(error(format!("duplicate label {}", label)));
+            }
+            symbols.push((label, here));
+        }
+        let mnemonic = match mnemonic {
+            Some(mnemonic) => mnemonic,
+            None => continue,
+        };
+        match mnemonic.as_str() {
+            ".ORIG" => {
+                if origin.is_some() {
+                    return Err(error("only one .ORIG per file is supported".to_owned()));
+                }
+                let value = parse_address(operand(&operands, 0).map_err(error)?)
+                    .ok_or_else(|| error("invalid .ORIG address".to_owned()))?;
+                origin = Some(value);
+                addr = value as u32;
+                continue;
+            }
+            ".END" => break,
+            _ => (),
+        }
+        if origin.is_none() {
+            return Err(error("statement before .ORIG".to_owned()));
+        }
+        let size = statement_size(&mnemonic, &operands).map_err(error)?;
+        if size > 0 {
+            lines.push((addr as u16, line));
+        }
+        statements.push(Statement {
+            line,
+            mnemonic,
+            operands,
+        });
+        addr += size as u32;
+        if addr > 0x10000 {
+            return Err(error("program runs past the end of memory".to_owned()));
+        }
+    }
+
+    let origin = origin.ok_or("no .ORIG found")?;
+
+    // second pass, encode everything now that all labels are known
+    let mut words = Vec::new();
+    for statement in &statements {
+        let encoded = encode_statement(statement, &table)
+            .map_err(|message| format!("line {}: {}", statement.line, message))?;
+        words.extend(encoded);
+    }
+    Ok(Program {
+        origin,
+        words,
+        symbols,
+        lines,
+    })
+}
+
+// an optional label, an optional mnemonic and its operands
+type SplitLine = (Option<String>, Option<String>, Vec<String>);
+
+fn split_line(text: &str) -> Result<SplitLine, String> {
+    let text = strip_comment(text).trim();
+    if text.is_empty() {
+        return Ok((None, None, Vec::new()));
+    }
+    let (first, rest) = split_word(text);
+    let (label, text) = if is_mnemonic(&first.to_uppercase()) {
+        (None, text)
+    } else {
+        let label = first.strip_suffix(':').unwrap_or(first);
+        if !is_label(label) {
+            return Err(format!("invalid label or instruction {}", first));
+        }
+        (Some(label.to_owned()), rest)
+    };
+    if text.is_empty() {
+        return Ok((label, None, Vec::new()));
+    }
+    let (mnemonic, rest) = split_word(text);
+    let mnemonic = mnemonic.to_uppercase();
+    if !is_mnemonic(&mnemonic) {
+        // without a colon the first word was more likely meant as the instruction
+        let unknown = if first.ends_with(':') {
+            &mnemonic
+        } else {
+            first
+        };
+        return Err(format!("unknown instruction {}", unknown));
+    }
+    let operands = if mnemonic == ".STRINGZ" {
+        vec![rest.to_owned()]
+    } else {
+        rest.split(',')
+            .flat_map(|part| part.split_whitespace())
+            .map(|part| part.to_owned())
+            .collect()
+    };
+    Ok((label, Some(mnemonic), operands))
+}
+
+fn split_word(text: &str) -> (&str, &str) {
+    match text.find(char::is_whitespace) {
+        Some(index) => (&text[..index], text[index..].trim()),
+        None => (text, ""),
+    }
+}
+
+// cut the line at the first ; that isn't inside a string or character literal
+fn strip_comment(text: &str) -> &str {
+    let mut quote: Option<char> = None;
+    let mut escaped = false;
+    for (index, c) in text.char_indices() {
+        match quote {
+            Some(q) => {
+                if escaped {
+                    escaped = false;
+                } else if c == '\\' {
+                    escaped = true;
+                } else if c == q {
+                    quote = None;
+                }
+            }
+            None => match c {
+                '"' | '\'' => quote = Some(c),
+                ';' => return &text[..index],
+                _ => (),
+            },
+        }
+    }
+    text
+}
+
+fn is_mnemonic(text: &str) -> bool {
+    matches!(
+        text,
+        "ADD"
+            | "AND"
+            | "XOR"
+            | "ADD16"
+            | "AND16"
+            | "XOR16"
+            | "JUMP"
+            | "JMP"
+            | "RET"
+            | "JSR"
+            | "JSRR"
+            | "LD"
+            | "ST"
+            | "STR"
+            | "STR16"
+            | "NOT"
+            | "TRAP"
+            | "RTI"
+            | "LSD"
+            | "LPN"
+            | "CLRP"
+            | "HALT"
+            | "PUTS"
+            | "GETC"
+            | "OUT"
+            | "IN"
+            | "PUTSP"
+            | ".ORIG"
+            | ".FILL"
+            | ".BLKW"
+            | ".STRINGZ"
+            | ".END"
+    ) || branch_flags(text).is_some()
+}
+
+// BR followed by any of n, z and p in that order, returns the flags as bits 2-0
+fn branch_flags(text: &str) -> Option<u16> {
+    let flags = text.strip_prefix("BR")?;
+    let mut out = 0u16;
+    let mut rest = flags;
+    for (name, bit) in [('N', 0b100), ('Z', 0b010), ('P', 0b001)] {
+        if let Some(next) = rest.strip_prefix(name) {
+            out |= bit;
+            rest = next;
+        }
+    }
+    rest.is_empty().then_some(out)
+}
+
+fn operand(operands: &[String], index: usize) -> Result<&str, String> {
+    operands
+        .get(index)
+        .map(|s| s.as_str())
+        .ok_or_else(|| format!("missing operand {}", index + 1))
+}
+
+fn expect_operands(operands: &[String], count: usize) -> Result<(), String> {
+    if operands.len() != count {
+        return Err(format!(
+            "expected {} operands, found {}",
+            count,
+            operands.len()
+        ));
+    }
+    Ok(())
+}
+
+// number of words a statement occupies, needed before labels are resolved
+fn statement_size(mnemonic: &str, operands: &[String]) -> Result<usize, String> {
+    Ok(match mnemonic {
+        ".FILL" => 1,
+        ".BLKW" => {
+            let count = parse_number(operand(operands, 0)?)
+                .filter(|n| (0..=0x10000).contains(n))
+                .ok_or("invalid .BLKW size")?;
+            count as usize
+        }
+        ".STRINGZ" => parse_string(operand(operands, 0)?)?.len() + 1,
+        "ADD" | "AND" | "XOR" => {
+            let third = operand(operands, 2)?;
+            if parse_register(third).is_some() {
+                1
+            } else {
+                match parse_immediate(third) {
+                    Some(value) if (-4..=3).contains(&value) => 1,
+                    _ => 2,
+                }
+            }
+        }
+        "LD" => {
+            if parse_immediate(operand(operands, 1)?).is_some() {
+                1
+            } else {
+                2
+            }
+        }
+        "STR" => match parse_immediate(operand(operands, 1)?) {
+            Some(value) if (-64..=63).contains(&value) => 1,
+            _ => 2,
+        },
+        "JSR" | "ST" | "ADD16" | "AND16" | "XOR16" | "STR16" => 2,
+        _ if branch_flags(mnemonic).is_some() => 2,
+        _ => 1,
+    })
+}
+
+fn encode_statement(
+    statement: &Statement,
+    table: &HashMap<String, u16>,
+) -> Result<Vec<u16>, String> {
+    let operands = &statement.operands;
+    let mnemonic = statement.mnemonic.as_str();
+    let words = match mnemonic {
+        ".FILL" => {
+            expect_operands(operands, 1)?;
+            let text = operand(operands, 0)?;
+            let value = match parse_char(text).or_else(|| parse_number(text)) {
+                Some(value) => to_word(value)?,
+                None => resolve_address(text, table)?,
+            };
+            vec![value]
+        }
+        ".BLKW" => {
+            let count = statement_size(mnemonic, operands)?;
+            let fill = match operands.get(1) {
+                Some(text) => to_word(parse_number(text).ok_or("invalid .BLKW fill value")?)?,
+                None => 0,
+            };
+            vec![fill; count]
+        }
+        ".STRINGZ" => {
+            let mut words: Vec<u16> = parse_string(operand(operands, 0)?)?
+                .into_iter()
+                .map(|c| c as u16)
+                .collect();
+            words.push(0);
+            words
+        }
+        _ => encode(&parse_instruction(mnemonic, operands, table)?)?,
+    };
+    if words.len() != statement_size(mnemonic, operands)? {
+        return Err("instruction size changed between passes".to_owned());
+    }
+    Ok(words)
+}
+
+// the instruction a statement stands for, the operands pick between the short and long forms
+fn parse_instruction(
+    mnemonic: &str,
+    operands: &[String],
+    table: &HashMap<String, u16>,
+) -> Result<Instruction, String> {
+    let reg = |index: usize| -> Result<Option<Operand>, String> {
+        let text = operand(operands, index)?;
+        match parse_register(text) {
+            Some(reg) => Ok(Some(register(reg))),
+            None => Err(format!("expected a register, found {}", text)),
+        }
+    };
+    let addr = |index: usize| -> Result<Option<Operand>, String> {
+        let text = operand(operands, index)?;
+        Ok(Some(Operand::Address(resolve_address(text, table)?)))
+    };
+    let immediate = |index: usize| -> Result<i32, String> {
+        let text = operand(operands, index)?;
+        parse_immediate(text).ok_or_else(|| format!("expected an immediate, found {}", text))
+    };
+    let imm16 = |index: usize| -> Result<Option<Operand>, String> {
+        Ok(Some(Operand::Imm16(to_word(immediate(index)?)? as i16)))
+    };
+    let instruction = |operation, dr, operand1, operand2| Instruction {
+        operation,
+        dr,
+        operand1,
+        operand2,
+    };
+    Ok(match mnemonic {
+        "ADD" | "AND" | "XOR" => {
+            expect_operands(operands, 3)?;
+            let [plain, short, long, address] = match mnemonic {
+                "ADD" => [
+                    Operation::ADD,
+                    Operation::ADDi,
+                    Operation::ADDi16,
+                    Operation::ADDa,
+                ],
+                "AND" => [
+                    Operation::AND,
+                    Operation::ANDi,
+                    Operation::ANDi16,
+                    Operation::ANDa,
+                ],
+                _ => [
+                    Operation::XOR,
+                    Operation::XORi,
+                    Operation::XORi16,
+                    Operation::XORa,
+                ],
+            };
+            let third = operand(operands, 2)?;
+            let (operation, operand2) = if let Some(sr2) = parse_register(third) {
+                (plain, Some(register(sr2)))
+            } else if let Some(value) = parse_immediate(third) {
+                if (-4..=3).contains(&value) {
+                    (short, Some(Operand::Imm3(i3::new(value as i8))))
+                } else {
+                    (long, Some(Operand::Imm16(to_word(value)? as i16)))
+                }
+            } else {
+                (address, addr(2)?)
+            };
+            instruction(operation, reg(0)?, reg(1)?, operand2)
+        }
+        // the 16-bit immediate forms whatever the size of the immediate
+        "ADD16" | "AND16" | "XOR16" => {
+            expect_operands(operands, 3)?;
+            let operation = match mnemonic {
+                "ADD16" => Operation::ADDi16,
+                "AND16" => Operation::ANDi16,
+                _ => Operation::XORi16,
+            };
+            instruction(operation, reg(0)?, reg(1)?, imm16(2)?)
+        }
+        "JUMP" | "JMP" => {
+            expect_operands(operands, 1)?;
+            instruction(Operation::JUMP, reg(0)?, None, None)
+        }
+        "JSR" => {
+            expect_operands(operands, 1)?;
+            instruction(Operation::JSR, None, None, addr(0)?)
+        }
+        "JSRR" => {
+            expect_operands(operands, 1)?;
+            instruction(Operation::JSRR, reg(0)?, None, None)
+        }
+        "LD" => {
+            expect_operands(operands, 2)?;
+            match parse_immediate(operand(operands, 1)?) {
+                Some(value) => {
+                    if !(-64..=63).contains(&value) {
+                        return Err(format!("immediate {} does not fit in 7 bits", value));
+                    }
+                    let imm7 = Operand::Imm7(i7::new(value as i8));
+                    instruction(Operation::LD, reg(0)?, Some(imm7), None)
+                }
+                None => instruction(Operation::LDa, reg(0)?, None, addr(1)?),
+            }
+        }
+        "ST" => {
+            expect_operands(operands, 2)?;
+            instruction(Operation::ST, reg(0)?, None, addr(1)?)
+        }
+        "STR" => {
+            expect_operands(operands, 2)?;
+            let value = immediate(1)?;
+            if (-64..=63).contains(&value) {
+                let imm7 = Operand::Imm7(i7::new(value as i8));
+                instruction(Operation::STR, reg(0)?, Some(imm7), None)
+            } else {
+                instruction(Operation::STR16, reg(0)?, None, imm16(1)?)
+            }
+        }
+        "STR16" => {
+            expect_operands(operands, 2)?;
+            instruction(Operation::STR16, reg(0)?, None, imm16(1)?)
+        }
+        "NOT" => {
+            expect_operands(operands, 2)?;
+            instruction(Operation::NOT, reg(0)?, reg(1)?, None)
+        }
+        "TRAP" => {
+            expect_operands(operands, 1)?;
+            let text = operand(operands, 0)?;
+            let vect = parse_address(text)
+                .filter(|v| *v <= 0xFF)
+                .ok_or_else(|| format!("invalid trap vector {}", text))?;
+            // the vectors of the service routines assemble to the named instruction, TRAP x25 is HALT
+            match parse_trap(vect) {
+                Operation::TRAP => {
+                    let vect = Operand::TrapVect(vect as u8);
+                    instruction(Operation::TRAP, None, Some(vect), None)
+                }
+                operation => instruction(operation, None, None, None),
+            }
+        }
+        "RET" | "RTI" | "GETC" | "OUT" | "PUTS" | "IN" | "PUTSP" | "HALT" | "LSD" | "LPN"
+        | "CLRP" => {
+            expect_operands(operands, 0)?;
+            let operation = match mnemonic {
+                "RET" => Operation::RET,
+                "RTI" => Operation::RTI,
+                "GETC" => Operation::GETC,
+                "OUT" => Operation::OUT,
+                "PUTS" => Operation::PUTS,
+                "IN" => Operation::IN,
+                "PUTSP" => Operation::PUTSP,
+                "HALT" => Operation::HALT,
+                "LSD" => Operation::LSD,
+                "LPN" => Operation::LPN,
+                _ => Operation::CLRP,
+            };
+            instruction(operation, None, None, None)
+        }
+        _ => match branch_flags(mnemonic) {
+            Some(flags) => {
+                expect_operands(operands, 1)?;
+                let flags = Operand::BR(Flags {
+                    n: flags & 0b100 != 0,
+                    z: flags & 0b010 != 0,
+                    p: flags & 0b001 != 0,
+                });
+                instruction(Operation::BR, None, Some(flags), addr(0)?)
+            }
+            None => return Err(format!("unknown instruction {}", mnemonic)),
+        },
+    })
+}
+
+fn register(reg: u16) -> Operand {
+    Operand::Register(u3::new(reg as u8))
+}
+
+fn parse_register(text: &str) -> Option<u16> {
+    let digit = text.strip_prefix('R').or_else(|| text.strip_prefix('r'))?;
+    match digit.parse::<u16>() {
+        Ok(n) if n < 8 && digit.len() == 1 => Some(n),
+        _ => None,
+    }
+}
+
+// #12, #-3 or #x1F
+fn parse_immediate(text: &str) -> Option<i32> {
+    parse_number(text.strip_prefix('#')?)
+}
+
+// decimal, or hexadecimal written as x1F or 0x1F, with an optional sign
+fn parse_number(text: &str) -> Option<i32> {
+    let text = text.strip_prefix('#').unwrap_or(text);
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, text),
+    };
+    let hex = digits
+        .strip_prefix("0x")
+        .or_else(|| digits.strip_prefix("0X"))
+        .or_else(|| digits.strip_prefix('x'))
+        .or_else(|| digits.strip_prefix('X'));
+    let value = match hex {
+        Some(hex) => i32::from_str_radix(hex, 16).ok()?,
+        None => digits.parse::<i32>().ok()?,
+    };
+    Some(if negative { -value } else { value })
+}
+
+fn parse_address(text: &str) -> Option<u16> {
+    parse_number(text).and_then(|value| u16::try_from(value).ok())
+}
+
+fn resolve_address(text: &str, table: &HashMap<String, u16>) -> Result<u16, String> {
+    if let Some(addr) = table.get(text) {
+        return Ok(*addr);
+    }
+    if is_label(text) && parse_number(text).is_none() {
+        return Err(format!("undefined label {}", text));
+    }
+    parse_address(text).ok_or_else(|| format!("invalid address {}", text))
+}
+
+// values can be written signed or unsigned
+fn to_word(value: i32) -> Result<u16, String> {
+    if (-0x8000..=0xFFFF).contains(&value) {
+        Ok(value as u16)
+    } else {
+        Err(format!("value {} does not fit in 16 bits", value))
+    }
+}
+
+fn parse_char(text: &str) -> Option<i32> {
+    let inner = text.strip_prefix('\'')?.strip_suffix('\'')?;
+    match unescape(inner).ok()?.as_slice() {
+        [c] => Some(*c as i32),
+        _ => None,
+    }
+}
+
+fn parse_string(text: &str) -> Result<Vec<u8>, String> {
+    let inner = text
+        .trim()
+        .strip_prefix('"')
+        .and_then(|t| t.strip_suffix('"'))
+        .ok_or("expected a string in double quotes")?;
+    unescape(inner)
+}
+
+fn unescape(text: &str) -> Result<Vec<u8>, String> {
+    let mut out = Vec::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        let c = if c == '\\' {
+            match chars.next() {
+                Some('n') => '\n',
+                Some('r') => '\r',
+                Some('t') => '\t',
+                Some('e') => '\x1b',
+                Some('0') => '\0',
+                Some(c @ ('\\' | '"' | '\'')) => c,
+                _ => return Err(format!("invalid escape in {}", text)),
+            }
+        } else {
+            c
+        };
+        if !c.is_ascii() {
+            return Err(format!("non ASCII character {}", c));
+        }
+        out.push(c as u8);
+    }
+    Ok(out)
+}
+
+// memory holding the assembled sources, for tests that run programs
+#[cfg(test)]
+pub(crate) fn assemble_memory(sources: &[&str]) -> [u16; 65536] {
+    let mut mem = [0u16; 65536];
+    for source in sources {
+        let program = assemble(source).unwrap_or_else(|e| panic!("{}", e));
+        let origin = program.origin as usize;
+        mem[origin..origin + program.words.len()].copy_from_slice(&program.words);
+    }
+    mem
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the words of one statement assembled at 0x3000
+    fn words(statement: &str) -> Result<Vec<u16>, String> {
+        assemble(&format!(".ORIG x3000\n{}\n.END\n", statement)).map(|program| program.words)
+    }
+
+    #[test]
+    fn no_operand_instructions_take_no_operands() {
+        for (mnemonic, word) in [
+            ("RET", 0b00101 << 11 | 0b111 << 7),
+            ("RTI", 0b01101 << 11),
+            ("GETC", 0b01100 << 11 | 0x20),
+            ("OUT", 0b01100 << 11 | 0x21),
+            ("PUTS", 0b01100 << 11 | 0x22),
+            ("IN", 0b01100 << 11 | 0x23),
+            ("PUTSP", 0b01100 << 11 | 0x24),
+            ("HALT", 0b01100 << 11 | 0x25),
+            ("LSD", 0b01100 << 11 | 0x26),
+            ("LPN", 0b01100 << 11 | 0x27),
+            ("CLRP", 0b01100 << 11 | 0x28),
+        ] {
+            assert_eq!(words(mnemonic), Ok(vec![word]), "{}", mnemonic);
+            for extra in ["R3", "R3, R4", "#1"] {
+                let statement = format!("{} {}", mnemonic, extra);
+                let error = words(&statement).expect_err(&statement);
+                assert!(
+                    error.contains("expected 0 operands"),
+                    "{}: {}",
+                    statement,
+                    error
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn operand_counts_are_checked() {
+        for (statement, count) in [
+            ("ADD R1, R2, R3", 3),
+            ("ADD R1, R2, #1", 3),
+            ("AND R1, R2, #100", 3),
+            ("XOR R1, R2, R3", 3),
+            ("NOT R1, R2", 2),
+            ("LD R1, #5", 2),
+            ("LD R1, x4000", 2),
+            ("ST R1, x4000", 2),
+            ("STR R1, #5", 2),
+            ("JUMP R1", 1),
+            ("JSR x4000", 1),
+            ("JSRR R1", 1),
+            ("BRnzp x3000", 1),
+            ("TRAP x30", 1),
+        ] {
+            assert!(words(statement).is_ok(), "{}", statement);
+            let (mnemonic, _) = statement.split_once(' ').unwrap();
+            let fewer = vec!["R1"; count - 1].join(", ");
+            let more = format!("{}, R7", statement);
+            for wrong in [format!("{} {}", mnemonic, fewer), more] {
+                let error = words(&wrong).expect_err(&wrong);
+                assert!(error.contains("operand"), "{}: {}", wrong, error);
+            }
+        }
+    }
+}
@@ -0,0 +1,369 @@
+use crate::console::{Console, EndOfInput};
+use crate::interrupt::InterruptController;
+use std::collections::{BTreeSet, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::ops::RangeInclusive;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AccessKind {
+    Fetch,
+    Read,
+    Write,
+}
+
+// one access that went through the bus, value is what was read or written
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Access {
+    pub kind: AccessKind,
+    pub addr: u16,
+    pub value: u16,
+}
+
+// console input as the program sees it, keys are kept so they can be read again after stepping back
+#[derive(Default)]
+pub(crate) struct InputLog {
+    // input that was read before stepping backwards, with the step that read it
+    pub(crate) pending: VecDeque<(u64, Option<u8>)>,
+    // input read by the instruction being executed, only kept while recording history
+    pub(crate) recorded: Vec<Option<u8>>,
+    pub(crate) recording: bool,
+    // set while replaying from a checkpoint so output is not repeated and hooks don't see accesses twice
+    pub(crate) replaying: bool,
+    // keys read and polls that found none waiting, a program waiting for input is not stuck in a loop
+    pub(crate) progress: u64,
+}
+
+// the parts of the machine a device can use while it is accessed
+pub struct DeviceContext<'a> {
+    console: &'a mut dyn Console,
+    input: &'a mut InputLog,
+    pub interrupts: &'a mut InterruptController,
+    running: &'a mut bool,
+    end_of_input: EndOfInput,
+    // the instruction being executed
+    step: u64,
+}
+
+impl<'a> DeviceContext<'a> {
+    pub(crate) fn new(
+        console: &'a mut dyn Console,
+        input: &'a mut InputLog,
+        interrupts: &'a mut InterruptController,
+        running: &'a mut bool,
+        end_of_input: EndOfInput,
+        step: u64,
+    ) -> DeviceContext<'a> {
+        DeviceContext {
+            console,
+            input,
+            interrupts,
+            running,
+            end_of_input,
+            step,
+        }
+    }
+
+    // true if read_key would not block, going forward after stepping back
+    // the keys that were read before only become available at the step that read them
+    pub fn poll_key(&mut self) -> io::Result<bool> {
+        match self.input.pending.front() {
+            Some((step, _)) => Ok(*step == self.step),
+            None if self.input.replaying => Ok(false),
+            None => {
+                let ready = self.console.poll()?;
+                if !ready {
+                    self.input.progress += 1;
+                }
+                Ok(ready)
+            }
+        }
+    }
+
+    // block for a key, None at the end of input, which can also halt the machine or fail the read
+    pub fn read_key(&mut self) -> io::Result<Option<u8>> {
+        let key = match self.input.pending.pop_front() {
+            Some((_, key)) => key,
+            None => self.console.read_byte()?,
+        };
+        if key.is_some() {
+            self.input.progress += 1;
+        } else {
+            match self.end_of_input {
+                EndOfInput::Null => (),
+                EndOfInput::Halt => self.halt(),
+                EndOfInput::Error => {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "end of input"))
+                }
+            }
+        }
+        if self.input.recording {
+            self.input.recorded.push(key);
+        }
+        Ok(key)
+    }
+
+    // write to the console and flush so output shows up immediately
+    pub fn write_output(&mut self, bytes: &[u8]) -> io::Result<()> {
+        if self.input.replaying {
+            return Ok(());
+        }
+        self.console.write_bytes(bytes)?;
+        self.console.flush()
+    }
+
+    pub fn is_running(&self) -> bool {
+        *self.running
+    }
+
+    // stop the machine after the current instruction
+    pub fn halt(&mut self) {
+        *self.running = false;
+    }
+}
+
+// something mapped onto a range of addresses, every load and store to the range is forwarded to it
+pub trait Device {
+    // a load, which may have side effects such as consuming a key
+    fn read(&mut self, addr: u16, ctx: &mut DeviceContext) -> io::Result<u16>;
+    fn write(&mut self, addr: u16, value: u16, ctx: &mut DeviceContext) -> io::Result<()>;
+    // the value a load would see, without side effects, for the debugger and listings
+    fn peek(&self, addr: u16) -> u16;
+    // called before every instruction, this is where a device raises its interrupt
+    fn tick(&mut self, _ctx: &mut DeviceContext) -> io::Result<()> {
+        Ok(())
+    }
+    // the device's registers, saved with the rest of the machine for reverse execution
+    fn save(&self) -> Vec<u16> {
+        Vec::new()
+    }
+    fn restore(&mut self, _state: &[u16]) {}
+}
+
+// a device made of two closures, for registers that don't need the console or interrupts
+pub struct CallbackDevice<R, W> {
+    read: R,
+    write: W,
+}
+
+impl<R: Fn(u16) -> u16, W: FnMut(u16, u16)> CallbackDevice<R, W> {
+    pub fn new(read: R, write: W) -> CallbackDevice<R, W> {
+        CallbackDevice { read, write }
+    }
+}
+
+impl<R: Fn(u16) -> u16, W: FnMut(u16, u16)> Device for CallbackDevice<R, W> {
+    fn read(&mut self, addr: u16, _ctx: &mut DeviceContext) -> io::Result<u16> {
+        Ok((self.read)(addr))
+    }
+
+    fn write(&mut self, addr: u16, value: u16, _ctx: &mut DeviceContext) -> io::Result<()> {
+        (self.write)(addr, value);
+        Ok(())
+    }
+
+    fn peek(&self, addr: u16) -> u16 {
+        (self.read)(addr)
+    }
+}
+
+type Hook = Box<dyn FnMut(&Access)>;
+
+// memory with devices mapped over parts of it, all loads, stores and fetches of the machine go through here
+pub struct Bus {
+    memory: Box<[u16; 65536]>,
+    devices: Vec<(RangeInclusive<u16>, Box<dyn Device>)>,
+    // index + 1 of the device mapped at every address, 0 is RAM
+    map: Box<[u8; 65536]>,
+    watchpoints: BTreeSet<u16>,
+    // accesses to watched addresses since they were last taken
+    watch_hits: Vec<Access>,
+    hooks: Vec<Hook>,
+}
+
+impl Bus {
+    pub fn new(memory: [u16; 65536]) -> Bus {
+        Bus {
+            memory: Box::new(memory),
+            devices: Vec::new(),
+            map: Box::new([0; 65536]),
+            watchpoints: BTreeSet::new(),
+            watch_hits: Vec::new(),
+            hooks: Vec::new(),
+        }
+    }
+
+    // map a device over a range of addresses, ranges can't overlap
+    pub fn register(
+        &mut self,
+        range: RangeInclusive<u16>,
+        device: Box<dyn Device>,
+    ) -> Result<(), String> {
+        if range.is_empty() {
+            return Err("empty device range".to_owned());
+        }
+        if let Some(addr) = range.clone().find(|addr| self.map[*addr as usize] != 0) {
+            let (other, _) = &self.devices[self.map[addr as usize] as usize - 1];
+            return Err(format!(
+                "0x{:04x}-0x{:04x} overlaps the device at 0x{:04x}-0x{:04x}",
+                range.start(),
+                range.end(),
+                other.start(),
+                other.end()
+            ));
+        }
+        if self.devices.len() == u8::MAX as usize {
+            return Err("too many devices".to_owned());
+        }
+        self.devices.push((range.clone(), device));
+        for addr in range {
+            self.map[addr as usize] = self.devices.len() as u8;
+        }
+        Ok(())
+    }
+
+    pub fn register_callbacks<R, W>(
+        &mut self,
+        range: RangeInclusive<u16>,
+        read: R,
+        write: W,
+    ) -> Result<(), String>
+    where
+        R: Fn(u16) -> u16 + 'static,
+        W: FnMut(u16, u16) + 'static,
+    {
+        self.register(range, Box::new(CallbackDevice::new(read, write)))
+    }
+
+    // the address ranges that have a device on them
+    pub fn device_ranges(&self) -> Vec<RangeInclusive<u16>> {
+        self.devices
+            .iter()
+            .map(|(range, _)| range.clone())
+            .collect()
+    }
+
+    pub fn is_device(&self, addr: u16) -> bool {
+        self.map[addr as usize] != 0
+    }
+
+    // RAM, device addresses hold whatever was there before the device was mapped
+    pub fn memory(&self) -> &[u16; 65536] {
+        &self.memory
+    }
+
+    pub(crate) fn memory_mut(&mut self) -> &mut [u16; 65536] {
+        &mut self.memory
+    }
+
+    pub fn peek(&self, addr: u16) -> u16 {
+        match self.map[addr as usize] {
+            0 => self.memory[addr as usize],
+            device => self.devices[device as usize - 1].1.peek(addr),
+        }
+    }
+
+    // a fetch or a load
+    pub fn read(
+        &mut self,
+        addr: u16,
+        kind: AccessKind,
+        ctx: &mut DeviceContext,
+    ) -> io::Result<u16> {
+        let value = match self.map[addr as usize] {
+            0 => self.memory[addr as usize],
+            device => self.devices[device as usize - 1].1.read(addr, ctx)?,
+        };
+        self.notify(Access { kind, addr, value }, ctx);
+        Ok(value)
+    }
+
+    // a store, returns the old value when it went to RAM so it can be undone
+    pub fn write(
+        &mut self,
+        addr: u16,
+        value: u16,
+        ctx: &mut DeviceContext,
+    ) -> io::Result<Option<u16>> {
+        let old = match self.map[addr as usize] {
+            0 => Some(std::mem::replace(&mut self.memory[addr as usize], value)),
+            device => {
+                self.devices[device as usize - 1]
+                    .1
+                    .write(addr, value, ctx)?;
+                None
+            }
+        };
+        let kind = AccessKind::Write;
+        self.notify(Access { kind, addr, value }, ctx);
+        Ok(old)
+    }
+
+    pub fn tick(&mut self, ctx: &mut DeviceContext) -> io::Result<()> {
+        for (_, device) in self.devices.iter_mut() {
+            device.tick(ctx)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn save_devices(&self) -> Vec<Vec<u16>> {
+        self.devices
+            .iter()
+            .map(|(_, device)| device.save())
+            .collect()
+    }
+
+    pub(crate) fn hash_devices<H: Hasher>(&self, state: &mut H) {
+        for (_, device) in self.devices.iter() {
+            device.save().hash(state);
+        }
+    }
+
+    pub(crate) fn restore_devices(&mut self, states: &[Vec<u16>]) {
+        for ((_, device), state) in self.devices.iter_mut().zip(states) {
+            device.restore(state);
+        }
+    }
+
+    // call `hook` for every access, for tracing
+    pub fn add_hook(&mut self, hook: Hook) {
+        self.hooks.push(hook);
+    }
+
+    pub fn clear_hooks(&mut self) {
+        self.hooks.clear();
+    }
+
+    // record every load and store of addr, breakpoints cover fetches
+    pub fn watch(&mut self, addr: u16) -> bool {
+        self.watchpoints.insert(addr)
+    }
+
+    pub fn unwatch(&mut self, addr: u16) -> bool {
+        self.watchpoints.remove(&addr)
+    }
+
+    pub fn watchpoints(&self) -> &BTreeSet<u16> {
+        &self.watchpoints
+    }
+
+    pub fn watch_hits(&self) -> &[Access] {
+        &self.watch_hits
+    }
+
+    pub fn take_watch_hits(&mut self) -> Vec<Access> {
+        std::mem::take(&mut self.watch_hits)
+    }
+
+    fn notify(&mut self, access: Access, ctx: &DeviceContext) {
+        if ctx.input.replaying {
+            return;
+        }
+        if access.kind != AccessKind::Fetch && self.watchpoints.contains(&access.addr) {
+            self.watch_hits.push(access);
+        }
+        for hook in self.hooks.iter_mut() {
+            hook(&access);
+        }
+    }
+}
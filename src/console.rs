@@ -0,0 +1,268 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, stdin, stdout, BufRead, BufReader, BufWriter, Read, Stdout, Write};
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::thread;
+use termion::raw::{IntoRawMode, RawTerminal};
+
+// the terminal the machine talks to, traps and devices only see this
+pub trait Console {
+    // block until a byte is available, None once the input is exhausted
+    fn read_byte(&mut self) -> io::Result<Option<u8>>;
+    fn write_bytes(&mut self, bytes: &[u8]) -> io::Result<()>;
+    fn flush(&mut self) -> io::Result<()>;
+    // true if read_byte would return without blocking
+    fn poll(&mut self) -> io::Result<bool>;
+}
+
+// what a program reading past the end of its input gets
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum EndOfInput {
+    // a null character, on every read
+    #[default]
+    Null,
+    // the read returns a null character and the machine halts after the instruction
+    Halt,
+    // the instruction fails with an I/O error
+    Error,
+}
+
+// the real terminal in raw mode, input is read on a separate thread so it can be polled
+pub struct TerminalConsole {
+    input: Receiver<io::Result<u8>>,
+    peeked: Option<Option<u8>>,
+    output: RawTerminal<Stdout>,
+}
+
+impl TerminalConsole {
+    pub fn new() -> io::Result<TerminalConsole> {
+        let output = stdout().into_raw_mode()?;
+        let (send, input) = channel();
+        thread::spawn(move || {
+            for byte in stdin().lock().bytes() {
+                if send.send(byte).is_err() {
+                    return;
+                }
+            }
+        });
+        Ok(TerminalConsole {
+            input,
+            peeked: None,
+            output,
+        })
+    }
+}
+
+impl Console for TerminalConsole {
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        if let Some(byte) = self.peeked.take() {
+            return Ok(byte);
+        }
+        match self.input.recv() {
+            Ok(byte) => byte.map(Some),
+            // the reader thread stops at the end of input
+            Err(_) => Ok(None),
+        }
+    }
+
+    // raw mode needs explicit carriage returns
+    fn write_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        for line in bytes.split_inclusive(|b| *b == b'\n') {
+            match line.strip_suffix(b"\n") {
+                Some(line) => {
+                    self.output.write_all(line)?;
+                    self.output.write_all(b"\r\n")?;
+                }
+                None => self.output.write_all(line)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+
+    fn poll(&mut self) -> io::Result<bool> {
+        if self.peeked.is_some() {
+            return Ok(true);
+        }
+        match self.input.try_recv() {
+            Ok(byte) => {
+                self.peeked = Some(Some(byte?));
+                Ok(true)
+            }
+            Err(TryRecvError::Empty) => Ok(false),
+            Err(TryRecvError::Disconnected) => {
+                self.peeked = Some(None);
+                Ok(true)
+            }
+        }
+    }
+}
+
+// scripted input and captured output, for tests and embedding
+#[derive(Default)]
+pub struct BufferConsole {
+    input: VecDeque<u8>,
+    output: Vec<u8>,
+}
+
+impl BufferConsole {
+    pub fn new(input: &[u8]) -> BufferConsole {
+        BufferConsole {
+            input: input.iter().copied().collect(),
+            output: Vec::new(),
+        }
+    }
+
+    pub fn push_input(&mut self, bytes: &[u8]) {
+        self.input.extend(bytes);
+    }
+
+    // input that has not been read by the machine yet
+    pub fn remaining_input(&self) -> Vec<u8> {
+        self.input.iter().copied().collect()
+    }
+
+    pub fn output(&self) -> &[u8] {
+        &self.output
+    }
+
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.output)
+    }
+}
+
+impl Console for BufferConsole {
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        Ok(self.input.pop_front())
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.output.extend_from_slice(bytes);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn poll(&mut self) -> io::Result<bool> {
+        Ok(!self.input.is_empty())
+    }
+}
+
+// any reader and writer pair, such as files or plain (non raw) stdin and stdout
+pub struct StreamConsole<R: Read, W: Write> {
+    input: R,
+    peeked: Option<Option<u8>>,
+    output: W,
+}
+
+pub type FileConsole = StreamConsole<BufReader<File>, BufWriter<File>>;
+
+impl<R: Read, W: Write> StreamConsole<R, W> {
+    pub fn new(input: R, output: W) -> StreamConsole<R, W> {
+        StreamConsole {
+            input,
+            peeked: None,
+            output,
+        }
+    }
+
+    pub fn into_inner(self) -> (R, W) {
+        (self.input, self.output)
+    }
+}
+
+impl FileConsole {
+    // read program input from one file and write program output to another
+    pub fn open(input: &Path, output: &Path) -> io::Result<FileConsole> {
+        Ok(StreamConsole::new(
+            BufReader::new(File::open(input)?),
+            BufWriter::new(File::create(output)?),
+        ))
+    }
+}
+
+// one reader shared by its clones, so the debugger's commands and the program's input can come from
+// the same stream, each only takes what it reads and leaves the rest buffered for the other
+pub struct SharedReader<R: BufRead> {
+    inner: Rc<RefCell<R>>,
+    // a copy of the inner buffer, fill_buf can't hand out a borrow of the RefCell
+    available: Vec<u8>,
+}
+
+impl<R: BufRead> SharedReader<R> {
+    pub fn new(inner: R) -> SharedReader<R> {
+        SharedReader {
+            inner: Rc::new(RefCell::new(inner)),
+            available: Vec::new(),
+        }
+    }
+}
+
+impl<R: BufRead> Clone for SharedReader<R> {
+    fn clone(&self) -> SharedReader<R> {
+        SharedReader {
+            inner: Rc::clone(&self.inner),
+            available: Vec::new(),
+        }
+    }
+}
+
+impl<R: BufRead> Read for SharedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.borrow_mut().read(buf)
+    }
+}
+
+impl<R: BufRead> BufRead for SharedReader<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        let mut inner = self.inner.borrow_mut();
+        self.available.clear();
+        self.available.extend_from_slice(inner.fill_buf()?);
+        Ok(&self.available)
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.inner.borrow_mut().consume(amount);
+    }
+}
+
+impl<R: Read, W: Write> Console for StreamConsole<R, W> {
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        if let Some(byte) = self.peeked.take() {
+            return Ok(byte);
+        }
+        let mut buffer = [0u8; 1];
+        loop {
+            match self.input.read(&mut buffer) {
+                Ok(0) => return Ok(None),
+                Ok(_) => return Ok(Some(buffer[0])),
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+                Err(error) => return Err(error),
+            }
+        }
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.output.write_all(bytes)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+
+    // streams can't be checked without reading, so this blocks until a byte or the end arrives
+    fn poll(&mut self) -> io::Result<bool> {
+        if self.peeked.is_none() {
+            self.peeked = Some(self.read_byte()?);
+        }
+        Ok(true)
+    }
+}
@@ -0,0 +1,652 @@
+use crate::bus::AccessKind;
+use crate::console::Console;
+use crate::dump::{dump, parse_symbolic_range, DumpFormat};
+use crate::error::SimError;
+use crate::lines::{split_location, LineMap};
+use crate::machine::Machine;
+use crate::parse::parse_address;
+use crate::snapshot::Snapshot;
+use crate::symbols::{Symbolic, Symbols};
+use crate::tokenizer::{Instruction, Operation};
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::{BufRead, Write};
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+use ux::u3;
+
+const HELP: &str = "\
+break ADDR        (b)   set a breakpoint, at an address or a source line like prog.asm:12
+delete [ADDR]     (d)   clear a breakpoint, or all of them
+breakpoints       (bl)  list breakpoints
+watch [ADDR]      (w)   stop after an instruction loads or stores ADDR, or list watchpoints
+unwatch [ADDR]          clear a watchpoint, or all of them
+step [N]          (s)   execute N instructions (default 1)
+next              (n)   step, treating JSR/JSRR and trapping to a handler as a single instruction
+finish            (f)   run until the current subroutine or trap handler returns
+continue          (c)   run until a breakpoint or HALT
+reverse-step [N]  (rs)  undo N instructions (default 1)
+reverse-continue  (rc)  run backwards until a breakpoint or the start of the history
+registers         (r)   print the registers and PC
+psr                     print the processor status register
+asg                     print the ASG LFSR states
+int VECT [PRI]          raise interrupt VECT at priority PRI (default 4)
+x ADDR [COUNT]          examine COUNT words of memory (default 8)
+print             (p)   print everything
+dump RANGE [FMT] [FILE]  write memory x3000-x30ff as hex (default), disasm or an image FILE
+save-state FILE         save a snapshot of the whole machine to FILE
+load-state FILE         continue from a snapshot, clearing the reverse execution history
+help              (h)   show this help
+quit              (q)   leave the debugger
+addresses can be written as 0x3000, x3000, 12288 or with the symbol table as LOOP or LOOP+2,
+an empty line repeats the last command
+";
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Command {
+    Break(u16),
+    Delete(Option<u16>),
+    Breakpoints,
+    Watch(Option<u16>),
+    Unwatch(Option<u16>),
+    Step(usize),
+    Next,
+    Finish,
+    Continue,
+    ReverseStep(usize),
+    ReverseContinue,
+    Registers,
+    Psr,
+    Asg,
+    Interrupt(u8, u3),
+    Examine(u16, u16),
+    Print,
+    Dump(RangeInclusive<u16>, DumpFormat, Option<PathBuf>),
+    SaveState(PathBuf),
+    LoadState(PathBuf),
+    Help,
+    Quit,
+}
+
+impl Command {
+    pub fn parse(line: &str) -> Result<Command, String> {
+        Command::parse_with(line, &Symbols::default(), &LineMap::default())
+    }
+
+    // addresses can be labels from the symbol table, breakpoints can also be source lines
+    pub fn parse_with(line: &str, symbols: &Symbols, lines: &LineMap) -> Result<Command, String> {
+        let parse_address = |text: &str| symbols.resolve(text);
+        let parse_location = |text: &str| match split_location(text) {
+            Some((file, line)) => lines.address(file, line),
+            None => symbols.resolve(text),
+        };
+        let mut words = line.split_whitespace();
+        let name = words.next().ok_or("empty command")?;
+        let args: Vec<&str> = words.collect();
+        let command = match name {
+            "break" | "b" => Command::Break(parse_location(arg(&args, 0)?)?),
+            "delete" | "d" | "clear" => match args.first() {
+                Some(addr) => Command::Delete(Some(parse_location(addr)?)),
+                None => Command::Delete(None),
+            },
+            "breakpoints" | "bl" => Command::Breakpoints,
+            "watch" | "w" => Command::Watch(args.first().map(|a| parse_address(a)).transpose()?),
+            "unwatch" => Command::Unwatch(args.first().map(|a| parse_address(a)).transpose()?),
+            "step" | "s" => Command::Step(parse_count(args.first())?),
+            "next" | "n" => Command::Next,
+            "finish" | "f" => Command::Finish,
+            "continue" | "c" => Command::Continue,
+            "reverse-step" | "rs" => Command::ReverseStep(parse_count(args.first())?),
+            "reverse-continue" | "rc" => Command::ReverseContinue,
+            "registers" | "regs" | "r" => Command::Registers,
+            "psr" => Command::Psr,
+            "asg" => Command::Asg,
+            "interrupt" | "int" => {
+                let vector = self::parse_address(arg(&args, 0)?)?;
+                let vector = u8::try_from(vector)
+                    .map_err(|_| format!("invalid interrupt vector: {}", args[0]))?;
+                let priority = match args.get(1) {
+                    Some(priority) => match priority.parse::<u8>() {
+                        Ok(priority) if priority <= 7 => u3::new(priority),
+                        _ => return Err(format!("invalid priority: {}", priority)),
+                    },
+                    None => u3::new(4),
+                };
+                Command::Interrupt(vector, priority)
+            }
+            "x" | "examine" => {
+                let addr = parse_address(arg(&args, 0)?)?;
+                let count = match args.get(1) {
+                    Some(count) => self::parse_address(count)?,
+                    None => 8,
+                };
+                Command::Examine(addr, count)
+            }
+            "print" | "p" => Command::Print,
+            "dump" => {
+                let range = parse_symbolic_range(arg(&args, 0)?, symbols)?;
+                let format = match args.get(1) {
+                    Some(name) => DumpFormat::from_name(name)
+                        .ok_or_else(|| format!("unknown dump format: {}", name))?,
+                    None => DumpFormat::Hex,
+                };
+                let file = match args.len() {
+                    0..=2 => None,
+                    _ => Some(path_arg(&args[2..])?),
+                };
+                if format == DumpFormat::Image && file.is_none() {
+                    return Err("an image dump needs a file to write".to_owned());
+                }
+                Command::Dump(range, format, file)
+            }
+            "save-state" => Command::SaveState(path_arg(&args)?),
+            "load-state" => Command::LoadState(path_arg(&args)?),
+            "help" | "h" => Command::Help,
+            "quit" | "q" => Command::Quit,
+            _ => return Err(format!("unknown command: {} (try help)", name)),
+        };
+        Ok(command)
+    }
+}
+
+fn arg<'a>(args: &[&'a str], index: usize) -> Result<&'a str, String> {
+    args.get(index)
+        .copied()
+        .ok_or_else(|| "missing argument".to_owned())
+}
+
+// the rest of the line, so a path can have spaces in it
+fn path_arg(args: &[&str]) -> Result<PathBuf, String> {
+    match args.join(" ") {
+        path if path.is_empty() => Err("missing file name".to_owned()),
+        path => Ok(PathBuf::from(path)),
+    }
+}
+
+fn parse_count(arg: Option<&&str>) -> Result<usize, String> {
+    match arg {
+        Some(count) => count
+            .parse()
+            .map_err(|_| format!("invalid step count: {}", count)),
+        None => Ok(1),
+    }
+}
+
+// wraps a machine with breakpoints and the commands of the debug REPL
+pub struct Debugger<C: Console> {
+    machine: Machine<C>,
+    breakpoints: BTreeSet<u16>,
+}
+
+impl<C: Console> Debugger<C> {
+    pub fn new(machine: Machine<C>) -> Debugger<C> {
+        Debugger {
+            machine,
+            breakpoints: BTreeSet::new(),
+        }
+    }
+
+    pub fn machine(&self) -> &Machine<C> {
+        &self.machine
+    }
+
+    pub fn machine_mut(&mut self) -> &mut Machine<C> {
+        &mut self.machine
+    }
+
+    pub fn into_machine(self) -> Machine<C> {
+        self.machine
+    }
+
+    // parse a command, addresses can be labels from the machine's symbols
+    pub fn parse(&self, line: &str) -> Result<Command, String> {
+        Command::parse_with(line, self.machine.symbols(), self.machine.line_map())
+    }
+
+    // an address with its label and source line, if it has them
+    fn describe(&self, addr: u16) -> String {
+        let name = self.machine.symbols().describe(addr);
+        match self.machine.line_map().location(addr) {
+            Some(location) => format!("{} {}", name, location),
+            None => name,
+        }
+    }
+
+    // an error with addresses named after the machine's symbols
+    fn error(&self, error: SimError) -> String {
+        error.describe(self.machine.symbols())
+    }
+
+    pub fn breakpoints(&self) -> &BTreeSet<u16> {
+        &self.breakpoints
+    }
+
+    pub fn add_breakpoint(&mut self, addr: u16) -> bool {
+        self.breakpoints.insert(addr)
+    }
+
+    pub fn remove_breakpoint(&mut self, addr: u16) -> bool {
+        self.breakpoints.remove(&addr)
+    }
+
+    // run a command and return the text to show the user
+    pub fn execute(&mut self, command: &Command) -> Result<String, String> {
+        match command {
+            Command::Break(addr) => {
+                self.add_breakpoint(*addr);
+                Ok(format!("breakpoint at {}\n", self.describe(*addr)))
+            }
+            Command::Delete(Some(addr)) => {
+                if self.remove_breakpoint(*addr) {
+                    Ok(format!("deleted breakpoint at {}\n", self.describe(*addr)))
+                } else {
+                    Err(format!("no breakpoint at {}", self.describe(*addr)))
+                }
+            }
+            Command::Delete(None) => {
+                self.breakpoints.clear();
+                Ok("deleted all breakpoints\n".to_owned())
+            }
+            Command::Breakpoints => {
+                if self.breakpoints.is_empty() {
+                    return Ok("no breakpoints\n".to_owned());
+                }
+                Ok(self
+                    .breakpoints
+                    .iter()
+                    .map(|addr| format!("{}\n", self.describe(*addr)))
+                    .collect())
+            }
+            Command::Watch(Some(addr)) => {
+                self.machine.bus_mut().watch(*addr);
+                Ok(format!("watchpoint at {}\n", self.describe(*addr)))
+            }
+            Command::Watch(None) => {
+                let watchpoints = self.machine.bus().watchpoints();
+                if watchpoints.is_empty() {
+                    return Ok("no watchpoints\n".to_owned());
+                }
+                Ok(watchpoints
+                    .iter()
+                    .map(|addr| format!("{}\n", self.describe(*addr)))
+                    .collect())
+            }
+            Command::Unwatch(Some(addr)) => {
+                if self.machine.bus_mut().unwatch(*addr) {
+                    Ok(format!("deleted watchpoint at {}\n", self.describe(*addr)))
+                } else {
+                    Err(format!("no watchpoint at {}", self.describe(*addr)))
+                }
+            }
+            Command::Unwatch(None) => {
+                let watchpoints: Vec<u16> =
+                    self.machine.bus().watchpoints().iter().copied().collect();
+                for addr in watchpoints {
+                    self.machine.bus_mut().unwatch(addr);
+                }
+                Ok("deleted all watchpoints\n".to_owned())
+            }
+            Command::Step(count) => {
+                for _ in 0..*count {
+                    if self.machine.is_halted() || self.watch_hit() {
+                        break;
+                    }
+                    self.machine.step().map_err(|e| self.error(e))?;
+                }
+                Ok(self.stop_report())
+            }
+            Command::Next => {
+                self.step_over().map_err(|e| self.error(e))?;
+                Ok(self.stop_report())
+            }
+            Command::Finish => {
+                self.finish().map_err(|e| self.error(e))?;
+                Ok(self.stop_report())
+            }
+            Command::Continue => {
+                self.continue_execution().map_err(|e| self.error(e))?;
+                Ok(self.stop_report())
+            }
+            Command::ReverseStep(count) => {
+                for _ in 0..*count {
+                    if !self.machine.reverse_step().map_err(|e| self.error(e))? {
+                        return Ok(format!("no more history\n{}", self.location()));
+                    }
+                }
+                Ok(self.location())
+            }
+            Command::ReverseContinue => {
+                if !self.reverse_continue().map_err(|e| self.error(e))? {
+                    return Ok(format!("no more history\n{}", self.location()));
+                }
+                Ok(self.location())
+            }
+            Command::Registers => Ok(format!(
+                "PC: {}\n{}\n",
+                self.describe(self.machine.pc()),
+                self.machine.print_registers()
+            )),
+            Command::Psr => {
+                let psr = self.machine.psr();
+                let mut out = format!(
+                    "priority: {} supervisor: {}\n{}",
+                    psr.priority,
+                    psr.supervisor,
+                    self.machine.print_nzp()
+                );
+                for request in self.machine.interrupts().pending() {
+                    out += &format!(
+                        "pending interrupt x{:02x} at priority {}\n",
+                        request.vector, request.priority
+                    );
+                }
+                Ok(out)
+            }
+            Command::Interrupt(vector, priority) => {
+                self.machine.raise_interrupt(*priority, *vector);
+                Ok(format!(
+                    "raised interrupt x{:02x} at priority {}\n",
+                    vector, priority
+                ))
+            }
+            Command::Asg => Ok(self.machine.print_asg()),
+            Command::Examine(addr, count) => Ok(self.examine(*addr, *count)),
+            Command::Print => Ok(self.machine.pretty_print()),
+            Command::Dump(range, format, file) => {
+                let words: Vec<u16> = range
+                    .clone()
+                    .map(|addr| self.machine.read_memory(addr))
+                    .collect();
+                let out = dump(&words, *range.start(), *format, self.machine.symbols());
+                match file {
+                    Some(path) => {
+                        fs::write(path, out).map_err(|e| format!("{}: {}", path.display(), e))?;
+                        Ok(format!(
+                            "wrote {} words at 0x{:04x} to {}\n",
+                            words.len(),
+                            range.start(),
+                            path.display()
+                        ))
+                    }
+                    None => Ok(String::from_utf8_lossy(&out).into_owned()),
+                }
+            }
+            Command::SaveState(path) => {
+                self.machine
+                    .snapshot()
+                    .write(path)
+                    .map_err(|e| e.to_string())?;
+                Ok(format!(
+                    "saved the machine after {} instructions to {}\n",
+                    self.machine.steps(),
+                    path.display()
+                ))
+            }
+            Command::LoadState(path) => {
+                let snapshot = Snapshot::read(path).map_err(|e| e.to_string())?;
+                self.machine.restore_snapshot(&snapshot)?;
+                Ok(format!("loaded {}\n{}", path.display(), self.location()))
+            }
+            Command::Help => Ok(HELP.to_owned()),
+            Command::Quit => Ok(String::new()),
+        }
+    }
+
+    // true if the instruction enters a subroutine or trap handler that returns to the next instruction
+    fn is_call(&self, instruction: &Instruction) -> bool {
+        match instruction.operation {
+            Operation::JSR | Operation::JSRR => true,
+            _ => instruction
+                .trap_vector()
+                .is_some_and(|vector| self.machine.trap_handler(vector).is_some()),
+        }
+    }
+
+    // execute one instruction, running over subroutine calls and trap handlers
+    pub fn step_over(&mut self) -> Result<(), SimError> {
+        let pc = self.machine.pc();
+        let (instruction, length) = self.machine.decode_at(pc)?;
+        if self.is_call(&instruction) {
+            let return_addr = pc.wrapping_add(length);
+            self.machine.step()?;
+            self.run_to(|m| m.pc() == return_addr)
+        } else {
+            self.machine.step()
+        }
+    }
+
+    // run until the current subroutine executes its RET, or the current trap handler its RTI,
+    // interrupt and exception handlers entered on the way return with their own RTI
+    pub fn finish(&mut self) -> Result<(), SimError> {
+        let mut depth = 0usize;
+        let mut first = true;
+        while !self.machine.is_halted() {
+            if !first && self.breakpoints.contains(&self.machine.pc()) {
+                return Ok(());
+            }
+            first = false;
+            let entries = self.machine.handler_entries();
+            self.machine.step()?;
+            if self.watch_hit() {
+                return Ok(());
+            }
+            depth += (self.machine.handler_entries() - entries) as usize;
+            let instruction = match self.machine.executed() {
+                Some(instruction) => instruction,
+                None => continue,
+            };
+            match instruction.operation {
+                _ if self.is_call(instruction) => depth += 1,
+                Operation::RET | Operation::RTI if depth == 0 => return Ok(()),
+                Operation::RET | Operation::RTI => depth -= 1,
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    // run until a breakpoint or HALT, always executing at least one instruction
+    pub fn continue_execution(&mut self) -> Result<(), SimError> {
+        self.machine.step()?;
+        self.run_to(|_| false)
+    }
+
+    // run at most `limit` instructions, so a front end can check for keys in between,
+    // returns true if execution stopped at a breakpoint, watchpoint or HALT first
+    pub fn run_for(&mut self, limit: usize) -> Result<bool, SimError> {
+        let mut count = 0;
+        self.run_to(|_| {
+            count += 1;
+            count > limit
+        })?;
+        Ok(count <= limit)
+    }
+
+    // step backwards until a breakpoint, returns false if the history ran out first
+    pub fn reverse_continue(&mut self) -> Result<bool, SimError> {
+        loop {
+            if !self.machine.reverse_step()? {
+                return Ok(false);
+            }
+            if self.breakpoints.contains(&self.machine.pc()) {
+                return Ok(true);
+            }
+        }
+    }
+
+    // run until `stop`, a breakpoint or a watchpoint
+    fn run_to<F: FnMut(&Machine<C>) -> bool>(&mut self, mut stop: F) -> Result<(), SimError> {
+        let breakpoints = &self.breakpoints;
+        self.machine.run_until(|m| {
+            breakpoints.contains(&m.pc()) || !m.bus().watch_hits().is_empty() || stop(m)
+        })
+    }
+
+    fn watch_hit(&self) -> bool {
+        !self.machine.bus().watch_hits().is_empty()
+    }
+
+    // the watchpoints that stopped execution followed by the location
+    pub fn stop_report(&mut self) -> String {
+        let mut out = String::new();
+        for access in self.machine.bus_mut().take_watch_hits() {
+            let kind = match access.kind {
+                AccessKind::Write => "store",
+                _ => "load",
+            };
+            out += &format!(
+                "watchpoint {}: {} 0x{:04x}\n",
+                self.describe(access.addr),
+                kind,
+                access.value
+            );
+        }
+        out + &self.location()
+    }
+
+    // describe where execution stopped, with the source line when the line map has it and the
+    // disassembly otherwise
+    pub fn location(&self) -> String {
+        if self.machine.is_halted() {
+            return "halted\n".to_owned();
+        }
+        let pc = self.machine.pc();
+        let mut out = String::new();
+        if self.breakpoints.contains(&pc) {
+            out += "breakpoint ";
+        }
+        if let Some(source) = self.machine.line_map().source(pc) {
+            return out + &format!("{}: {}\n", self.describe(pc), source);
+        }
+        match self.machine.decode_at(pc) {
+            Ok((instruction, _)) => {
+                out += &format!(
+                    "{}: {}\n",
+                    self.describe(pc),
+                    instruction.with(self.machine.symbols())
+                )
+            }
+            Err(error) => out += &format!("{}: {}\n", self.describe(pc), self.error(error)),
+        }
+        out
+    }
+
+    fn examine(&self, addr: u16, count: u16) -> String {
+        let mut out = String::new();
+        for i in 0..count {
+            let addr = addr.wrapping_add(i);
+            let value = self.machine.read_memory(addr);
+            out += &format!("{}: 0x{:04x} {:016b}\n", self.describe(addr), value, value);
+        }
+        out
+    }
+}
+
+// read commands from input until quit or the end of input
+pub fn run_repl<C: Console, R: BufRead, W: Write>(
+    debugger: &mut Debugger<C>,
+    mut input: R,
+    mut output: W,
+) -> Result<(), String> {
+    let io_error = |e: std::io::Error| e.to_string();
+    let mut last: Option<Command> = None;
+    write!(output, "{}", debugger.location()).map_err(io_error)?;
+    loop {
+        write!(output, "(lc4) ").map_err(io_error)?;
+        output.flush().map_err(io_error)?;
+        let mut line = String::new();
+        if input.read_line(&mut line).map_err(io_error)? == 0 {
+            return Ok(());
+        }
+        let command = if line.trim().is_empty() {
+            match &last {
+                Some(command) => command.clone(),
+                None => continue,
+            }
+        } else {
+            match debugger.parse(&line) {
+                Ok(command) => command,
+                Err(error) => {
+                    writeln!(output, "{}", error).map_err(io_error)?;
+                    continue;
+                }
+            }
+        };
+        if command == Command::Quit {
+            return Ok(());
+        }
+        match debugger.execute(&command) {
+            Ok(text) => write!(output, "{}", text),
+            Err(error) => writeln!(output, "error: {}", error),
+        }
+        .map_err(io_error)?;
+        last = Some(command);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble_memory;
+    use crate::console::{BufferConsole, SharedReader, StreamConsole};
+    use crate::interrupt::{INTERRUPT_TABLE, PRIVILEGE_VECTOR};
+
+    const HANDLER: u16 = 0x4000;
+
+    // the subroutine at SUB is called from 0x3000 and returns to the HALT at 0x3002
+    const PROGRAM: &str = "
+        .ORIG x3000
+        JSR SUB
+        HALT
+SUB     ADD R1, R1, #1
+        ADD R1, R1, #1
+        RET
+        .END
+    ";
+
+    // stopped at the start of SUB, with handler installed for the vector and the privilege exception
+    fn in_subroutine(vector: u8) -> Debugger<BufferConsole> {
+        let mut mem = assemble_memory(&[PROGRAM, ".ORIG x4000\nADD R2, R2, #1\nRTI\n.END\n"]);
+        mem[(INTERRUPT_TABLE + vector as u16) as usize] = HANDLER;
+        mem[(INTERRUPT_TABLE + PRIVILEGE_VECTOR as u16) as usize] = HANDLER;
+        let mut debugger = Debugger::new(Machine::new(Some(mem), BufferConsole::new(b"")));
+        debugger.machine_mut().step().unwrap();
+        assert_eq!(debugger.machine().pc(), 0x3003);
+        debugger
+    }
+
+    #[test]
+    fn finish_runs_over_interrupt_handlers() {
+        let mut debugger = in_subroutine(0x90);
+        debugger.machine_mut().raise_interrupt(u3::new(4), 0x90);
+        debugger.finish().unwrap();
+        assert_eq!(debugger.machine().pc(), 0x3002);
+        assert_eq!(debugger.machine().register(1), 2);
+        assert_eq!(debugger.machine().register(2), 1);
+    }
+
+    #[test]
+    fn finish_runs_over_exception_handlers() {
+        let mut debugger = in_subroutine(0x90);
+        // RTI in user mode is a privilege exception, its handler returns to the instruction after it
+        debugger.machine_mut().write_memory(0x3004, 0b01101 << 11);
+        debugger.finish().unwrap();
+        assert_eq!(debugger.machine().pc(), 0x3002);
+        assert_eq!(debugger.machine().register(1), 1);
+        assert_eq!(debugger.machine().register(2), 1);
+    }
+
+    #[test]
+    fn repl_commands_and_program_input_share_a_stream() {
+        let mem = assemble_memory(&[".ORIG x3000\nGETC\nOUT\nHALT\n.END\n"]);
+        // GETC reads the x typed after the continue command, then q quits
+        let input = SharedReader::new(&b"c\nxq\n"[..]);
+        let console = StreamConsole::new(input.clone(), Vec::new());
+        let mut debugger = Debugger::new(Machine::new(Some(mem), console));
+        let mut output = Vec::new();
+        run_repl(&mut debugger, input, &mut output).unwrap();
+        assert!(debugger.machine().is_halted());
+        assert_eq!(debugger.machine().register(0), b'x' as i16);
+        assert!(String::from_utf8(output).unwrap().contains("halted"));
+    }
+}
@@ -0,0 +1,348 @@
+use crate::bus::{Bus, Device, DeviceContext};
+use std::io;
+use ux::u3;
+
+// memory mapped device registers, the page from 0xFE00 up is reserved for them
+pub const DEVICE_PAGE: u16 = 0xFE00;
+// keyboard status and data
+pub const KBSR: u16 = 0xFE00;
+pub const KBDR: u16 = 0xFE02;
+// display status and data
+pub const DSR: u16 = 0xFE04;
+pub const DDR: u16 = 0xFE06;
+// interval timer control, period and count
+pub const TCR: u16 = 0xFE08;
+pub const TPR: u16 = 0xFE0A;
+pub const TCNT: u16 = 0xFE0C;
+// machine control, clearing bit 15 stops the clock
+pub const MCR: u16 = 0xFFFE;
+
+// status register bits
+pub const READY: u16 = 1 << 15;
+pub const INTERRUPT_ENABLE: u16 = 1 << 14;
+pub const CLOCK_ENABLE: u16 = 1 << 15;
+pub const TIMER_ENABLE: u16 = 1;
+// bits 10-8 of TCR, the priority of the timer interrupt
+const TIMER_PRIORITY: u16 = 0b111 << 8;
+
+// the keyboard interrupts through x80 at priority 4 when its interrupt enable bit is set
+pub const KEYBOARD_VECTOR: u8 = 0x80;
+pub fn keyboard_priority() -> u3 {
+    u3::new(4)
+}
+
+// the timer interrupts through x81 at the priority in its control register
+pub const TIMER_VECTOR: u8 = 0x81;
+
+// the keyboard, display and machine control register every machine starts with
+pub fn attach_standard_devices(bus: &mut Bus) -> Result<(), String> {
+    bus.register(KBSR..=KBDR + 1, Box::new(Keyboard::default()))?;
+    bus.register(DSR..=DDR + 1, Box::new(Display::default()))?;
+    bus.register(TCR..=TCNT + 3, Box::new(Timer::default()))?;
+    bus.register(MCR..=MCR + 1, Box::new(MachineControl::default()))
+}
+
+// KBSR holds the ready and interrupt enable bits, KBDR the last key
+#[derive(Default)]
+pub struct Keyboard {
+    status: u16,
+    data: u16,
+}
+
+impl Keyboard {
+    // latch a key into KBDR if the keyboard is not already holding one
+    fn poll(&mut self, ctx: &mut DeviceContext) -> io::Result<()> {
+        if self.status & READY == 0 && ctx.poll_key()? {
+            // the end of input reads as a null character
+            self.data = ctx.read_key()?.unwrap_or(b'\0') as u16;
+            self.status |= READY;
+        }
+        Ok(())
+    }
+}
+
+impl Device for Keyboard {
+    fn read(&mut self, addr: u16, ctx: &mut DeviceContext) -> io::Result<u16> {
+        match addr {
+            KBSR => {
+                self.poll(ctx)?;
+                Ok(self.status)
+            }
+            KBDR => {
+                self.poll(ctx)?;
+                self.status &= !READY;
+                Ok(self.data)
+            }
+            _ => Ok(0),
+        }
+    }
+
+    fn write(&mut self, addr: u16, value: u16, ctx: &mut DeviceContext) -> io::Result<()> {
+        if addr == KBSR {
+            self.status = self.status & READY | value & INTERRUPT_ENABLE;
+            if value & INTERRUPT_ENABLE == 0 {
+                ctx.interrupts.clear(KEYBOARD_VECTOR);
+            }
+        }
+        Ok(())
+    }
+
+    fn peek(&self, addr: u16) -> u16 {
+        match addr {
+            KBSR => self.status,
+            KBDR => self.data,
+            _ => 0,
+        }
+    }
+
+    // raise the keyboard interrupt when it is enabled and a key is waiting
+    fn tick(&mut self, ctx: &mut DeviceContext) -> io::Result<()> {
+        if self.status & INTERRUPT_ENABLE != 0 {
+            self.poll(ctx)?;
+            if self.status & READY != 0 {
+                ctx.interrupts.raise(keyboard_priority(), KEYBOARD_VECTOR);
+            }
+        }
+        Ok(())
+    }
+
+    fn save(&self) -> Vec<u16> {
+        vec![self.status, self.data]
+    }
+
+    fn restore(&mut self, state: &[u16]) {
+        if let [status, data] = state {
+            self.status = *status;
+            self.data = *data;
+        }
+    }
+}
+
+// the display is always ready, DSR only keeps the interrupt enable bit
+#[derive(Default)]
+pub struct Display {
+    status: u16,
+}
+
+impl Device for Display {
+    fn read(&mut self, addr: u16, _ctx: &mut DeviceContext) -> io::Result<u16> {
+        Ok(self.peek(addr))
+    }
+
+    fn write(&mut self, addr: u16, value: u16, ctx: &mut DeviceContext) -> io::Result<()> {
+        match addr {
+            DSR => self.status = value & INTERRUPT_ENABLE,
+            DDR => ctx.write_output(&[value as u8])?,
+            _ => (),
+        }
+        Ok(())
+    }
+
+    fn peek(&self, addr: u16) -> u16 {
+        match addr {
+            DSR => READY | self.status,
+            _ => 0,
+        }
+    }
+
+    fn save(&self) -> Vec<u16> {
+        vec![self.status]
+    }
+
+    fn restore(&mut self, state: &[u16]) {
+        if let [status] = state {
+            self.status = *status;
+        }
+    }
+}
+
+// counts down once per instruction while enabled, when the count runs out it sets the ready bit,
+// raises its interrupt if that is enabled and starts over from the period
+#[derive(Default)]
+pub struct Timer {
+    // ready, interrupt enable, priority and enable bits
+    control: u16,
+    period: u16,
+    count: u16,
+}
+
+impl Device for Timer {
+    fn read(&mut self, addr: u16, _ctx: &mut DeviceContext) -> io::Result<u16> {
+        let value = self.peek(addr);
+        // reading the status acknowledges the expiry, so polling sees each one once
+        if addr == TCR {
+            self.control &= !READY;
+        }
+        Ok(value)
+    }
+
+    fn write(&mut self, addr: u16, value: u16, ctx: &mut DeviceContext) -> io::Result<()> {
+        match addr {
+            TCR => {
+                // starting the timer loads the count from the period
+                if self.control & TIMER_ENABLE == 0 && value & TIMER_ENABLE != 0 {
+                    self.count = self.period;
+                }
+                self.control = self.control & READY
+                    | value & (INTERRUPT_ENABLE | TIMER_PRIORITY | TIMER_ENABLE);
+                if value & INTERRUPT_ENABLE == 0 {
+                    ctx.interrupts.clear(TIMER_VECTOR);
+                }
+            }
+            TPR => self.period = value,
+            TCNT => self.count = value,
+            _ => (),
+        }
+        Ok(())
+    }
+
+    fn peek(&self, addr: u16) -> u16 {
+        match addr {
+            TCR => self.control,
+            TPR => self.period,
+            TCNT => self.count,
+            _ => 0,
+        }
+    }
+
+    fn tick(&mut self, ctx: &mut DeviceContext) -> io::Result<()> {
+        if self.control & TIMER_ENABLE == 0 || self.period == 0 {
+            return Ok(());
+        }
+        self.count = self.count.saturating_sub(1);
+        if self.count == 0 {
+            self.count = self.period;
+            self.control |= READY;
+            if self.control & INTERRUPT_ENABLE != 0 {
+                let priority = u3::new((self.control >> 8 & 0b111) as u8);
+                ctx.interrupts.raise(priority, TIMER_VECTOR);
+            }
+        }
+        Ok(())
+    }
+
+    fn save(&self) -> Vec<u16> {
+        vec![self.control, self.period, self.count]
+    }
+
+    fn restore(&mut self, state: &[u16]) {
+        if let [control, period, count] = state {
+            self.control = *control;
+            self.period = *period;
+            self.count = *count;
+        }
+    }
+}
+
+// MCR, the clock enable bit reads as set while the machine is running
+pub struct MachineControl {
+    value: u16,
+}
+
+impl Default for MachineControl {
+    fn default() -> MachineControl {
+        MachineControl {
+            value: CLOCK_ENABLE,
+        }
+    }
+}
+
+impl Device for MachineControl {
+    fn read(&mut self, addr: u16, ctx: &mut DeviceContext) -> io::Result<u16> {
+        match addr {
+            MCR if ctx.is_running() => Ok(self.value | CLOCK_ENABLE),
+            MCR => Ok(self.value & !CLOCK_ENABLE),
+            _ => Ok(0),
+        }
+    }
+
+    fn write(&mut self, addr: u16, value: u16, ctx: &mut DeviceContext) -> io::Result<()> {
+        if addr == MCR {
+            self.value = value;
+            if value & CLOCK_ENABLE == 0 {
+                ctx.halt();
+            }
+        }
+        Ok(())
+    }
+
+    fn peek(&self, addr: u16) -> u16 {
+        match addr {
+            MCR => self.value,
+            _ => 0,
+        }
+    }
+
+    fn save(&self) -> Vec<u16> {
+        vec![self.value]
+    }
+
+    fn restore(&mut self, state: &[u16]) {
+        if let [value] = state {
+            self.value = *value;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble_memory;
+    use crate::console::BufferConsole;
+    use crate::interrupt::INTERRUPT_TABLE;
+    use crate::machine::{Machine, PSR};
+
+    // a supervisor mode program with its stack at x2FFF that starts the timer with R0 in TPR and R1 in TCR, then runs LOOP
+    fn start_timer(period: i16, control: u16, loop_body: &str) -> Machine<BufferConsole> {
+        let program = format!(
+            ".ORIG x3000\nST R0, xFE0A\nST R1, xFE08\n{}\nHALT\n.END\n",
+            loop_body
+        );
+        let mut mem = assemble_memory(&[
+            &program,
+            ".ORIG x4000\nADD R2, R2, #1\nLD R3, xFE08\nRTI\n.END\n",
+        ]);
+        mem[(INTERRUPT_TABLE + TIMER_VECTOR as u16) as usize] = 0x4000;
+        let mut machine = Machine::new(Some(mem), BufferConsole::new(b""));
+        machine.set_psr(PSR {
+            supervisor: true,
+            ..machine.psr().clone()
+        });
+        machine.set_register(6, 0x2FFF);
+        machine.set_register(0, period);
+        machine.set_register(1, control as i16);
+        machine
+    }
+
+    // counts the polls in R4 until the ready bit shows up in R5
+    const POLL: &str = "LOOP ADD R4, R4, #1\nLD R5, xFE08\nBRzp LOOP";
+
+    #[test]
+    fn the_timer_sets_its_ready_bit_once_per_period() {
+        for period in [3, 9] {
+            let mut machine = start_timer(period, TIMER_ENABLE, POLL);
+            machine.run_machine().unwrap();
+            // one tick per instruction, a poll takes three
+            assert_eq!(machine.register(4), period / 3 + 1, "period {}", period);
+            assert_eq!(machine.register(5) as u16, READY | TIMER_ENABLE);
+            assert_eq!(machine.read_memory(TPR), period as u16);
+        }
+    }
+
+    #[test]
+    fn the_timer_interrupts_every_period() {
+        let control = INTERRUPT_ENABLE | 2 << 8 | TIMER_ENABLE;
+        let mut machine = start_timer(8, control, "LOOP ADD R4, R4, #1\nBRnzp LOOP");
+        let mut entries = Vec::new();
+        for step in 0..40 {
+            machine.step().unwrap();
+            if machine.handler_entries() > entries.len() as u64 {
+                entries.push(step);
+                assert_eq!(machine.psr().priority, u3::new(2));
+            }
+        }
+        assert_eq!(entries.len(), 4);
+        assert!(entries.windows(2).all(|pair| pair[1] - pair[0] == 8));
+        assert_eq!(machine.register(2), 4);
+    }
+}
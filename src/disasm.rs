@@ -0,0 +1,234 @@
+use crate::symbols::{Symbolic, Symbols};
+use crate::tokenizer::{check_instruction_double, tokenize, Instruction, Operand, Operation};
+use std::fmt;
+
+impl Operation {
+    // assembly mnemonic, the operand forms of an instruction share one name
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            Operation::ADD | Operation::ADDi | Operation::ADDi16 | Operation::ADDa => "ADD",
+            Operation::AND | Operation::ANDi | Operation::ANDi16 | Operation::ANDa => "AND",
+            Operation::XOR | Operation::XORi | Operation::XORi16 | Operation::XORa => "XOR",
+            Operation::BR => "BR",
+            Operation::JUMP => "JUMP",
+            Operation::RET => "RET",
+            Operation::JSR => "JSR",
+            Operation::JSRR => "JSRR",
+            Operation::LD | Operation::LDa => "LD",
+            Operation::ST => "ST",
+            Operation::STR | Operation::STR16 => "STR",
+            Operation::NOT => "NOT",
+            Operation::TRAP => "TRAP",
+            Operation::RTI => "RTI",
+            Operation::LSD => "LSD",
+            Operation::LPN => "LPN",
+            Operation::CLRP => "CLRP",
+            Operation::HALT => "HALT",
+            Operation::PUTS => "PUTS",
+            Operation::GETC => "GETC",
+            Operation::OUT => "OUT",
+            Operation::IN => "IN",
+            Operation::PUTSP => "PUTSP",
+        }
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.fmt_with(f, &Symbols::default())
+    }
+}
+
+impl Symbolic for Operand {
+    fn fmt_with(&self, f: &mut fmt::Formatter<'_>, symbols: &Symbols) -> fmt::Result {
+        match self {
+            Operand::BR(flags) => {
+                for (set, name) in [(flags.n, 'n'), (flags.z, 'z'), (flags.p, 'p')] {
+                    if set {
+                        write!(f, "{}", name)?;
+                    }
+                }
+                Ok(())
+            }
+            Operand::Address(addr) => write!(f, "{}", symbols.describe(*addr)),
+            Operand::Imm16(num) => write!(f, "#{}", num),
+            Operand::Imm7(num) => write!(f, "#{}", num),
+            Operand::Imm3(num) => write!(f, "#{}", num),
+            Operand::TrapVect(vect) => write!(f, "x{:02x}", vect),
+            Operand::Register(reg) => write!(f, "R{}", reg),
+        }
+    }
+}
+
+impl Instruction {
+    // the mnemonic the assembler turns back into this instruction, a 16-bit immediate small enough
+    // for the short form is written ADD16, AND16, XOR16 or STR16
+    pub fn mnemonic(&self) -> &'static str {
+        let fits = |low, high| matches!(self.operand2, Some(Operand::Imm16(n)) if (low..=high).contains(&n));
+        match self.operation {
+            Operation::ADDi16 if fits(-4, 3) => "ADD16",
+            Operation::ANDi16 if fits(-4, 3) => "AND16",
+            Operation::XORi16 if fits(-4, 3) => "XOR16",
+            Operation::STR16 if fits(-64, 63) => "STR16",
+            operation => operation.mnemonic(),
+        }
+    }
+}
+
+// LC-4 assembly syntax, e.g. `ADD R1, R2, #3`, `BRnz 0x3010` or `TRAP x30`
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.fmt_with(f, &Symbols::default())
+    }
+}
+
+// with symbols an address operand gets its label, `BRnz 0x3010 <LOOP>`
+impl Symbolic for Instruction {
+    fn fmt_with(&self, f: &mut fmt::Formatter<'_>, symbols: &Symbols) -> fmt::Result {
+        write!(f, "{}", self.mnemonic())?;
+        let mut operands = [&self.dr, &self.operand1, &self.operand2]
+            .into_iter()
+            .flatten()
+            .peekable();
+        // branch flags are part of the mnemonic
+        if let Some(Operand::BR(flags)) = operands.peek() {
+            write!(f, "{}", Operand::BR(flags.clone()))?;
+            operands.next();
+        }
+        for (i, operand) in operands.enumerate() {
+            write!(
+                f,
+                "{}{}",
+                if i == 0 { " " } else { ", " },
+                operand.with(symbols)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// one line of a listing, an instruction or a data word
+pub struct Line {
+    pub addr: u16,
+    pub words: Vec<u16>,
+    pub instruction: Option<Instruction>,
+}
+
+impl fmt::Display for Line {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.fmt_with(f, &Symbols::default())
+    }
+}
+
+impl Symbolic for Line {
+    fn fmt_with(&self, f: &mut fmt::Formatter<'_>, symbols: &Symbols) -> fmt::Result {
+        let raw: Vec<String> = self.words.iter().map(|w| format!("{:04x}", w)).collect();
+        write!(f, "0x{:04x}  {:<9}  ", self.addr, raw.join(" "))?;
+        match &self.instruction {
+            Some(instruction) => write!(f, "{}", instruction.with(symbols)),
+            None => {
+                let word = self.words[0];
+                write!(f, ".FILL 0x{:04x}", word)?;
+                // most data is text, so show printable characters
+                if (0x20..0x7f).contains(&word) {
+                    write!(f, " ; '{}'", word as u8 as char)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+// decode a block of words loaded at origin, words that are not instructions become .FILL data
+pub fn disassemble(words: &[u16], origin: u16) -> Vec<Line> {
+    let mut lines = Vec::new();
+    let mut i = 0;
+    while i < words.len() {
+        let addr = origin.wrapping_add(i as u16);
+        let word = words[i];
+        // a decoded instruction's length bit always matches its length
+        let length = if check_instruction_double(word) { 2 } else { 1 };
+        match tokenize(word, words.get(i + 1).copied()) {
+            Ok(instruction) => {
+                lines.push(Line {
+                    addr,
+                    words: words[i..i + length].to_vec(),
+                    instruction: Some(instruction),
+                });
+                i += length;
+            }
+            Err(_) => {
+                lines.push(Line {
+                    addr,
+                    words: vec![word],
+                    instruction: None,
+                });
+                i += 1;
+            }
+        }
+    }
+    lines
+}
+
+// disassemble and render as text, one line per instruction, with a `LABEL:` line before every labelled
+// address and addresses named after the symbols
+pub fn listing(words: &[u16], origin: u16, symbols: &Symbols) -> String {
+    let mut out = String::new();
+    for line in disassemble(words, origin) {
+        if let Some(label) = symbols.label(line.addr) {
+            out += &format!("{}:\n", label);
+        }
+        out += &format!("{}\n", line.with(symbols));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+
+    // small and large immediates and addresses
+    const SECOND_WORDS: [u16; 4] = [0x0000, 0x0003, 0xffff, 0x3000];
+
+    #[test]
+    fn every_instruction_reassembles_to_itself() {
+        for word in 0..=u16::MAX {
+            for second in SECOND_WORDS {
+                let instruction = match tokenize(word, Some(second)) {
+                    Ok(instruction) => instruction,
+                    Err(_) => continue,
+                };
+                let text = instruction.to_string();
+                let program = assemble(&format!(".ORIG x3000\n{}\n.END\n", text))
+                    .unwrap_or_else(|e| panic!("{}: {}", text, e));
+                let words = &program.words;
+                assert_eq!(
+                    tokenize(words[0], words.get(1).copied()).as_ref(),
+                    Ok(&instruction),
+                    "{}",
+                    text
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn small_16_bit_immediates_keep_their_form() {
+        for (words, text) in [
+            (
+                [0b00001 << 11 | 1 << 10 | 1 << 7 | 2 << 4 | 1 << 3, 3],
+                "ADD16 R1, R2, #3",
+            ),
+            (
+                [0b00001 << 11 | 1 << 10 | 1 << 7 | 2 << 4 | 1 << 3, 4],
+                "ADD R1, R2, #4",
+            ),
+            ([0b00111 << 11 | 1 << 10 | 1 << 7, 0xffc0], "STR16 R1, #-64"),
+            ([0b00111 << 11 | 1 << 10 | 1 << 7, 0xffbf], "STR R1, #-65"),
+        ] {
+            let instruction = tokenize(words[0], Some(words[1])).unwrap();
+            assert_eq!(instruction.to_string(), text);
+        }
+    }
+}
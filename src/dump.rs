@@ -0,0 +1,170 @@
+use crate::disasm::listing;
+use crate::symbols::Symbols;
+use std::ops::RangeInclusive;
+
+// words per line of a hex dump
+const HEX_WIDTH: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DumpFormat {
+    // the origin followed by the words, big endian, the format read_input_files loads
+    Image,
+    // address, words and the low byte of each word as a character
+    Hex,
+    // the disassembler's listing, with raw words and the characters of printable data
+    Disassembly,
+}
+
+impl DumpFormat {
+    pub fn from_name(name: &str) -> Option<DumpFormat> {
+        match name {
+            "image" | "bin" => Some(DumpFormat::Image),
+            "hex" => Some(DumpFormat::Hex),
+            "disasm" => Some(DumpFormat::Disassembly),
+            _ => None,
+        }
+    }
+}
+
+// a range of addresses written as START-END, both included, like x4000-x40ff
+pub fn parse_range(text: &str) -> Result<RangeInclusive<u16>, String> {
+    parse_symbolic_range(text, &Symbols::default())
+}
+
+// the ends can also be labels, like BUFFER-BUFFER+31
+pub fn parse_symbolic_range(text: &str, symbols: &Symbols) -> Result<RangeInclusive<u16>, String> {
+    let (start, end) = text
+        .split_once('-')
+        .ok_or_else(|| format!("invalid range: {} (expected START-END)", text))?;
+    let (start, end) = (symbols.resolve(start)?, symbols.resolve(end)?);
+    if start > end {
+        return Err(format!("invalid range: {} ends before it starts", text));
+    }
+    Ok(start..=end)
+}
+
+// render the words loaded at origin, the listing names addresses after the symbols
+pub fn dump(words: &[u16], origin: u16, format: DumpFormat, symbols: &Symbols) -> Vec<u8> {
+    match format {
+        DumpFormat::Image => std::iter::once(origin)
+            .chain(words.iter().copied())
+            .flat_map(u16::to_be_bytes)
+            .collect(),
+        DumpFormat::Hex => hex_dump(words, origin).into_bytes(),
+        DumpFormat::Disassembly => listing(words, origin, symbols).into_bytes(),
+    }
+}
+
+// characters are stored one per word, so the text column shows the words that are printable characters
+fn hex_dump(words: &[u16], origin: u16) -> String {
+    let mut out = String::new();
+    for (i, chunk) in words.chunks(HEX_WIDTH).enumerate() {
+        let addr = origin.wrapping_add((i * HEX_WIDTH) as u16);
+        let hex: Vec<String> = chunk.iter().map(|w| format!("{:04x}", w)).collect();
+        let text: String = chunk
+            .iter()
+            .map(|&w| match w {
+                0x20..=0x7e => w as u8 as char,
+                _ => '.',
+            })
+            .collect();
+        out += &format!(
+            "0x{:04x}  {:<width$}  {}\n",
+            addr,
+            hex.join(" "),
+            text,
+            width = HEX_WIDTH * 5 - 1
+        );
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reader::read_input_files;
+
+    fn symbols() -> Symbols {
+        Symbols::parse("START 0x3000\nBUFFER 0x4000\n").unwrap()
+    }
+
+    // ADD R1, R1, #3, BRnzp START, HALT and "Hi"
+    const WORDS: [u16; 6] = [
+        0b00001 << 11 | 1 << 7 | 1 << 4 | 1 << 3 | 3,
+        0b00100 << 11 | 1 << 10 | 0b111 << 7,
+        0x3000,
+        0b01100 << 11 | 0x25,
+        b'H' as u16,
+        b'i' as u16,
+    ];
+
+    #[test]
+    fn ranges_are_start_and_end_included() {
+        assert_eq!(parse_range("x4000-x40ff"), Ok(0x4000..=0x40FF));
+        assert_eq!(parse_range("0x3000-0x3000"), Ok(0x3000..=0x3000));
+        assert_eq!(
+            parse_range("x4000"),
+            Err("invalid range: x4000 (expected START-END)".to_owned())
+        );
+        assert_eq!(
+            parse_range("x4001-x4000"),
+            Err("invalid range: x4001-x4000 ends before it starts".to_owned())
+        );
+    }
+
+    #[test]
+    fn range_ends_can_be_labels() {
+        let symbols = symbols();
+        assert_eq!(
+            parse_symbolic_range("BUFFER-BUFFER+31", &symbols),
+            Ok(0x4000..=0x401F)
+        );
+        assert_eq!(
+            parse_symbolic_range("START-x3005", &symbols),
+            Ok(0x3000..=0x3005)
+        );
+        assert_eq!(
+            parse_symbolic_range("START-END", &symbols),
+            Err("unknown label: END".to_owned())
+        );
+    }
+
+    #[test]
+    fn hex_dumps_show_the_printable_words_as_text() {
+        let mut words = WORDS.to_vec();
+        words.extend([b'!' as u16, 0, 0x1F]);
+        let text = dump(&words, 0x3000, DumpFormat::Hex, &symbols());
+        assert_eq!(
+            String::from_utf8(text).unwrap(),
+            "0x3000  089b 2780 3000 6025 0048 0069 0021 0000  ....Hi!.\n\
+             0x3008  001f                                     .\n"
+        );
+    }
+
+    #[test]
+    fn disassembly_dumps_are_listings_with_labels() {
+        let text = dump(&WORDS, 0x3000, DumpFormat::Disassembly, &symbols());
+        assert_eq!(
+            String::from_utf8(text).unwrap(),
+            "START:\n\
+             0x3000  089b       ADD R1, R1, #3\n\
+             0x3001  2780 3000  BRnzp 0x3000 <START>\n\
+             0x3003  6025       HALT\n\
+             0x3004  0048       .FILL 0x0048 ; 'H'\n\
+             0x3005  0069       .FILL 0x0069 ; 'i'\n"
+        );
+    }
+
+    #[test]
+    fn image_dumps_load_back_to_the_same_words() {
+        let bytes = dump(&WORDS, 0x5000, DumpFormat::Image, &symbols());
+        let dir = std::env::temp_dir().join(format!("lc4-dump-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("dump.obj");
+        std::fs::write(&path, bytes).unwrap();
+        let memory = read_input_files(&[path]).unwrap();
+        assert_eq!(memory[0x5000..0x5006], WORDS);
+        assert_eq!(memory[0x4FFF], 0);
+        assert_eq!(memory[0x5006], 0);
+    }
+}
@@ -0,0 +1,206 @@
+use crate::tokenizer::{tokenize, Instruction, Operand, Operation};
+
+// encode an instruction into its one or two words, the inverse of tokenize:
+// whenever this succeeds, tokenize gives back the same instruction
+pub fn encode(instruction: &Instruction) -> Result<Vec<u16>, String> {
+    let operation = instruction.operation;
+    let dr = || register(&instruction.dr, "destination register");
+    let sr = || register(&instruction.operand1, "source register");
+    let address = || match &instruction.operand2 {
+        Some(Operand::Address(addr)) => Ok(*addr),
+        other => Err(wrong_operand(other, "an address")),
+    };
+    let imm16 = || match &instruction.operand2 {
+        Some(Operand::Imm16(value)) => Ok(*value as u16),
+        other => Err(wrong_operand(other, "a 16 bit immediate")),
+    };
+    let imm7 = || match &instruction.operand1 {
+        Some(Operand::Imm7(value)) => Ok(i8::from(*value) as u16 & 0x7F),
+        other => Err(wrong_operand(other, "a 7 bit immediate")),
+    };
+    let words = match operation {
+        Operation::ADD | Operation::AND | Operation::XOR => {
+            let sr2 = register(&instruction.operand2, "second source register")?;
+            vec![opcode(operation) << 11 | dr()? << 7 | sr()? << 4 | sr2]
+        }
+        Operation::ADDi | Operation::ANDi | Operation::XORi => {
+            let imm3 = match &instruction.operand2 {
+                Some(Operand::Imm3(value)) => i8::from(*value) as u16 & 0b111,
+                other => return Err(wrong_operand(other, "a 3 bit immediate")),
+            };
+            vec![opcode(operation) << 11 | dr()? << 7 | sr()? << 4 | 1 << 3 | imm3]
+        }
+        Operation::ADDi16 | Operation::ANDi16 | Operation::XORi16 => vec![
+            opcode(operation) << 11 | 1 << 10 | dr()? << 7 | sr()? << 4 | 1 << 3,
+            imm16()?,
+        ],
+        Operation::ADDa | Operation::ANDa | Operation::XORa => vec![
+            opcode(operation) << 11 | 1 << 10 | dr()? << 7 | sr()? << 4,
+            address()?,
+        ],
+        Operation::BR => {
+            let flags = match &instruction.operand1 {
+                Some(Operand::BR(flags)) => {
+                    (flags.n as u16) << 2 | (flags.z as u16) << 1 | flags.p as u16
+                }
+                other => return Err(wrong_operand(other, "branch flags")),
+            };
+            vec![0b00100 << 11 | 1 << 10 | flags << 7, address()?]
+        }
+        Operation::JUMP => vec![0b00101 << 11 | dr()? << 7],
+        Operation::RET => vec![0b00101 << 11 | 0b111 << 7],
+        Operation::JSR => vec![0b00110 << 11 | 1 << 10, address()?],
+        Operation::JSRR => vec![0b00110 << 11 | dr()? << 7],
+        Operation::LD => vec![0b01000 << 11 | dr()? << 7 | imm7()?],
+        Operation::LDa => vec![0b01000 << 11 | 1 << 10 | dr()? << 7, address()?],
+        Operation::ST => vec![0b01001 << 11 | 1 << 10 | dr()? << 7, address()?],
+        Operation::STR => vec![0b00111 << 11 | dr()? << 7 | imm7()?],
+        Operation::STR16 => vec![0b00111 << 11 | 1 << 10 | dr()? << 7, imm16()?],
+        Operation::NOT => vec![0b01010 << 11 | dr()? << 7 | sr()? << 4],
+        Operation::RTI => vec![0b01101 << 11],
+        // TRAP and the named service routines
+        _ => match instruction.trap_vector() {
+            Some(vect) => vec![0b01100 << 11 | vect as u16],
+            None => return Err(wrong_operand(&instruction.operand1, "a trap vector")),
+        },
+    };
+    // some instructions share an encoding, JUMP R7 is RET and TRAP x25 is HALT,
+    // and operands the decoder doesn't produce have nowhere to go
+    match tokenize(words[0], words.get(1).copied()) {
+        Ok(decoded) if decoded == *instruction => Ok(words),
+        Ok(decoded) if decoded.operation != operation => Err(format!(
+            "{} has the encoding of {}, use {}",
+            instruction,
+            decoded,
+            decoded.operation.mnemonic()
+        )),
+        _ => Err(format!("{} has no encoding", instruction)),
+    }
+}
+
+// bits 15-11 of the three operand instructions
+fn opcode(operation: Operation) -> u16 {
+    match operation {
+        Operation::ADD | Operation::ADDi | Operation::ADDi16 | Operation::ADDa => 0b00001,
+        Operation::AND | Operation::ANDi | Operation::ANDi16 | Operation::ANDa => 0b00010,
+        _ => 0b00011,
+    }
+}
+
+fn register(operand: &Option<Operand>, name: &str) -> Result<u16, String> {
+    match operand {
+        Some(Operand::Register(reg)) => Ok(u16::from(*reg)),
+        other => Err(wrong_operand(other, &format!("a {}", name))),
+    }
+}
+
+fn wrong_operand(operand: &Option<Operand>, expected: &str) -> String {
+    match operand {
+        Some(operand) => format!("expected {}, found {}", expected, operand),
+        None => format!("expected {}", expected),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tokenizer::Flags;
+    use ux::u3;
+
+    const SECOND_WORDS: [u16; 5] = [0x0000, 0x0001, 0x7fff, 0x8000, 0xffff];
+
+    fn round_trip(instruction: &Instruction) {
+        let words = encode(instruction).unwrap_or_else(|e| panic!("{:?}: {}", instruction, e));
+        assert_eq!(
+            tokenize(words[0], words.get(1).copied()).as_ref(),
+            Ok(instruction),
+            "{:04x?}",
+            words
+        );
+    }
+
+    // every instruction the decoder can produce encodes back to words that decode to it
+    #[test]
+    fn every_decoded_instruction_round_trips() {
+        for word in 0..=u16::MAX {
+            if let Ok(instruction) = tokenize(word, None) {
+                round_trip(&instruction);
+            }
+            for second in SECOND_WORDS {
+                if let Ok(instruction) = tokenize(word, Some(second)) {
+                    round_trip(&instruction);
+                }
+            }
+        }
+    }
+
+    fn instruction(
+        operation: Operation,
+        dr: Option<Operand>,
+        operand1: Option<Operand>,
+        operand2: Option<Operand>,
+    ) -> Instruction {
+        Instruction {
+            operation,
+            dr,
+            operand1,
+            operand2,
+        }
+    }
+
+    #[test]
+    fn shared_encodings_are_rejected() {
+        // JUMP R7 is RET
+        let jump = instruction(
+            Operation::JUMP,
+            Some(Operand::Register(u3::new(7))),
+            None,
+            None,
+        );
+        assert!(encode(&jump).unwrap_err().contains("use RET"));
+        // TRAP x25 is HALT, the vectors of the named service routines belong to them
+        for vect in 0x20..=0x28 {
+            let trap = instruction(Operation::TRAP, None, Some(Operand::TrapVect(vect)), None);
+            assert!(encode(&trap).is_err(), "TRAP x{:02x}", vect);
+        }
+        let trap = instruction(Operation::TRAP, None, Some(Operand::TrapVect(0x30)), None);
+        assert_eq!(encode(&trap), Ok(vec![0b01100 << 11 | 0x30]));
+    }
+
+    #[test]
+    fn wrong_operands_are_rejected() {
+        // a trap vector that isn't one
+        let trap = instruction(Operation::TRAP, None, Some(Operand::Imm16(0x100)), None);
+        assert!(encode(&trap).is_err());
+        let trap = instruction(Operation::TRAP, None, None, None);
+        assert!(encode(&trap).is_err());
+        // a three operand instruction missing its second source register
+        let add = instruction(
+            Operation::ADD,
+            Some(Operand::Register(u3::new(1))),
+            Some(Operand::Register(u3::new(2))),
+            None,
+        );
+        assert!(encode(&add).is_err());
+        // branch flags where an address belongs
+        let br = instruction(
+            Operation::BR,
+            None,
+            Some(Operand::BR(Flags {
+                n: true,
+                z: false,
+                p: false,
+            })),
+            Some(Operand::Imm16(3)),
+        );
+        assert!(encode(&br).is_err());
+        // operands RTI has nowhere to put
+        let rti = instruction(
+            Operation::RTI,
+            Some(Operand::Register(u3::new(1))),
+            None,
+            None,
+        );
+        assert!(encode(&rti).is_err());
+    }
+}
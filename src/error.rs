@@ -0,0 +1,193 @@
+use crate::limits::Limit;
+use crate::symbols::{Symbolic, Symbols};
+use crate::tokenizer::Instruction;
+use std::fmt;
+use std::path::PathBuf;
+
+// the instruction that was executing when an error happened
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fault {
+    pub pc: u16,
+    // the one or two raw words of the instruction
+    pub words: Vec<u16>,
+    // None when the words could not be decoded
+    pub instruction: Option<Instruction>,
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.fmt_with(f, &Symbols::default())
+    }
+}
+
+impl Symbolic for Fault {
+    fn fmt_with(&self, f: &mut fmt::Formatter<'_>, symbols: &Symbols) -> fmt::Result {
+        let words: Vec<String> = self.words.iter().map(|w| format!("0x{:04x}", w)).collect();
+        write!(f, "{} [{}]", symbols.describe(self.pc), words.join(" "))?;
+        if let Some(instruction) = &self.instruction {
+            write!(f, " {}", instruction.with(symbols))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SimError {
+    // the words at the PC are not a valid encoding of their instruction
+    Decode {
+        fault: Fault,
+        reason: String,
+    },
+    // the opcode field does not belong to any instruction
+    IllegalOpcode {
+        fault: Fault,
+    },
+    // privileged memory accessed in user mode
+    AccessViolation {
+        fault: Fault,
+        address: u16,
+    },
+    // a supervisor only instruction executed in user mode
+    PrivilegeViolation {
+        fault: Fault,
+    },
+    // a TRAP with no handler in the trap vector table and no built-in routine
+    UnhandledTrap {
+        fault: Fault,
+        vector: u8,
+    },
+    // reading from or writing to the console failed
+    Io {
+        fault: Option<Fault>,
+        reason: String,
+    },
+    // run_machine stopped the program at one of its limits, with the last instructions it executed
+    LimitExceeded {
+        limit: Limit,
+        pc: u16,
+        recent: Vec<Fault>,
+    },
+    // a program image could not be loaded
+    Loader {
+        path: PathBuf,
+        reason: String,
+    },
+}
+
+impl SimError {
+    pub fn fault(&self) -> Option<&Fault> {
+        match self {
+            SimError::Decode { fault, .. }
+            | SimError::IllegalOpcode { fault }
+            | SimError::AccessViolation { fault, .. }
+            | SimError::PrivilegeViolation { fault }
+            | SimError::UnhandledTrap { fault, .. } => Some(fault),
+            SimError::Io { fault, .. } => fault.as_ref(),
+            SimError::LimitExceeded { .. } | SimError::Loader { .. } => None,
+        }
+    }
+
+    // address of the faulting instruction, or of the next one when a limit stopped the program
+    pub fn pc(&self) -> Option<u16> {
+        match self {
+            SimError::LimitExceeded { pc, .. } => Some(*pc),
+            _ => self.fault().map(|fault| fault.pc),
+        }
+    }
+
+    // the message with addresses named after the symbols, like ACV exception at 0x3005 <STORE_KEY+2>
+    pub fn describe(&self, symbols: &Symbols) -> String {
+        self.with(symbols).to_string()
+    }
+}
+
+impl fmt::Display for SimError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.fmt_with(f, &Symbols::default())
+    }
+}
+
+impl Symbolic for SimError {
+    fn fmt_with(&self, f: &mut fmt::Formatter<'_>, symbols: &Symbols) -> fmt::Result {
+        match self {
+            SimError::Decode { fault, reason } => {
+                write!(f, "decode error at {}: {}", fault.with(symbols), reason)
+            }
+            SimError::IllegalOpcode { fault } => {
+                write!(f, "illegal opcode at {}", fault.with(symbols))
+            }
+            SimError::AccessViolation { fault, address } => write!(
+                f,
+                "ACV exception at {}: privileged memory {} accessed in user mode",
+                fault.with(symbols),
+                symbols.describe(*address)
+            ),
+            SimError::PrivilegeViolation { fault } => write!(
+                f,
+                "privilege mode exception at {}: supervisor instruction in user mode",
+                fault.with(symbols)
+            ),
+            SimError::UnhandledTrap { fault, vector } => write!(
+                f,
+                "unhandled trap at {}: no handler installed for vector x{:02x}",
+                fault.with(symbols),
+                vector
+            ),
+            SimError::Io {
+                fault: Some(fault),
+                reason,
+            } => write!(f, "I/O error at {}: {}", fault.with(symbols), reason),
+            SimError::Io {
+                fault: None,
+                reason,
+            } => write!(f, "I/O error: {}", reason),
+            SimError::LimitExceeded { limit, pc, recent } => {
+                write!(f, "{} at {}", limit, symbols.describe(*pc))?;
+                if !recent.is_empty() {
+                    write!(f, ", last instructions executed:")?;
+                    for executed in recent {
+                        write!(f, "\n  {}", executed.with(symbols))?;
+                    }
+                }
+                Ok(())
+            }
+            SimError::Loader { path, reason } => {
+                write!(f, "could not load {}: {}", path.display(), reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SimError {}
+
+// why an instruction could not complete, the machine adds the fault context
+#[derive(Debug)]
+pub(crate) enum ExecError {
+    Malformed(String),
+    AccessViolation(u16),
+    PrivilegeViolation,
+    UnhandledTrap(u8),
+    Io(String),
+}
+
+// the operand helpers report malformed instructions as strings
+impl From<String> for ExecError {
+    fn from(reason: String) -> ExecError {
+        ExecError::Malformed(reason)
+    }
+}
+
+impl ExecError {
+    pub(crate) fn at(self, fault: Fault) -> SimError {
+        match self {
+            ExecError::Malformed(reason) => SimError::Decode { fault, reason },
+            ExecError::AccessViolation(address) => SimError::AccessViolation { fault, address },
+            ExecError::PrivilegeViolation => SimError::PrivilegeViolation { fault },
+            ExecError::UnhandledTrap(vector) => SimError::UnhandledTrap { fault, vector },
+            ExecError::Io(reason) => SimError::Io {
+                fault: Some(fault),
+                reason,
+            },
+        }
+    }
+}
@@ -0,0 +1,276 @@
+use crate::interrupt::InterruptController;
+use crate::machine::PSR;
+use std::collections::VecDeque;
+
+// the part of the machine state that is cheap to copy on every instruction
+#[derive(Debug, Clone, PartialEq)]
+pub struct CpuState {
+    pub pc: u16,
+    pub register: [i16; 8],
+    pub psr: PSR,
+    pub usp: u16,
+    pub ssp: u16,
+    // clock, first and second LFSR states
+    pub asg: [u16; 3],
+    pub halt_flag: bool,
+    pub interrupts: InterruptController,
+    // the registers of every device on the bus
+    pub devices: Vec<Vec<u16>>,
+}
+
+// everything needed to undo one instruction
+pub struct UndoRecord {
+    // state before the instruction executed
+    pub cpu: CpuState,
+    // (address, old value) for every memory write, in the order they happened
+    pub memory: Vec<(u16, u16)>,
+    // console input consumed, None is a read that hit the end of input
+    pub input: Vec<Option<u8>>,
+}
+
+// full copy of the machine taken before executing instruction `step`
+pub struct Checkpoint {
+    pub step: u64,
+    pub cpu: CpuState,
+    pub memory: Box<[u16; 65536]>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct HistoryConfig {
+    // number of instructions that can be undone directly
+    pub capacity: usize,
+    // take a checkpoint every `interval` instructions
+    pub interval: u64,
+    // oldest checkpoints are dropped past this, each costs 128KiB
+    pub max_checkpoints: usize,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> HistoryConfig {
+        HistoryConfig {
+            capacity: 100_000,
+            interval: 10_000,
+            max_checkpoints: 32,
+        }
+    }
+}
+
+// undo information for reverse execution, recent instructions are undone from
+// their records and older ones by replaying forward from a checkpoint
+pub struct History {
+    pub(crate) config: HistoryConfig,
+    // the last record belongs to the most recently executed instruction
+    pub(crate) records: VecDeque<UndoRecord>,
+    pub(crate) checkpoints: VecDeque<Checkpoint>,
+    // input consumed since the oldest checkpoint, with the step that read it, needed to replay
+    pub(crate) input_log: VecDeque<(u64, Option<u8>)>,
+}
+
+impl History {
+    pub fn new(config: HistoryConfig) -> History {
+        History {
+            config: HistoryConfig {
+                interval: config.interval.max(1),
+                ..config
+            },
+            records: VecDeque::new(),
+            checkpoints: VecDeque::new(),
+            input_log: VecDeque::new(),
+        }
+    }
+
+    pub fn config(&self) -> HistoryConfig {
+        self.config
+    }
+
+    // true if a checkpoint should be taken before executing `step`
+    pub(crate) fn wants_checkpoint(&self, step: u64) -> bool {
+        step % self.config.interval == 0 && self.checkpoints.back().map_or(true, |c| c.step < step)
+    }
+
+    pub(crate) fn push_checkpoint(&mut self, checkpoint: Checkpoint) {
+        self.checkpoints.push_back(checkpoint);
+        while self.checkpoints.len() > self.config.max_checkpoints.max(1) {
+            self.checkpoints.pop_front();
+        }
+        let oldest = self.checkpoints.front().map_or(0, |c| c.step);
+        while self
+            .input_log
+            .front()
+            .is_some_and(|(step, _)| *step < oldest)
+        {
+            self.input_log.pop_front();
+        }
+    }
+
+    pub(crate) fn push_record(&mut self, step: u64, record: UndoRecord) {
+        for key in &record.input {
+            self.input_log.push_back((step, *key));
+        }
+        self.records.push_back(record);
+        while self.records.len() > self.config.capacity {
+            self.records.pop_front();
+        }
+    }
+
+    // forget everything at or after `step`, returning the input that was read from then on with the step that read it
+    pub(crate) fn truncate(&mut self, step: u64) -> Vec<(u64, Option<u8>)> {
+        while self.checkpoints.back().is_some_and(|c| c.step > step) {
+            self.checkpoints.pop_back();
+        }
+        let mut input = Vec::new();
+        while self.input_log.back().is_some_and(|(s, _)| *s >= step) {
+            input.extend(self.input_log.pop_back());
+        }
+        input.reverse();
+        input
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.records.clear();
+        self.checkpoints.clear();
+        self.input_log.clear();
+    }
+
+    // number of instructions that can currently be stepped back over, directly or by replaying
+    pub fn depth(&self, step: u64) -> u64 {
+        let replayable = self
+            .checkpoints
+            .front()
+            .map_or(0, |checkpoint| step - checkpoint.step.min(step));
+        replayable.max(self.records.len() as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble_memory;
+    use crate::console::BufferConsole;
+    use crate::machine::Machine;
+    use crate::snapshot::Snapshot;
+
+    // changes registers, flags, memory, the ASG, the timer's registers and the display in supervisor mode
+    const BUSY: &str = "
+        .ORIG x3000
+        LD R0, #5
+        LD R1, #9
+        LD R2, #13
+        LSD
+LOOP    LPN
+        ADD R3, R3, #-1
+        ST R0, x4000
+        ST R3, xFE0A
+        STR R4, #7
+        ADD R4, R4, #1
+        OUT
+        BRnzp LOOP
+        .END
+    ";
+
+    // reads a key every loop and keeps the last one in memory
+    const READER: &str = "
+        .ORIG x3000
+LOOP    GETC
+        ST R0, x4000
+        ADD R3, R3, R0
+        BRnzp LOOP
+        .END
+    ";
+
+    fn machine(source: &str, input: &[u8], config: HistoryConfig) -> Machine<BufferConsole> {
+        let mut machine = Machine::new(Some(assemble_memory(&[source])), BufferConsole::new(input));
+        machine.set_psr(PSR {
+            supervisor: true,
+            ..machine.psr().clone()
+        });
+        machine.set_register(4, 0x5000);
+        machine.enable_history(config);
+        machine
+    }
+
+    // the state before every step, reversing has to go back through exactly the same states
+    fn run(machine: &mut Machine<BufferConsole>, steps: usize) -> Vec<Snapshot> {
+        let mut states = Vec::new();
+        for _ in 0..steps {
+            states.push(machine.snapshot());
+            machine.step().unwrap();
+        }
+        states
+    }
+
+    fn reverse(machine: &mut Machine<BufferConsole>, states: &[Snapshot]) {
+        for state in states.iter().rev() {
+            assert!(machine.reverse_step().unwrap());
+            assert_eq!(machine.snapshot(), *state, "step {}", state.steps);
+        }
+        assert!(!machine.reverse_step().unwrap());
+    }
+
+    #[test]
+    fn reverse_steps_restore_every_state() {
+        let mut machine = machine(BUSY, b"", HistoryConfig::default());
+        let states = run(&mut machine, 60);
+        reverse(&mut machine, &states);
+    }
+
+    #[test]
+    fn reverse_steps_replay_from_checkpoints() {
+        let config = HistoryConfig {
+            capacity: 5,
+            interval: 16,
+            max_checkpoints: 32,
+        };
+        let mut machine = machine(BUSY, b"", config);
+        let states = run(&mut machine, 100);
+        assert_eq!(machine.history().unwrap().depth(100), 100);
+        let output = machine.console().output().to_vec();
+        reverse(&mut machine, &states);
+        // replaying doesn't print again
+        assert_eq!(machine.console().output(), output);
+    }
+
+    #[test]
+    fn input_is_read_again_after_reversing() {
+        let config = HistoryConfig {
+            capacity: 3,
+            interval: 8,
+            max_checkpoints: 32,
+        };
+        let mut machine = machine(READER, b"abcdefghij", config);
+        let states = run(&mut machine, 30);
+        assert_eq!(machine.console().remaining_input(), b"ij");
+        let end = Snapshot {
+            input: Vec::new(),
+            ..machine.snapshot()
+        };
+        for state in states.iter().rev() {
+            assert!(machine.reverse_step().unwrap());
+            // the keys read since then wait to be read again
+            let pending = Snapshot {
+                input: Vec::new(),
+                ..machine.snapshot()
+            };
+            assert_eq!(pending, *state);
+        }
+        let read: Vec<u8> = machine
+            .snapshot()
+            .input
+            .iter()
+            .filter_map(|(_, key)| *key)
+            .collect();
+        assert_eq!(read, b"abcdefgh");
+        // going forward again reads the same keys, not new ones from the console
+        let again = run(&mut machine, 30);
+        for (state, first) in again.iter().zip(&states) {
+            assert_eq!(state.cpu, first.cpu);
+            assert_eq!(state.memory, first.memory);
+        }
+        let replayed = Snapshot {
+            input: Vec::new(),
+            ..machine.snapshot()
+        };
+        assert_eq!(replayed, end);
+        assert_eq!(machine.console().remaining_input(), b"ij");
+    }
+}
@@ -0,0 +1,134 @@
+use ux::u3;
+
+// the interrupt vector table follows the trap vector table, the handler for vector v is at 0x0100 + v
+pub const INTERRUPT_TABLE: u16 = 0x0100;
+
+// exceptions share the interrupt vector table
+pub const PRIVILEGE_VECTOR: u8 = 0x00;
+pub const ILLEGAL_OPCODE_VECTOR: u8 = 0x01;
+pub const ACV_VECTOR: u8 = 0x02;
+
+// an interrupt asserted by a device
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Request {
+    pub priority: u3,
+    pub vector: u8,
+}
+
+// collects interrupt requests until the machine services them
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct InterruptController {
+    // in the order they were raised, at most one request per vector
+    pending: Vec<Request>,
+}
+
+impl InterruptController {
+    pub fn new() -> InterruptController {
+        InterruptController::default()
+    }
+
+    // assert an interrupt, raising a vector that is already pending only updates its priority
+    pub fn raise(&mut self, priority: u3, vector: u8) {
+        match self.pending.iter_mut().find(|r| r.vector == vector) {
+            Some(request) => request.priority = priority,
+            None => self.pending.push(Request { priority, vector }),
+        }
+    }
+
+    // withdraw a request that has not been serviced yet
+    pub fn clear(&mut self, vector: u8) {
+        self.pending.retain(|r| r.vector != vector);
+    }
+
+    pub fn pending(&self) -> &[Request] {
+        &self.pending
+    }
+
+    // the request that should preempt a program running at `priority`, among those `installed` accepts,
+    // the highest priority wins and equal priorities are taken in the order they were raised
+    pub fn next_request<F: Fn(u8) -> bool>(&self, priority: u3, installed: F) -> Option<Request> {
+        self.pending
+            .iter()
+            .filter(|r| r.priority > priority && installed(r.vector))
+            .fold(None, |best: Option<Request>, r| match best {
+                Some(best) if best.priority >= r.priority => Some(best),
+                _ => Some(*r),
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble_memory;
+    use crate::console::BufferConsole;
+    use crate::machine::{Machine, PSR};
+
+    const HANDLER: u16 = 0x4000;
+
+    // a user program at priority 3 with its stack at x5000 and one handler for vectors x90 and x91
+    fn machine() -> Machine<BufferConsole> {
+        let mut mem = assemble_memory(&[
+            ".ORIG x3000\nADD R1, R1, #1\nADD R1, R1, #1\nADD R1, R1, #1\nHALT\n.END\n",
+            ".ORIG x4000\nADD R2, R2, #1\nRTI\n.END\n",
+        ]);
+        mem[(INTERRUPT_TABLE + 0x90) as usize] = HANDLER;
+        mem[(INTERRUPT_TABLE + 0x91) as usize] = HANDLER;
+        let mut machine = Machine::new(Some(mem), BufferConsole::new(b""));
+        machine.set_register(6, 0x5000);
+        machine.set_psr(PSR {
+            priority: u3::new(3),
+            ..machine.psr().clone()
+        });
+        machine
+    }
+
+    #[test]
+    fn interrupts_at_or_below_the_program_priority_wait() {
+        let mut machine = machine();
+        machine.raise_interrupt(u3::new(3), 0x90);
+        machine.step().unwrap();
+        assert_eq!(machine.pc(), 0x3001);
+        assert_eq!(machine.handler_entries(), 0);
+        assert_eq!(machine.interrupts().pending().len(), 1);
+
+        // once the program drops below it the request is taken
+        machine.set_psr(PSR {
+            priority: u3::new(2),
+            ..machine.psr().clone()
+        });
+        machine.step().unwrap();
+        assert_eq!(machine.pc(), HANDLER + 1);
+        assert_eq!(machine.handler_entries(), 1);
+        assert!(machine.interrupts().pending().is_empty());
+    }
+
+    #[test]
+    fn handlers_run_on_the_supervisor_stack_and_rti_restores_the_program() {
+        let mut machine = machine();
+        machine.step().unwrap();
+        let psr = machine.psr().to_word();
+        machine.raise_interrupt(u3::new(5), 0x91);
+
+        // taking the interrupt runs the first instruction of the handler
+        machine.step().unwrap();
+        assert_eq!(machine.pc(), HANDLER + 1);
+        assert_eq!(machine.register(2), 1);
+        assert!(machine.psr().supervisor);
+        assert_eq!(machine.psr().priority, u3::new(5));
+        assert_eq!(machine.usp(), 0x5000);
+        assert_eq!(machine.register(6) as u16, 0x2FFD);
+        assert_eq!(machine.read_memory(0x2FFD), 0x3001);
+        assert_eq!(machine.read_memory(0x2FFE), psr);
+
+        machine.step().unwrap();
+        assert_eq!(machine.pc(), 0x3001);
+        assert_eq!(machine.psr().to_word(), psr);
+        assert_eq!(machine.register(6), 0x5000);
+        assert_eq!(machine.ssp(), 0x2FFF);
+
+        machine.run_machine().unwrap();
+        assert_eq!(machine.register(1), 3);
+        assert_eq!(machine.register(2), 1);
+    }
+}
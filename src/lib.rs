@@ -0,0 +1,41 @@
+// the instruction and register names follow the LC-4 mnemonics
+#![allow(clippy::upper_case_acronyms)]
+pub mod asm;
+pub mod bus;
+pub mod console;
+pub mod debugger;
+pub mod devices;
+pub mod disasm;
+pub mod dump;
+pub mod encoder;
+pub mod error;
+pub mod history;
+pub mod interrupt;
+pub mod limits;
+pub mod lines;
+pub mod machine;
+pub mod parse;
+pub mod prng;
+pub mod reader;
+pub mod snapshot;
+pub mod symbols;
+pub mod tokenizer;
+pub mod trace;
+pub mod tui;
+
+pub use bus::{Bus, Device, DeviceContext};
+pub use console::{
+    BufferConsole, Console, EndOfInput, FileConsole, SharedReader, StreamConsole, TerminalConsole,
+};
+pub use encoder::encode;
+pub use error::{Fault, SimError};
+pub use interrupt::InterruptController;
+pub use limits::{Limit, Limits};
+pub use lines::LineMap;
+pub use machine::{Machine, PSR};
+pub use prng::{ASG, LFSR};
+pub use reader::read_input_files;
+pub use snapshot::Snapshot;
+pub use symbols::{Symbolic, Symbols};
+pub use tokenizer::{tokenize, DecodeError, Instruction, Operand, Operation};
+pub use trace::{Trace, TraceFormat, TraceRecord};
@@ -0,0 +1,176 @@
+use std::fmt;
+use std::time::Duration;
+
+// instructions kept for the report when a run is stopped by a limit
+pub const RECENT_INSTRUCTIONS: usize = 8;
+
+// limits on a run of the machine, see Machine::set_limits
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Limits {
+    // most instructions a single run may execute
+    pub max_steps: Option<u64>,
+    // wall clock time a run may take, checked between instructions so a blocking read is not cut short
+    pub timeout: Option<Duration>,
+    // stop when the machine comes back to a state it was in before, a machine that does that will
+    // repeat the same instructions forever
+    pub detect_loops: bool,
+}
+
+impl Limits {
+    pub fn is_unlimited(&self) -> bool {
+        *self == Limits::default()
+    }
+}
+
+// the limit that stopped a run
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Limit {
+    Steps(u64),
+    Timeout(Duration),
+    // the state repeated after `period` instructions
+    Loop { period: u64 },
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Limit::Steps(steps) => write!(f, "step limit of {} instructions reached", steps),
+            Limit::Timeout(timeout) => write!(f, "timed out after {:?}", timeout),
+            Limit::Loop { period: 1 } => write!(f, "infinite loop, the instruction repeats itself"),
+            Limit::Loop { period } => write!(
+                f,
+                "infinite loop, the machine repeats the same {} instructions",
+                period
+            ),
+        }
+    }
+}
+
+// finds a repeated machine state with Brent's algorithm: the state saved at the last power of two
+// steps is compared with every later one, so a loop is found within a couple of its periods after the
+// program enters it, whatever its length, states are compared by PC and hash, see Machine::loop_hash
+#[derive(Default)]
+pub(crate) struct LoopDetector {
+    saved: Option<(u16, u64)>,
+    // steps since the state was saved
+    since: u64,
+    // steps until the next state is saved
+    power: u64,
+}
+
+impl LoopDetector {
+    // check the state before the next instruction, returning how many instructions ago it was seen,
+    // the hash is only computed when the PC matches or the state is saved
+    pub(crate) fn check<F: FnOnce() -> u64>(&mut self, pc: u16, hash: F) -> Option<u64> {
+        let saving = self.since == self.power;
+        let hash = match self.saved {
+            Some((saved_pc, _)) if saved_pc == pc => Some(hash()),
+            _ if saving => Some(hash()),
+            _ => None,
+        };
+        if let (Some((_, saved)), Some(hash)) = (self.saved, hash) {
+            if saved == hash {
+                return Some(self.since);
+            }
+        }
+        if let (true, Some(hash)) = (saving, hash) {
+            self.saved = Some((pc, hash));
+            self.since = 0;
+            self.power = (self.power * 2).max(1);
+        }
+        self.since += 1;
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble_memory;
+    use crate::console::BufferConsole;
+    use crate::error::SimError;
+    use crate::machine::Machine;
+
+    fn run(source: &str, limits: Limits) -> (Machine<BufferConsole>, Result<(), SimError>) {
+        let mem = assemble_memory(&[source]);
+        let mut machine = Machine::new(Some(mem), BufferConsole::new(b""));
+        machine.set_limits(limits);
+        let result = machine.run_machine();
+        (machine, result)
+    }
+
+    const DETECT_LOOPS: Limits = Limits {
+        max_steps: None,
+        timeout: None,
+        detect_loops: true,
+    };
+
+    #[test]
+    fn a_branch_to_itself_is_reported_with_the_instructions_before_it() {
+        let source = ".ORIG x3000\nADD R1, R1, #1\nADD R1, R1, #1\nSTUCK BRnzp STUCK\n.END\n";
+        match run(source, DETECT_LOOPS).1 {
+            Err(SimError::LimitExceeded { limit, pc, recent }) => {
+                assert_eq!(limit, Limit::Loop { period: 1 });
+                assert_eq!(pc, 0x3002);
+                let pcs: Vec<u16> = recent.iter().map(|fault| fault.pc).collect();
+                assert_eq!(pcs[..3], [0x3000, 0x3001, 0x3002]);
+                assert!(pcs[3..].iter().all(|&pc| pc == 0x3002));
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn loops_of_several_instructions_are_reported_with_their_period() {
+        let source = ".ORIG x3000\nLOOP ADD R1, R1, #0\nNOT R2, R2\nBRnzp LOOP\n.END\n";
+        match run(source, DETECT_LOOPS).1 {
+            Err(SimError::LimitExceeded { limit, .. }) => {
+                // NOT flips R2 back every other pass
+                assert_eq!(limit, Limit::Loop { period: 6 })
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn long_loops_that_make_progress_are_not_reported() {
+        let source = ".ORIG x3000\nADD R0, R0, #5000\nLOOP ADD R0, R0, #-1\nBRp LOOP\nHALT\n.END\n";
+        let (machine, result) = run(source, DETECT_LOOPS);
+        result.unwrap();
+        assert!(machine.is_halted());
+        assert_eq!(machine.register(0), 0);
+    }
+
+    #[test]
+    fn runs_stop_at_the_step_limit() {
+        let limits = Limits {
+            max_steps: Some(20),
+            ..Limits::default()
+        };
+        let source = ".ORIG x3000\nLOOP ADD R1, R1, #1\nBRnzp LOOP\n.END\n";
+        let (machine, result) = run(source, limits);
+        match result {
+            Err(SimError::LimitExceeded { limit, recent, .. }) => {
+                assert_eq!(limit, Limit::Steps(20));
+                assert_eq!(recent.len(), RECENT_INSTRUCTIONS);
+            }
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(machine.register(1), 10);
+    }
+
+    #[test]
+    fn runs_stop_at_the_timeout() {
+        let limits = Limits {
+            timeout: Some(Duration::from_millis(20)),
+            ..Limits::default()
+        };
+        let source = ".ORIG x3000\nLOOP ADD R1, R1, #1\nBRnzp LOOP\n.END\n";
+        match run(source, limits).1 {
+            Err(SimError::LimitExceeded { limit, .. }) => {
+                assert_eq!(limit, Limit::Timeout(Duration::from_millis(20)))
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+}
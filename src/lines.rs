@@ -0,0 +1,261 @@
+use crate::error::SimError;
+use crate::parse::parse_address;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// a source file named in a line map, with its text when it could be read
+#[derive(Debug, Clone, PartialEq)]
+struct Source {
+    // the path as written in the map, shown to the user
+    name: String,
+    lines: Option<Vec<String>>,
+}
+
+// where each instruction came from in the assembly source, read from the line maps the assembler
+// writes, see Machine::set_line_map
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LineMap {
+    // (source, line) for the first word of every statement
+    locations: BTreeMap<u16, (usize, usize)>,
+    sources: Vec<Source>,
+}
+
+impl LineMap {
+    // `0x3000 prog.asm:12` lines as written by asm, relative source paths are found from dir
+    pub fn parse(text: &str, dir: &Path) -> Result<LineMap, String> {
+        let mut map = LineMap::default();
+        for (number, line) in text.lines().enumerate() {
+            let error = |reason: String| format!("line {}: {}", number + 1, reason);
+            let line = match line.find([';', '#']) {
+                Some(at) => &line[..at],
+                None => line,
+            };
+            let (addr, location) = match line.trim().split_once(char::is_whitespace) {
+                Some((addr, location)) => (addr, location.trim()),
+                None if line.trim().is_empty() => continue,
+                None => {
+                    return Err(error(format!(
+                        "expected ADDRESS FILE:LINE, found '{}'",
+                        line.trim()
+                    )))
+                }
+            };
+            let addr = parse_address(addr).map_err(error)?;
+            let (file, source_line) = split_location(location)
+                .ok_or_else(|| error(format!("expected FILE:LINE, found '{}'", location)))?;
+            map.insert(addr, file, source_line, dir);
+        }
+        Ok(map)
+    }
+
+    pub fn read(path: &Path) -> Result<LineMap, SimError> {
+        let loader_error = |reason: String| SimError::Loader {
+            path: path.to_owned(),
+            reason,
+        };
+        let text = fs::read_to_string(path).map_err(|e| loader_error(e.to_string()))?;
+        LineMap::parse(&text, path.parent().unwrap_or(Path::new(""))).map_err(loader_error)
+    }
+
+    // map addr to a line of file, the source text is read the first time the file is mentioned
+    pub fn insert(&mut self, addr: u16, file: &str, line: usize, dir: &Path) {
+        let source = match self.sources.iter().position(|source| source.name == file) {
+            Some(source) => source,
+            None => {
+                let path: PathBuf = dir.join(file);
+                let lines = fs::read_to_string(path)
+                    .ok()
+                    .map(|text| text.lines().map(str::to_owned).collect());
+                self.sources.push(Source {
+                    name: file.to_owned(),
+                    lines,
+                });
+                self.sources.len() - 1
+            }
+        };
+        self.locations.insert(addr, (source, line));
+    }
+
+    pub fn extend(&mut self, other: &LineMap) {
+        for (addr, (source, line)) in &other.locations {
+            let source = &other.sources[*source];
+            let index = match self.sources.iter().position(|s| s.name == source.name) {
+                Some(index) => index,
+                None => {
+                    self.sources.push(source.clone());
+                    self.sources.len() - 1
+                }
+            };
+            self.locations.insert(*addr, (index, *line));
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.locations.is_empty()
+    }
+
+    // the file and line of the statement at addr, like prog.asm:12
+    pub fn location(&self, addr: u16) -> Option<String> {
+        let (source, line) = self.locations.get(&addr)?;
+        Some(format!("{}:{}", self.sources[*source].name, line))
+    }
+
+    // the source text of the statement at addr, without its indentation
+    pub fn source(&self, addr: u16) -> Option<&str> {
+        let (source, line) = self.locations.get(&addr)?;
+        let lines = self.sources[*source].lines.as_ref()?;
+        lines.get(line.checked_sub(1)?).map(|text| text.trim())
+    }
+
+    // the first address of the statement at or after a line of file, a file matches when its path
+    // ends with the one given, so prog.asm finds src/prog.asm
+    pub fn address(&self, file: &str, line: usize) -> Result<u16, String> {
+        let matching: Vec<usize> = (0..self.sources.len())
+            .filter(|&source| Path::new(&self.sources[source].name).ends_with(file))
+            .collect();
+        match matching[..] {
+            [] => return Err(format!("no line map for {}", file)),
+            [_] => (),
+            _ => return Err(format!("{} matches more than one source file", file)),
+        }
+        self.locations
+            .iter()
+            .filter(|(_, (source, at))| *source == matching[0] && *at >= line)
+            .min_by_key(|(addr, (_, at))| (*at, **addr))
+            .map(|(addr, _)| *addr)
+            .ok_or_else(|| format!("no code at or after {}:{}", file, line))
+    }
+}
+
+// FILE:LINE, split at the last colon so a path can have colons in it
+pub fn split_location(text: &str) -> Option<(&str, usize)> {
+    let (file, line) = text.rsplit_once(':')?;
+    match line.parse() {
+        Ok(line) if !file.is_empty() => Some((file, line)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::{assemble, assemble_memory};
+    use crate::console::BufferConsole;
+    use crate::debugger::{Command, Debugger};
+    use crate::machine::Machine;
+    use crate::symbols::Symbols;
+
+    const SOURCE: &str = "; count to three
+        .ORIG x3000
+START   ADD R1, R1, #1   ; one
+        ADD R1, R1, #2
+
+        HALT
+        .END
+";
+
+    // a directory of this test run's own with the source written to it as name
+    fn source_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("lc4-lines-{}", std::process::id()));
+        fs::create_dir_all(dir.join("src")).unwrap();
+        fs::write(dir.join(name), SOURCE).unwrap();
+        dir
+    }
+
+    // the line map asm writes for SOURCE, naming the source file
+    fn map_text(name: &str) -> String {
+        assemble(SOURCE).unwrap().line_map(name)
+    }
+
+    // a debugger stopped at the start of SOURCE with the line map in text
+    fn debugger(text: &str, dir: &Path) -> Debugger<BufferConsole> {
+        let mut machine = Machine::new(Some(assemble_memory(&[SOURCE])), BufferConsole::new(b""));
+        machine.set_line_map(LineMap::parse(text, dir).unwrap());
+        Debugger::new(machine)
+    }
+
+    #[test]
+    fn maps_written_by_asm_are_read_with_their_source() {
+        let dir = source_dir("read.asm");
+        let map = LineMap::parse(&map_text("read.asm"), &dir).unwrap();
+        assert_eq!(map.location(0x3000), Some("read.asm:3".to_owned()));
+        assert_eq!(map.location(0x3002), Some("read.asm:6".to_owned()));
+        assert_eq!(map.location(0x3003), None);
+        assert_eq!(map.source(0x3000), Some("START   ADD R1, R1, #1   ; one"));
+        assert_eq!(map.source(0x3002), Some("HALT"));
+    }
+
+    #[test]
+    fn comments_and_blank_lines_are_skipped_and_bad_lines_refused() {
+        let text = "; from asm\n\n0x3000 prog.asm:3 # first\n";
+        let map = LineMap::parse(text, Path::new("")).unwrap();
+        assert_eq!(map.location(0x3000), Some("prog.asm:3".to_owned()));
+        assert_eq!(map.source(0x3000), None);
+        assert_eq!(
+            LineMap::parse("0x3000 prog.asm:3\n0x3001\n", Path::new("")),
+            Err("line 2: expected ADDRESS FILE:LINE, found '0x3001'".to_owned())
+        );
+        assert_eq!(
+            LineMap::parse("0x3000 prog.asm\n", Path::new("")),
+            Err("line 1: expected FILE:LINE, found 'prog.asm'".to_owned())
+        );
+        assert_eq!(
+            LineMap::parse("start prog.asm:3\n", Path::new("")),
+            Err("line 1: invalid address: start".to_owned())
+        );
+    }
+
+    #[test]
+    fn source_lines_resolve_to_the_next_statement() {
+        let dir = source_dir("src/resolve.asm");
+        let map = LineMap::parse(&map_text("src/resolve.asm"), &dir).unwrap();
+        assert_eq!(map.address("src/resolve.asm", 3), Ok(0x3000));
+        // the blank line before HALT and the lines before the first instruction
+        assert_eq!(map.address("resolve.asm", 5), Ok(0x3002));
+        assert_eq!(map.address("resolve.asm", 1), Ok(0x3000));
+        assert_eq!(
+            map.address("resolve.asm", 7),
+            Err("no code at or after resolve.asm:7".to_owned())
+        );
+        assert_eq!(
+            map.address("other.asm", 3),
+            Err("no line map for other.asm".to_owned())
+        );
+    }
+
+    #[test]
+    fn breakpoints_can_be_set_on_source_lines() {
+        let dir = source_dir("break.asm");
+        let map = LineMap::parse(&map_text("break.asm"), &dir).unwrap();
+        let symbols = Symbols::default();
+        assert_eq!(
+            Command::parse_with("break break.asm:4", &symbols, &map),
+            Ok(Command::Break(0x3001))
+        );
+        assert_eq!(
+            Command::parse_with("delete break.asm:6", &symbols, &map),
+            Ok(Command::Delete(Some(0x3002)))
+        );
+        assert_eq!(
+            Command::parse_with("break break.asm:9", &symbols, &map),
+            Err("no code at or after break.asm:9".to_owned())
+        );
+    }
+
+    #[test]
+    fn the_debugger_shows_the_source_line_or_else_the_disassembly() {
+        let dir = source_dir("shown.asm");
+        assert_eq!(
+            debugger(&map_text("shown.asm"), &dir).location(),
+            "0x3000 shown.asm:3: START   ADD R1, R1, #1   ; one\n"
+        );
+        // the source can't be read
+        assert_eq!(
+            debugger(&map_text("gone.asm"), &dir).location(),
+            "0x3000 gone.asm:3: ADD R1, R1, #1\n"
+        );
+        // no line map at all
+        assert_eq!(debugger("", &dir).location(), "0x3000: ADD R1, R1, #1\n");
+    }
+}
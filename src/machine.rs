@@ -1,50 +1,123 @@
-use crate::log::log;
+use crate::bus::{AccessKind, Bus, DeviceContext, InputLog};
+use crate::console::{Console, EndOfInput};
+use crate::devices::attach_standard_devices;
+use crate::error::{ExecError, Fault, SimError};
+use crate::history::{Checkpoint, CpuState, History, HistoryConfig, UndoRecord};
+use crate::interrupt::{
+    InterruptController, ACV_VECTOR, ILLEGAL_OPCODE_VECTOR, INTERRUPT_TABLE, PRIVILEGE_VECTOR,
+};
+use crate::limits::{Limit, Limits, LoopDetector, RECENT_INSTRUCTIONS};
+use crate::lines::LineMap;
 use crate::prng::ASG;
-use crate::tokenizer::{check_instruction_double, tokenize, Instruction, Operand, Operation};
-use std::io::StdinLock;
-use std::io::Write;
+use crate::snapshot::Snapshot;
+use crate::symbols::{Symbolic, Symbols};
+use crate::tokenizer::{
+    check_instruction_double, tokenize, DecodeError, Instruction, Operand, Operation,
+};
+use crate::trace::{Trace, TraceRecord};
 
+use std::collections::hash_map::DefaultHasher;
+use std::collections::VecDeque;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::time::Instant;
 use ux::u3;
 
-struct TerminalHandles<'a, W: Write> {
-    pub input: std::io::Bytes<StdinLock<'a>>,
-    pub output: W,
+#[derive(Debug, Clone, PartialEq)]
+pub struct PSR {
+    pub priority: u3,
+    pub supervisor: bool,
+    pub n: bool,
+    pub p: bool,
+    pub z: bool,
 }
 
-struct PSR {
-    priority: u3,
-    supervisor: bool,
-    n: bool,
-    p: bool,
-    z: bool,
+impl PSR {
+    // the PSR as it is saved on the supervisor stack: bit 15 is set in user mode,
+    // bits 10-8 hold the priority and bits 2-0 the condition codes
+    pub fn to_word(&self) -> u16 {
+        ((!self.supervisor as u16) << 15)
+            | (u16::from(self.priority) << 8)
+            | (self.n as u16) << 2
+            | (self.z as u16) << 1
+            | self.p as u16
+    }
+
+    pub fn from_word(word: u16) -> PSR {
+        PSR {
+            priority: u3::new((word >> 8 & 0b111) as u8),
+            supervisor: word >> 15 == 0,
+            n: word >> 2 & 1 == 1,
+            z: word >> 1 & 1 == 1,
+            p: word & 1 == 1,
+        }
+    }
 }
 
-pub struct Machine<'a, W: Write> {
+pub struct Machine<C: Console> {
     // implementing a buffer would be more consistent with the hardware
     //input_buffer: Vec<u8>,
-    term: TerminalHandles<'a, W>,
+    term: C,
     halt_flag: bool,
+    // a record of every executed instruction, None when not tracing
+    trace: Option<Trace>,
+    // the instruction executed by the step being traced and the writes it made
+    traced: Option<Fault>,
+    trace_writes: Vec<(u16, u16)>,
+    // labels of the loaded programs, for traces and the debugger
+    symbols: Symbols,
+    // the source line of each instruction, for traces and the debugger
+    line_map: LineMap,
+    // run the Rust service routines for traps that have no handler installed
+    builtin_traps: bool,
+    // enter the exception handler for ACV, privilege and illegal opcode exceptions when one is installed
+    vector_exceptions: bool,
+    // what reading past the end of the console input does
+    end_of_input: EndOfInput,
     asg: ASG,
-    memory: [u16; 65536],
-    // needs to be usize to use as an index into arrays
-    pc: usize,
+    bus: Bus,
+    pc: u16,
     register: [i16; 8],
     usp: u16,
     ssp: u16,
     psr: PSR,
+    interrupts: InterruptController,
+    // number of instructions executed so far
+    steps: u64,
+    // number of interrupt and exception handlers entered, traps are not counted
+    handler_entries: u64,
+    // the instruction completed by the last step, None if it didn't complete one
+    executed: Option<Instruction>,
+    // undo information for reverse execution, None when it is not recorded
+    history: Option<History>,
+    // (address, old value) of the RAM writes of the instruction being executed, only kept while recording history
+    written: Vec<(u16, u16)>,
+    input: InputLog,
+    // enforced by run_machine
+    limits: Limits,
+    // the last instructions executed, only kept while a limit is set
+    recent: VecDeque<Fault>,
+    // number of RAM writes that changed a value
+    memory_changes: u64,
 }
 
-impl<'a, W: Write> Machine<'a, W> {
-    pub fn new(
-        mem: Option<[u16; 65536]>,
-        input: std::io::Bytes<StdinLock<'a>>,
-        output: W,
-    ) -> Machine<W> {
+impl<C: Console> Machine<C> {
+    pub fn new(mem: Option<[u16; 65536]>, console: C) -> Machine<C> {
+        let mut bus = Bus::new(mem.unwrap_or([0b0u16; 65536]));
+        attach_standard_devices(&mut bus).expect("standard devices overlap");
         Machine {
-            term: TerminalHandles { input, output },
+            term: console,
             halt_flag: true,
+            trace: None,
+            traced: None,
+            trace_writes: Vec::new(),
+            symbols: Symbols::default(),
+            line_map: LineMap::default(),
+            builtin_traps: true,
+            vector_exceptions: true,
+            end_of_input: EndOfInput::Null,
             asg: ASG::new(),
-            memory: mem.unwrap_or([0b0u16; 65536]),
+            bus,
             pc: 0x3000,
             register: [0b0i16; 8],
             usp: 0xFDFF,
@@ -56,9 +129,32 @@ impl<'a, W: Write> Machine<'a, W> {
                 p: false,
                 z: false,
             },
+            interrupts: InterruptController::new(),
+            steps: 0,
+            handler_entries: 0,
+            executed: None,
+            history: None,
+            written: Vec::new(),
+            input: InputLog::default(),
+            limits: Limits::default(),
+            recent: VecDeque::new(),
+            memory_changes: 0,
         }
     }
 
+    // the bus and the context its devices see, borrowed separately so both can be used at once
+    fn bus_and_context(&mut self) -> (&mut Bus, DeviceContext<'_>) {
+        let ctx = DeviceContext::new(
+            &mut self.term,
+            &mut self.input,
+            &mut self.interrupts,
+            &mut self.halt_flag,
+            self.end_of_input,
+            self.steps,
+        );
+        (&mut self.bus, ctx)
+    }
+
     // set the result registers
     fn setcc(&mut self, value: i16) {
         if value > 0 {
@@ -76,214 +172,312 @@ impl<'a, W: Write> Machine<'a, W> {
         }
     }
 
-    // simulate a single instruction using the executor module
-    fn simulate_instruction(&mut self) -> Result<(), String> {
-        let double: bool = check_instruction_double(self.memory[self.pc]);
-        if double {
-            match tokenize(self.memory[self.pc], Some(self.memory[self.pc + 1])) {
-                Ok(instruction) => match instruction.operation {
-                    Operation::ADDi16 => self.execute_double_def(instruction),
-                    Operation::ADDa => self.execute_double_def(instruction),
-                    Operation::ANDi16 => self.execute_double_def(instruction),
-                    Operation::ANDa => self.execute_double_def(instruction),
-                    Operation::XORi16 => self.execute_double_def(instruction),
-                    Operation::XORa => self.execute_double_def(instruction),
-                    Operation::BR => self.br(&instruction),
-                    Operation::JSR => self.jsr(&instruction),
-                    Operation::LDa => self.lda(instruction),
-                    Operation::ST => self.st(instruction),
-                    Operation::STR16 => self.str16(instruction),
-                    _ => {
-                        Err("single length instruction in simulate_instruction double".to_string())
-                    }
-                },
-                Err(error) => Err(error),
+    // simulate a single decoded instruction, the PC already points past it
+    fn simulate_instruction(&mut self, instruction: &Instruction) -> Result<(), ExecError> {
+        match instruction.operation {
+            Operation::ADD => self.execute_def(instruction),
+            Operation::ADDi => self.execute_def(instruction),
+            Operation::ADDi16 => self.execute_double_def(instruction),
+            Operation::ADDa => self.execute_double_def(instruction),
+            Operation::AND => self.execute_def(instruction),
+            Operation::ANDi => self.execute_def(instruction),
+            Operation::ANDi16 => self.execute_double_def(instruction),
+            Operation::ANDa => self.execute_double_def(instruction),
+            Operation::XOR => self.execute_def(instruction),
+            Operation::XORi => self.execute_def(instruction),
+            Operation::XORi16 => self.execute_double_def(instruction),
+            Operation::XORa => self.execute_double_def(instruction),
+            Operation::BR => self.br(instruction),
+            Operation::JUMP => self.jump(instruction),
+            Operation::RET => self.ret(),
+            Operation::JSR => self.jsr(instruction),
+            Operation::JSRR => self.jsrr(instruction),
+            Operation::LD => self.ld(instruction),
+            Operation::LDa => self.lda(instruction),
+            Operation::ST => self.st(instruction),
+            Operation::STR => self.str(instruction),
+            Operation::STR16 => self.str16(instruction),
+            Operation::NOT => self.not(instruction),
+            Operation::RTI => self.rti(),
+            Operation::TRAP
+            | Operation::LSD
+            | Operation::LPN
+            | Operation::CLRP
+            | Operation::HALT
+            | Operation::PUTS
+            | Operation::GETC
+            | Operation::OUT
+            | Operation::IN
+            | Operation::PUTSP => self.trap(instruction),
+        }
+    }
+
+    // jump through the trap vector table, falling back to the built-in routine when no handler is installed
+    fn trap(&mut self, instruction: &Instruction) -> Result<(), ExecError> {
+        let vector = instruction
+            .trap_vector()
+            .ok_or_else(|| "TRAP without a trap vector".to_owned())?;
+        if let Some(handler) = self.trap_handler(vector) {
+            return self.enter_supervisor(handler);
+        }
+        if !self.builtin_traps {
+            return Err(ExecError::UnhandledTrap(vector));
+        }
+        match instruction.operation {
+            Operation::LSD => self.lsd(),
+            Operation::LPN => self.lpn(),
+            Operation::CLRP => self.clrp(),
+            Operation::HALT => {
+                self.halt_flag = false;
+                Ok(())
             }
-        } else {
-            match tokenize(self.memory[self.pc], None) {
-                Ok(instruction) => match instruction.operation {
-                    Operation::ADD => self.execute_def(instruction),
-                    Operation::ADDi => self.execute_def(instruction),
-                    Operation::AND => self.execute_def(instruction),
-                    Operation::ANDi => self.execute_def(instruction),
-                    Operation::XOR => self.execute_def(instruction),
-                    Operation::XORi => self.execute_def(instruction),
-                    Operation::JUMP => self.jump(instruction),
-                    Operation::RET => self.ret(),
-                    Operation::JSRR => self.jsrr(instruction),
-                    Operation::LD => self.ld(instruction),
-                    Operation::STR => self.str(instruction),
-                    Operation::NOT => self.not(instruction),
-                    Operation::TRAP => Ok(()), //this will not happen
-                    Operation::RTI => self.rti(),
-                    Operation::LSD => self.lsd(),
-                    Operation::LPN => self.lpn(),
-                    Operation::CLRP => self.clrp(),
-                    Operation::HALT => {
-                        self.halt_flag = false;
-                        Ok(())
-                    }
-                    Operation::PUTS => self.puts(),
-                    Operation::GETC => self.getc(),
-                    Operation::OUT => self.out(),
-                    Operation::IN => self.in_trap(),
-                    Operation::PUTSP => self.putsp(),
-                    _ => Err("double instruction in simulate_instruction double".to_string()),
-                },
-                Err(error) => Err(error),
+            Operation::PUTS => self.puts(),
+            Operation::GETC => self.getc(),
+            Operation::OUT => self.out(),
+            Operation::IN => self.in_trap(),
+            Operation::PUTSP => self.putsp(),
+            _ => Err(ExecError::UnhandledTrap(vector)),
+        }
+    }
+
+    // the address in the trap vector table for `vector`, None when the entry is empty
+    pub fn trap_handler(&self, vector: u8) -> Option<u16> {
+        match self.bus.memory()[vector as usize] {
+            0 => None,
+            handler => Some(handler),
+        }
+    }
+
+    // the address in the interrupt vector table for `vector`, None when the entry is empty
+    pub fn interrupt_handler(&self, vector: u8) -> Option<u16> {
+        match self.bus.memory()[INTERRUPT_TABLE.wrapping_add(vector as u16) as usize] {
+            0 => None,
+            handler => Some(handler),
+        }
+    }
+
+    // enter the handler of the most urgent pending interrupt that has a higher priority than the program,
+    // requests without an installed handler stay pending
+    fn service_interrupt(&mut self) -> Result<(), ExecError> {
+        let request = self.interrupts.next_request(self.psr.priority, |vector| {
+            self.interrupt_handler(vector).is_some()
+        });
+        if let Some(request) = request {
+            if let Some(handler) = self.interrupt_handler(request.vector) {
+                self.interrupts.clear(request.vector);
+                self.enter_supervisor(handler)?;
+                self.psr.priority = request.priority;
+                self.handler_entries += 1;
+            }
+        }
+        Ok(())
+    }
+
+    // enter the handler for an exception, the error is returned when exceptions are not vectored or
+    // no handler is installed, the saved PC is the instruction after the one that caused it
+    fn raise_exception(&mut self, error: SimError) -> Result<(), SimError> {
+        if !self.vector_exceptions {
+            return Err(error);
+        }
+        let vector = match error {
+            SimError::PrivilegeViolation { .. } => PRIVILEGE_VECTOR,
+            // words that don't decode are illegal instructions whatever their opcode
+            SimError::IllegalOpcode { .. } | SimError::Decode { .. } => ILLEGAL_OPCODE_VECTOR,
+            SimError::AccessViolation { .. } => ACV_VECTOR,
+            _ => return Err(error),
+        };
+        let (handler, fault) = match (self.interrupt_handler(vector), error.fault()) {
+            (Some(handler), Some(fault)) => (handler, fault.clone()),
+            _ => return Err(error),
+        };
+        self.pc = fault.pc.wrapping_add(fault.words.len() as u16);
+        self.enter_supervisor(handler)
+            .map_err(|error| error.at(fault))?;
+        self.handler_entries += 1;
+        Ok(())
+    }
+
+    // save the PSR and PC on the supervisor stack and continue at handler in supervisor mode
+    fn enter_supervisor(&mut self, handler: u16) -> Result<(), ExecError> {
+        let psr = self.psr.to_word();
+        if !self.psr.supervisor {
+            self.usp = self.register[6] as u16;
+            self.register[6] = self.ssp as i16;
+        }
+        self.psr.supervisor = true;
+        self.push(psr)?;
+        self.push(self.pc)?;
+        self.pc = handler;
+        Ok(())
+    }
+
+    // R6 is the stack pointer, the stack grows down
+    fn push(&mut self, value: u16) -> Result<(), ExecError> {
+        let sp = (self.register[6] as u16).wrapping_sub(1);
+        self.register[6] = sp as i16;
+        self.store(sp, value)
+    }
+
+    fn pop(&mut self) -> Result<u16, ExecError> {
+        let sp = self.register[6] as u16;
+        self.register[6] = sp.wrapping_add(1) as i16;
+        self.load(sp)
+    }
+
+    // write to the console and flush so output shows up immediately
+    fn write_console(&mut self, bytes: &[u8]) -> Result<(), ExecError> {
+        let (_, mut ctx) = self.bus_and_context();
+        ctx.write_output(bytes)
+            .map_err(|error| ExecError::Io(format!("couldn't write to terminal: {}", error)))
+    }
+
+    // block for a key, the end of input reads as a null character unless it is an error
+    fn read_console(&mut self) -> Result<u8, ExecError> {
+        let (_, mut ctx) = self.bus_and_context();
+        match ctx.read_key() {
+            Ok(key) => Ok(key.unwrap_or(b'\0')),
+            Err(error) => Err(ExecError::Io(format!("couldn't read input: {}", error))),
+        }
+    }
+
+    // let the devices raise their interrupts
+    fn update_devices(&mut self) -> Result<(), ExecError> {
+        let (bus, mut ctx) = self.bus_and_context();
+        bus.tick(&mut ctx).map_err(device_error)
+    }
+
+    // all memory reads by instructions go through the bus so devices and hooks see them
+    fn load(&mut self, addr: u16) -> Result<u16, ExecError> {
+        let (bus, mut ctx) = self.bus_and_context();
+        bus.read(addr, AccessKind::Read, &mut ctx)
+            .map_err(device_error)
+    }
+
+    fn fetch(&mut self, addr: u16) -> Result<u16, ExecError> {
+        let (bus, mut ctx) = self.bus_and_context();
+        bus.read(addr, AccessKind::Fetch, &mut ctx)
+            .map_err(device_error)
+    }
+
+    // all memory writes by instructions go through the bus, RAM writes are kept so they can be undone
+    fn store(&mut self, addr: u16, value: u16) -> Result<(), ExecError> {
+        let (bus, mut ctx) = self.bus_and_context();
+        let old = bus.write(addr, value, &mut ctx).map_err(device_error)?;
+        if self.trace.is_some() {
+            self.trace_writes.push((addr, value));
+        }
+        if let Some(old) = old {
+            if old != value {
+                self.memory_changes += 1;
+            }
+            if self.history.is_some() {
+                self.written.push((addr, old));
             }
         }
+        Ok(())
     }
 
-    fn puts(&mut self) -> Result<(), String> {
-        let mut addr = self.register[0] as usize;
-        let mut out = self.memory[addr] as u8 as char;
-        while out != 0x0000 as char {
-            match write!(self.term.output, "{}", out) {
-                Ok(()) => Ok(()),
-                Err(_) => Err("couldn't write to terminal".to_owned()),
-            }?;
-            match self.term.output.flush() {
-                Ok(()) => Ok(()),
-                Err(_) => Err("couldn't write to terminal".to_owned()),
-            }?;
-            addr += 1;
-            out = self.memory[addr] as u8 as char;
+    fn puts(&mut self) -> Result<(), ExecError> {
+        let mut addr = self.register[0] as u16;
+        let mut out = self.load(addr)? as u8;
+        while out != 0x00 {
+            self.write_console(&[out])?;
+            addr = addr.wrapping_add(1);
+            out = self.load(addr)? as u8;
         }
         Ok(())
     }
-    fn in_trap(&mut self) -> Result<(), String> {
+    fn in_trap(&mut self) -> Result<(), ExecError> {
         // go to next line and print input prompt for user
-        match write!(self.term.output, "{}{}input: ", '\n', '\r') {
-            Ok(_) => Ok(()),
-            Err(_) => Err("couldn't write to terminal".to_owned()),
-        }?;
-        // flush to terminal
-        match self.term.output.flush() {
-            Ok(()) => Ok(()),
-            Err(_) => Err("couldn't write to terminal".to_owned()),
-        }?;
+        self.write_console(b"\ninput: ")?;
         // block and read input
-        let key = match self.term.input.next() {
-            Some(key) => match key {
-                Ok(key) => Ok(key),
-                Err(_) => Err("couldn't read input".to_owned()),
-            }?,
-            None => b'\0',
-        };
+        let key = self.read_console()?;
         // echo key and place cursor on next line
-        match write!(self.term.output, "{}{}{}", key as char, '\n', '\r') {
-            Ok(_) => Ok(()),
-            Err(_) => Err("couldn't write to terminal".to_owned()),
-        }?;
-        match self.term.output.flush() {
-            Ok(()) => Ok(()),
-            Err(_) => Err("couldn't write to terminal".to_owned()),
-        }?;
+        self.write_console(&[key, b'\n'])?;
         self.register[0] = key as i16;
         Ok(())
     }
-    fn getc(&mut self) -> Result<(), String> {
-        let key = match self.term.input.next() {
-            Some(key) => match key {
-                Ok(key) => Ok(key),
-                Err(_) => Err("couldn't read input".to_owned()),
-            }?,
-            None => b'\0',
-        };
+    fn getc(&mut self) -> Result<(), ExecError> {
+        let key = self.read_console()?;
         self.register[0] = key as i16;
         Ok(())
     }
-    fn out(&mut self) -> Result<(), String> {
-        let out = self.register[0].to_be_bytes()[1] as char;
-        match write!(self.term.output, "{}", out) {
-            Ok(()) => Ok(()),
-            Err(_) => Err("couldn't write to terminal".to_owned()),
-        }?;
-        match self.term.output.flush() {
-            Ok(()) => Ok(()),
-            Err(_) => Err("couldn't write to terminal".to_owned()),
-        }?;
-        Ok(())
+    fn out(&mut self) -> Result<(), ExecError> {
+        let out = self.register[0].to_be_bytes()[1];
+        self.write_console(&[out])
     }
 
-    fn putsp(&mut self) -> Result<(), String> {
-        let mut addr = self.register[0] as usize;
-        let mut out = self.memory[addr].to_be_bytes();
+    fn putsp(&mut self) -> Result<(), ExecError> {
+        let mut addr = self.register[0] as u16;
+        let mut out = self.load(addr)?.to_be_bytes();
+        let mut bytes = Vec::new();
         while out[0] != 0x00 {
-            match write!(self.term.output, "{}{}", out[0] as char, out[1] as char) {
-                Ok(()) => Ok(()),
-                Err(_) => Err("couldn't write to terminal".to_owned()),
-            }?;
-            addr += 1;
-            out = self.memory[addr].to_be_bytes();
-        }
-        match self.term.output.flush() {
-            Ok(()) => Ok(()),
-            Err(_) => Err("couldn't write to terminal".to_owned()),
-        }?;
-        Ok(())
+            bytes.extend_from_slice(&out);
+            addr = addr.wrapping_add(1);
+            out = self.load(addr)?.to_be_bytes();
+        }
+        self.write_console(&bytes)
     }
 
-    fn str16(&mut self, instruction: Instruction) -> Result<(), String> {
-        let addr = self.register[instruction_to_dr(&instruction)?] as usize;
-        let value = instruction_to_imm16(&instruction)?;
-        self.memory[addr] = value as u16;
-        Ok(())
+    fn str16(&mut self, instruction: &Instruction) -> Result<(), ExecError> {
+        let addr = self.register[instruction_to_dr(instruction)?] as u16;
+        let value = instruction_to_imm16(instruction)?;
+        self.acv_exception(addr as usize)?;
+        self.store(addr, value as u16)
     }
 
-    fn st(&mut self, instruction: Instruction) -> Result<(), String> {
-        let sr = instruction_to_dr(&instruction)?;
-        let addr = instruction_to_addr(&instruction)? as usize;
-        self.acv_exception(addr)?;
-        self.memory[addr] = self.register[sr] as u16;
-        Ok(())
+    fn st(&mut self, instruction: &Instruction) -> Result<(), ExecError> {
+        let sr = instruction_to_dr(instruction)?;
+        let addr = instruction_to_addr(instruction)? as u16;
+        self.acv_exception(addr as usize)?;
+        self.store(addr, self.register[sr] as u16)
     }
 
-    fn lda(&mut self, instruction: Instruction) -> Result<(), String> {
-        let dr = instruction_to_dr(&instruction)?;
-        let addr = instruction_to_addr(&instruction)? as usize;
-        let value = self.memory[addr] as i16;
+    fn lda(&mut self, instruction: &Instruction) -> Result<(), ExecError> {
+        let dr = instruction_to_dr(instruction)?;
+        let addr = instruction_to_addr(instruction)? as u16;
+        // reading KBDR or TCR clears their ready bits, so loads are checked like stores
+        self.acv_exception(addr as usize)?;
+        let value = self.load(addr)? as i16;
         self.setcc(value);
         self.register[dr] = value;
         Ok(())
     }
 
-    fn jsr(&mut self, instruction: &Instruction) -> Result<(), String> {
-        let addr = instruction_to_addr(instruction)? as usize;
-        self.register[7] = self.pc as i16 + 2;
-        self.pc = addr - 2;
+    fn jsr(&mut self, instruction: &Instruction) -> Result<(), ExecError> {
+        let addr = instruction_to_addr(instruction)? as u16;
+        self.register[7] = self.pc as i16;
+        self.pc = addr;
         Ok(())
     }
 
-    // found out how, the pc advances by 2 after BR :skull:
-    fn br(&mut self, instruction: &Instruction) -> Result<(), String> {
+    fn br(&mut self, instruction: &Instruction) -> Result<(), ExecError> {
         let addr = instruction_to_addr(instruction)?;
         match &instruction.operand1 {
             Some(br) => match br {
                 Operand::BR(flag) => {
                     if (flag.n & self.psr.n) | (flag.z & self.psr.z) | (flag.p & self.psr.p) {
-                        self.pc = addr as usize - 2;
+                        self.pc = addr as u16;
                     };
                     Ok(())
                 }
-                _ => Err("br came with something other than flags".to_owned()),
+                _ => Err("br came with something other than flags".to_owned().into()),
             },
-            None => Err("BR does not have flag".to_owned()),
+            None => Err("BR does not have flag".to_owned().into()),
         }
     }
 
-    fn clrp(&mut self) -> Result<(), String> {
+    fn clrp(&mut self) -> Result<(), ExecError> {
         self.asg.set_seed(0, 0, 0);
         Ok(())
     }
 
-    fn lpn(&mut self) -> Result<(), String> {
+    fn lpn(&mut self) -> Result<(), ExecError> {
         self.register[0] = self.asg.clock_16() as i16;
         Ok(())
     }
 
-    fn lsd(&mut self) -> Result<(), String> {
+    fn lsd(&mut self) -> Result<(), ExecError> {
         //        let mut addr = self.register[0] as usize;
         let clock = self.register[0] as u16;
         // addr += 1;
@@ -294,72 +488,76 @@ impl<'a, W: Write> Machine<'a, W> {
         Ok(())
     }
 
-    fn rti(&mut self) -> Result<(), String> {
+    fn rti(&mut self) -> Result<(), ExecError> {
+        if !self.psr.supervisor {
+            return Err(ExecError::PrivilegeViolation);
+        }
+        self.pc = self.pop()?;
+        self.psr = PSR::from_word(self.pop()?);
         if !self.psr.supervisor {
-            return Err("privilege mode exception".to_owned());
+            self.ssp = self.register[6] as u16;
+            self.register[6] = self.usp as i16;
         }
-        self.pc = self.memory[self.register[6] as usize] as usize - 1;
-        self.ssp; // TODO
-                  // somewhat uneeded because trap instructions and interrupts don't exist
         Ok(())
     }
 
-    fn not(&mut self, instruction: Instruction) -> Result<(), String> {
-        let dr = instruction_to_dr(&instruction)?;
-        let sr = instruction_to_sr1(&instruction)?;
+    fn not(&mut self, instruction: &Instruction) -> Result<(), ExecError> {
+        let dr = instruction_to_dr(instruction)?;
+        let sr = instruction_to_sr1(instruction)?;
         let value = !self.register[sr];
         self.register[dr] = value;
         Ok(())
     }
 
-    fn str(&mut self, instruction: Instruction) -> Result<(), String> {
-        let dest_addr = instruction_to_dr(&instruction)?;
-        self.acv_exception(dest_addr)?;
-        let value = instruction_to_imm7(&instruction)?;
-        self.memory[dest_addr] = value as u16;
-        Ok(())
+    fn str(&mut self, instruction: &Instruction) -> Result<(), ExecError> {
+        let dest_addr = self.register[instruction_to_dr(instruction)?] as u16;
+        self.acv_exception(dest_addr as usize)?;
+        let value = instruction_to_imm7(instruction)?;
+        self.store(dest_addr, value as u16)
     }
 
-    fn acv_exception(&mut self, addr: usize) -> Result<(), String> {
+    fn acv_exception(&mut self, addr: usize) -> Result<(), ExecError> {
         if address_privileged(addr) & !self.psr.supervisor {
-            return Err("ACV exception, privileged memory in non supervisor".to_owned());
+            return Err(ExecError::AccessViolation(addr as u16));
         }
         Ok(())
     }
 
-    fn ld(&mut self, instruction: Instruction) -> Result<(), String> {
-        let dr = instruction_to_dr(&instruction)?;
-        let num = instruction_to_imm7(&instruction)?;
+    fn ld(&mut self, instruction: &Instruction) -> Result<(), ExecError> {
+        let dr = instruction_to_dr(instruction)?;
+        let num = instruction_to_imm7(instruction)?;
         self.setcc(num);
         self.register[dr] = num;
         Ok(())
     }
 
-    fn jump(&mut self, instruction: Instruction) -> Result<(), String> {
-        let reg = instruction_to_dr(&instruction)?;
-        self.pc = self.register[reg] as usize - 1;
+    fn jump(&mut self, instruction: &Instruction) -> Result<(), ExecError> {
+        let reg = instruction_to_dr(instruction)?;
+        self.pc = self.register[reg] as u16;
         Ok(())
     }
 
-    fn ret(&mut self) -> Result<(), String> {
-        self.pc = self.register[7] as usize - 1;
+    fn ret(&mut self) -> Result<(), ExecError> {
+        self.pc = self.register[7] as u16;
         Ok(())
     }
 
-    fn jsrr(&mut self, instruction: Instruction) -> Result<(), String> {
+    fn jsrr(&mut self, instruction: &Instruction) -> Result<(), ExecError> {
+        // read the target first in case it is R7
+        let reg = instruction_to_dr(instruction)?;
+        let target = self.register[reg] as u16;
         self.register[7] = self.pc as i16;
-        let reg = instruction_to_dr(&instruction)?;
-        self.pc = self.register[reg] as usize - 1;
+        self.pc = target;
         Ok(())
     }
 
-    fn execute_double_def(&mut self, instruction: Instruction) -> Result<(), String> {
-        let dr: usize = instruction_to_dr(&instruction)?;
-        let sr1: usize = instruction_to_sr1(&instruction)?;
+    fn execute_double_def(&mut self, instruction: &Instruction) -> Result<(), ExecError> {
+        let dr: usize = instruction_to_dr(instruction)?;
+        let sr1: usize = instruction_to_sr1(instruction)?;
         let sr2 = match &instruction.operand2 {
             Some(op) => match op {
-                Operand::Imm16(_) => Ok(instruction_to_imm16(&instruction)?),
-                Operand::Address(_) => Ok(instruction_to_addr(&instruction)?),
+                Operand::Imm16(_) => Ok(instruction_to_imm16(instruction)?),
+                Operand::Address(_) => Ok(instruction_to_addr(instruction)?),
                 _ => Err("unexpected second operand in double instruction".to_owned()),
             },
             None => Err("no second operand double instruction".to_owned()),
@@ -373,7 +571,8 @@ impl<'a, W: Write> Machine<'a, W> {
                 Ok(())
             }
             Operation::ADDa => {
-                let value = self.register[sr1] + self.memory[sr2 as usize] as i16;
+                self.acv_exception(sr2 as u16 as usize)?;
+                let value = self.register[sr1] + self.load(sr2 as u16)? as i16;
                 self.setcc(value);
                 self.register[dr] = value;
                 Ok(())
@@ -385,7 +584,8 @@ impl<'a, W: Write> Machine<'a, W> {
                 Ok(())
             }
             Operation::ANDa => {
-                let value = self.register[sr1] & self.memory[sr2 as usize] as i16;
+                self.acv_exception(sr2 as u16 as usize)?;
+                let value = self.register[sr1] & self.load(sr2 as u16)? as i16;
                 self.setcc(value);
                 self.register[dr] = value;
                 Ok(())
@@ -397,23 +597,27 @@ impl<'a, W: Write> Machine<'a, W> {
                 Ok(())
             }
             Operation::XORa => {
-                let value = self.register[sr1] ^ self.memory[sr2 as usize] as i16;
+                self.acv_exception(sr2 as u16 as usize)?;
+                let value = self.register[sr1] ^ self.load(sr2 as u16)? as i16;
                 self.setcc(value);
                 self.register[dr] = value;
                 Ok(())
             }
-            _ => Err("".to_owned()),
+            _ => Err(ExecError::Malformed(format!(
+                "{:?} executed as the wrong instruction form",
+                instruction.operation
+            ))),
         }
     }
 
-    fn execute_def(&mut self, instruction: Instruction) -> Result<(), String> {
+    fn execute_def(&mut self, instruction: &Instruction) -> Result<(), ExecError> {
         // this is for intructions in the 'default' configuration (dr, sr1, sr2|imm3)
-        let dr: usize = instruction_to_dr(&instruction)?;
-        let sr1: usize = instruction_to_sr1(&instruction)?;
+        let dr: usize = instruction_to_dr(instruction)?;
+        let sr1: usize = instruction_to_sr1(instruction)?;
         let sr2 = match &instruction.operand2 {
             Some(op) => match op {
-                Operand::Imm3(_) => Ok(instruction_to_imm3(&instruction)?),
-                Operand::Register(_) => Ok(instruction_to_sr2(&instruction)? as i16),
+                Operand::Imm3(_) => Ok(instruction_to_imm3(instruction)?),
+                Operand::Register(_) => Ok(instruction_to_sr2(instruction)? as i16),
                 _ => Err("unexpected second operand".to_owned()),
             },
             None => Err("no second operand".to_owned()),
@@ -457,49 +661,533 @@ impl<'a, W: Write> Machine<'a, W> {
                 self.register[dr] = value;
                 Ok(())
             }
-            _ => Err("".to_owned()),
+            _ => Err(ExecError::Malformed(format!(
+                "{:?} executed as the wrong instruction form",
+                instruction.operation
+            ))),
         }
     }
 
-    // runs the machine until it reaches a halt instruction or exception
-    pub fn run_machine(&mut self) -> Result<(), String> {
-        //let mut key;
-        while self.halt_flag & (self.pc < 0xFE00)
-        // for if multi-threaded input handling is implemented
-        // & (self.input_buffer.last() != Some(&('\x1B' as u8)))
-        {
-            if self.memory[self.pc] == 0 {
-                self.halt_flag = false;
-            } else {
-                let mut out = String::new();
-                if check_instruction_double(self.memory[self.pc]) {
-                    out += &format!(
-                        "{:016b}\n{:016b}\nexectuing: {:?}\n",
-                        self.memory[self.pc],
-                        self.memory[self.pc + 1],
-                        tokenize(self.memory[self.pc], Some(self.memory[self.pc + 1]))?
-                    );
-                    self.simulate_instruction()?;
-                    out += &self.pretty_print();
-                    self.pc += 2;
-                } else {
-                    out += &format!(
-                        "{:016b}\nexecuting: {:?}\n",
-                        self.memory[self.pc],
-                        tokenize(self.memory[self.pc], None)?
-                    );
-                    self.simulate_instruction()?;
-                    out += &self.pretty_print();
-                    self.pc += 1;
+    // fetch, decode and execute the instruction at the PC
+    pub fn step(&mut self) -> Result<(), SimError> {
+        if self.is_halted() {
+            return Ok(());
+        }
+        self.executed = None;
+        let before = match self.history.as_ref() {
+            Some(history) => {
+                if history.wants_checkpoint(self.steps) {
+                    let checkpoint = Checkpoint {
+                        step: self.steps,
+                        cpu: self.cpu_state(),
+                        memory: Box::new(*self.bus.memory()),
+                    };
+                    if let Some(history) = self.history.as_mut() {
+                        history.push_checkpoint(checkpoint);
+                    }
                 }
-                log(&out);
+                Some(self.cpu_state())
+            }
+            None => None,
+        };
+        // the register file before the step, the instructions replayed by stepping back are not traced again
+        let traced = match self.trace {
+            Some(_) if !self.input.replaying => Some(self.register),
+            _ => None,
+        };
+        let mut result = self
+            .update_devices()
+            .and_then(|_| self.service_interrupt())
+            .map_err(|error| match error {
+                ExecError::Io(reason) => SimError::Io {
+                    fault: None,
+                    reason,
+                },
+                error => error.at(Fault {
+                    pc: self.pc,
+                    words: Vec::new(),
+                    instruction: None,
+                }),
+            })
+            .and_then(|_| self.execute_next())
+            .or_else(|error| self.raise_exception(error));
+        if let Some(cpu) = before {
+            let record = UndoRecord {
+                cpu,
+                memory: std::mem::take(&mut self.written),
+                input: std::mem::take(&mut self.input.recorded),
+            };
+            if let Some(history) = self.history.as_mut() {
+                history.push_record(self.steps, record);
+            }
+        }
+        if let Some(before) = traced {
+            result = result.and(self.write_trace(before));
+        }
+        self.traced = None;
+        self.trace_writes.clear();
+        self.steps += 1;
+        result
+    }
+
+    // the trace record for the step, nothing is recorded when no instruction was decoded
+    fn write_trace(&mut self, before: [i16; 8]) -> Result<(), SimError> {
+        let executed = match self.traced.take() {
+            Some(executed) => executed,
+            None => return Ok(()),
+        };
+        let record = TraceRecord {
+            step: self.steps,
+            pc: executed.pc,
+            symbol: self.symbols.name(executed.pc),
+            source: self.line_map.location(executed.pc),
+            words: executed.words,
+            disassembly: executed
+                .instruction
+                .map(|instruction| instruction.with(&self.symbols).to_string())
+                .unwrap_or_default(),
+            registers: (0..8)
+                .filter(|&reg| self.register[reg] != before[reg])
+                .map(|reg| (reg, self.register[reg] as u16))
+                .collect(),
+            memory: std::mem::take(&mut self.trace_writes),
+            psr: self.psr.to_word(),
+            n: self.psr.n,
+            z: self.psr.z,
+            p: self.psr.p,
+        };
+        match self.trace.as_mut() {
+            Some(trace) => trace.record(&record).map_err(|error| SimError::Io {
+                fault: None,
+                reason: format!("couldn't write trace: {}", error),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    fn execute_next(&mut self) -> Result<(), SimError> {
+        let pc = self.pc;
+        let fetch_error = |error: ExecError| {
+            error.at(Fault {
+                pc,
+                words: Vec::new(),
+                instruction: None,
+            })
+        };
+        let word = self.fetch(pc).map_err(fetch_error)?;
+        // an empty word marks the end of the program
+        if word == 0 {
+            self.halt_flag = false;
+            return Ok(());
+        }
+        let mut words = vec![word];
+        if check_instruction_double(word) {
+            words.push(self.fetch(pc.wrapping_add(1)).map_err(fetch_error)?);
+        }
+        let (instruction, length) = decode(pc, &words)?;
+        if !self.limits.is_unlimited() {
+            if self.recent.len() == RECENT_INSTRUCTIONS {
+                self.recent.pop_front();
             }
+            self.recent.push_back(Fault {
+                pc,
+                words: words.clone(),
+                instruction: Some(instruction.clone()),
+            });
+        }
+        if self.trace.is_some() {
+            self.traced = Some(Fault {
+                pc,
+                words: words.clone(),
+                instruction: Some(instruction.clone()),
+            });
+        }
+        self.pc = pc.wrapping_add(length);
+        if let Err(error) = self.simulate_instruction(&instruction) {
+            let fault = Fault {
+                pc,
+                words,
+                instruction: Some(instruction),
+            };
+            return Err(error.at(fault));
+        }
+        self.executed = Some(instruction);
+        Ok(())
+    }
+
+    fn words_at(&self, addr: u16, length: u16) -> Vec<u16> {
+        (0..length)
+            .map(|i| self.bus.peek(addr.wrapping_add(i)))
+            .collect()
+    }
+
+    // decode the instruction at addr, returning it with its length in words
+    // the words are read without side effects, so device registers are not accessed
+    pub fn decode_at(&self, addr: u16) -> Result<(Instruction, u16), SimError> {
+        let word = self.bus.peek(addr);
+        let length = if check_instruction_double(word) { 2 } else { 1 };
+        decode(addr, &self.words_at(addr, length))
+    }
+
+    // runs the machine until it halts or `stop` returns true, the check happens before every instruction
+    pub fn run_until<F: FnMut(&Self) -> bool>(&mut self, mut stop: F) -> Result<(), SimError> {
+        while !self.is_halted() && !stop(self) {
+            self.step()?;
+        }
+        Ok(())
+    }
+
+    // runs the machine until it reaches a halt instruction or exception, or one of its limits
+    pub fn run_machine(&mut self) -> Result<(), SimError> {
+        if self.limits.is_unlimited() {
+            return self.run_until(|_| false);
+        }
+        let limits = self.limits;
+        let started = Instant::now();
+        let mut loops = LoopDetector::default();
+        let mut steps = 0;
+        while !self.is_halted() {
+            let limit = match limits {
+                Limits {
+                    max_steps: Some(max_steps),
+                    ..
+                } if steps >= max_steps => Some(Limit::Steps(max_steps)),
+                Limits {
+                    timeout: Some(timeout),
+                    ..
+                } if started.elapsed() >= timeout => Some(Limit::Timeout(timeout)),
+                Limits {
+                    detect_loops: true, ..
+                } => loops
+                    .check(self.pc, || self.loop_hash())
+                    .map(|period| Limit::Loop { period }),
+                _ => None,
+            };
+            if let Some(limit) = limit {
+                return Err(SimError::LimitExceeded {
+                    limit,
+                    pc: self.pc,
+                    recent: self.recent.iter().cloned().collect(),
+                });
+            }
+            self.step()?;
+            steps += 1;
+        }
+        Ok(())
+    }
+
+    // hash of everything that decides what the machine does next, memory is represented by the number
+    // of writes that changed it and input by the reads and waits for it
+    fn loop_hash(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        (
+            self.pc,
+            self.register,
+            self.psr.to_word(),
+            self.usp,
+            self.ssp,
+        )
+            .hash(&mut hasher);
+        let asg = &self.asg;
+        (asg.clock.state, asg.first.state, asg.second.state).hash(&mut hasher);
+        (&self.interrupts, self.memory_changes, self.input.progress).hash(&mut hasher);
+        self.bus.hash_devices(&mut hasher);
+        hasher.finish()
+    }
+
+    // the step limit, timeout and loop detection for run_machine
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+        self.recent.clear();
+    }
+
+    pub fn limits(&self) -> Limits {
+        self.limits
+    }
+
+    // start recording undo information so the machine can step backwards
+    pub fn enable_history(&mut self, config: HistoryConfig) {
+        self.history = Some(History::new(config));
+        self.input.recording = true;
+    }
+
+    pub fn disable_history(&mut self) {
+        self.history = None;
+        self.input.recording = false;
+    }
+
+    pub fn history(&self) -> Option<&History> {
+        self.history.as_ref()
+    }
+
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    // interrupt and exception handlers entered so far, the debugger counts them like calls
+    pub fn handler_entries(&self) -> u64 {
+        self.handler_entries
+    }
+
+    // the instruction the last step executed, the first one of the handler when it took an interrupt,
+    // None when it raised an exception instead
+    pub fn executed(&self) -> Option<&Instruction> {
+        self.executed.as_ref()
+    }
+
+    fn cpu_state(&self) -> CpuState {
+        CpuState {
+            pc: self.pc,
+            register: self.register,
+            psr: self.psr.clone(),
+            usp: self.usp,
+            ssp: self.ssp,
+            asg: [
+                self.asg.clock.state,
+                self.asg.first.state,
+                self.asg.second.state,
+            ],
+            halt_flag: self.halt_flag,
+            interrupts: self.interrupts.clone(),
+            devices: self.bus.save_devices(),
+        }
+    }
+
+    fn restore_cpu_state(&mut self, cpu: &CpuState) {
+        self.pc = cpu.pc;
+        self.register = cpu.register;
+        self.psr = cpu.psr.clone();
+        self.usp = cpu.usp;
+        self.ssp = cpu.ssp;
+        self.asg.set_seed(cpu.asg[0], cpu.asg[1], cpu.asg[2]);
+        self.halt_flag = cpu.halt_flag;
+        self.interrupts = cpu.interrupts.clone();
+        self.bus.restore_devices(&cpu.devices);
+    }
+
+    // the whole machine, to be resumed later with restore_snapshot, possibly by another machine
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            cpu: self.cpu_state(),
+            steps: self.steps,
+            input: self.input.pending.iter().copied().collect(),
+            memory: Box::new(*self.bus.memory()),
         }
+    }
+
+    // continue from a snapshot, the machine needs the same devices as the one it was taken from,
+    // the history is cleared since it belongs to the old state
+    pub fn restore_snapshot(&mut self, snapshot: &Snapshot) -> Result<(), String> {
+        let devices = self.bus.save_devices();
+        let matches = devices.len() == snapshot.cpu.devices.len()
+            && devices
+                .iter()
+                .zip(&snapshot.cpu.devices)
+                .all(|(device, saved)| device.len() == saved.len());
+        if !matches {
+            return Err("the snapshot was taken with different devices on the bus".to_owned());
+        }
+        self.restore_cpu_state(&snapshot.cpu);
+        self.bus.memory_mut().copy_from_slice(&snapshot.memory[..]);
+        self.steps = snapshot.steps;
+        self.input.pending = snapshot.input.iter().copied().collect();
+        self.recent.clear();
+        self.invalidate_history();
         Ok(())
     }
 
+    // undo the last instruction, returns false when there is no history left to go back to
+    pub fn reverse_step(&mut self) -> Result<bool, SimError> {
+        let history = match self.history.as_mut() {
+            Some(history) => history,
+            None => return Ok(false),
+        };
+        if self.steps == 0 {
+            return Ok(false);
+        }
+        if let Some(record) = history.records.pop_back() {
+            history.truncate(self.steps - 1);
+            for (addr, old) in record.memory.iter().rev() {
+                self.bus.memory_mut()[*addr as usize] = *old;
+            }
+            self.steps -= 1;
+            for key in record.input.iter().rev() {
+                self.input.pending.push_front((self.steps, *key));
+            }
+            self.restore_cpu_state(&record.cpu);
+            return Ok(true);
+        }
+        // the records ran out, go back to the closest checkpoint and replay up to the previous instruction
+        let target = self.steps - 1;
+        let checkpoint = match history.checkpoints.iter().rev().find(|c| c.step <= target) {
+            Some(checkpoint) => checkpoint,
+            None => return Ok(false),
+        };
+        self.bus
+            .memory_mut()
+            .copy_from_slice(&checkpoint.memory[..]);
+        let cpu = checkpoint.cpu.clone();
+        let start = checkpoint.step;
+        for input in history.truncate(start).into_iter().rev() {
+            self.input.pending.push_front(input);
+        }
+        self.restore_cpu_state(&cpu);
+        self.steps = start;
+        self.input.replaying = true;
+        let mut result = Ok(());
+        while self.steps < target && result.is_ok() {
+            result = self.step();
+        }
+        self.input.replaying = false;
+        result.map(|_| true)
+    }
+
+    // changing the state by hand makes the recorded history meaningless
+    fn invalidate_history(&mut self) {
+        if let Some(history) = self.history.as_mut() {
+            history.clear();
+        }
+    }
+
+    pub fn is_halted(&self) -> bool {
+        !self.halt_flag
+    }
+
+    // clears the halt state so execution can resume
+    pub fn resume(&mut self) {
+        self.invalidate_history();
+        self.halt_flag = true;
+    }
+
+    // when disabled a trap with no handler installed stops the machine with an error
+    pub fn set_builtin_traps(&mut self, enabled: bool) {
+        self.builtin_traps = enabled;
+    }
+
+    // when disabled exceptions always stop the machine with an error, as if no handler was installed
+    pub fn set_vector_exceptions(&mut self, enabled: bool) {
+        self.vector_exceptions = enabled;
+    }
+
+    pub fn set_end_of_input(&mut self, end_of_input: EndOfInput) {
+        self.end_of_input = end_of_input;
+    }
+
+    // record every instruction executed from now on, None stops tracing
+    pub fn set_trace(&mut self, trace: Option<Trace>) {
+        self.trace = trace;
+        self.traced = None;
+        self.trace_writes.clear();
+    }
+
+    // name addresses in traces after these labels
+    pub fn set_symbols(&mut self, symbols: Symbols) {
+        self.symbols = symbols;
+    }
+
+    pub fn symbols(&self) -> &Symbols {
+        &self.symbols
+    }
+
+    // show the source line of each instruction in traces
+    pub fn set_line_map(&mut self, line_map: LineMap) {
+        self.line_map = line_map;
+    }
+
+    pub fn line_map(&self) -> &LineMap {
+        &self.line_map
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    pub fn set_pc(&mut self, pc: u16) {
+        self.invalidate_history();
+        self.pc = pc;
+    }
+
+    pub fn registers(&self) -> &[i16; 8] {
+        &self.register
+    }
+
+    pub fn register(&self, index: usize) -> i16 {
+        self.register[index]
+    }
+
+    pub fn set_register(&mut self, index: usize, value: i16) {
+        self.invalidate_history();
+        self.register[index] = value;
+    }
+
+    pub fn psr(&self) -> &PSR {
+        &self.psr
+    }
+
+    pub fn set_psr(&mut self, psr: PSR) {
+        self.invalidate_history();
+        self.psr = psr;
+    }
+
+    pub fn interrupts(&self) -> &InterruptController {
+        &self.interrupts
+    }
+
+    pub fn interrupts_mut(&mut self) -> &mut InterruptController {
+        &mut self.interrupts
+    }
+
+    // assert an interrupt, it is taken before the next instruction if its priority is above the program's
+    pub fn raise_interrupt(&mut self, priority: u3, vector: u8) {
+        self.interrupts.raise(priority, vector);
+    }
+
+    pub fn usp(&self) -> u16 {
+        self.usp
+    }
+
+    pub fn ssp(&self) -> u16 {
+        self.ssp
+    }
+
+    pub fn console(&self) -> &C {
+        &self.term
+    }
+
+    pub fn console_mut(&mut self) -> &mut C {
+        &mut self.term
+    }
+
+    pub fn asg(&self) -> &ASG {
+        &self.asg
+    }
+
+    // RAM without the device registers
+    pub fn memory(&self) -> &[u16; 65536] {
+        self.bus.memory()
+    }
+
+    pub fn bus(&self) -> &Bus {
+        &self.bus
+    }
+
+    // for registering devices, hooks and watchpoints
+    pub fn bus_mut(&mut self) -> &mut Bus {
+        &mut self.bus
+    }
+
+    // device registers read as their current value without doing any I/O
+    pub fn read_memory(&self, addr: u16) -> u16 {
+        self.bus.peek(addr)
+    }
+
+    pub fn write_memory(&mut self, addr: u16, value: u16) {
+        self.invalidate_history();
+        self.bus.memory_mut()[addr as usize] = value;
+    }
+
     // pretty print all info
-    fn pretty_print(&self) -> String {
+    pub fn pretty_print(&self) -> String {
         let mut out = String::new();
         out += &format!("PC: {} ", self.pc);
         //println!("PC: 0x{:04x}", self.pc);
@@ -512,22 +1200,23 @@ impl<'a, W: Write> Machine<'a, W> {
         out
     }
 
-    fn print_asg(&self) -> String {
+    pub fn print_asg(&self) -> String {
         format!(
             "clock:0x{:04x} first:0x{:04x} second:0x{:04x}\n",
             self.asg.clock.state, self.asg.first.state, self.asg.second.state
         )
     }
 
-    fn print_nzp(&self) -> String {
+    pub fn print_nzp(&self) -> String {
         format!("n: {} z: {} p: {}\n", self.psr.n, self.psr.z, self.psr.p)
     }
 
     // print memory around PC
     fn print_pretty_memory(&self) -> String {
         let mut out: String = String::new();
-        for i in (self.pc)..(self.pc + 2) {
-            out += &format!("0x{:04x}: {:016b}\n", i, self.memory[i]);
+        for i in 0..2 {
+            let addr = self.pc.wrapping_add(i);
+            out += &format!("0x{:04x}: {:016b}\n", addr, self.bus.peek(addr));
         }
         out
     }
@@ -560,7 +1249,7 @@ fn instruction_to_addr(instruction: &Instruction) -> Result<i16, String> {
     match &instruction.operand2 {
         Some(addr) => match addr {
             Operand::Address(addr) => Ok(*addr as i16),
-            Operand::Imm16(num) => Ok(*num as i16),
+            Operand::Imm16(num) => Ok(*num),
             _ => Err("incorrect operand2, not address".to_owned()),
         },
         None => Err("no address specified".to_owned()),
@@ -611,6 +1300,271 @@ fn instruction_to_imm16(instruction: &Instruction) -> Result<i16, String> {
     }
 }
 
+// decode the one or two words of an instruction at addr, returning it with its length
+fn decode(addr: u16, words: &[u16]) -> Result<(Instruction, u16), SimError> {
+    match tokenize(words[0], words.get(1).copied()) {
+        Ok(instruction) => Ok((instruction, words.len() as u16)),
+        Err(error) => {
+            let fault = Fault {
+                pc: addr,
+                words: words.to_vec(),
+                instruction: None,
+            };
+            match error {
+                DecodeError::IllegalOpcode(_) => Err(SimError::IllegalOpcode { fault }),
+                _ => Err(SimError::Decode {
+                    fault,
+                    reason: error.to_string(),
+                }),
+            }
+        }
+    }
+}
+
+fn device_error(error: io::Error) -> ExecError {
+    ExecError::Io(format!("device error: {}", error))
+}
+
+// the vector tables and the OS below x3000 and the device page from xFE00 are supervisor only
 fn address_privileged(addr: usize) -> bool {
     !(0x3000..=0xFDFF).contains(&addr)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble_memory;
+    use crate::console::BufferConsole;
+    use crate::devices::{DDR, DSR, KBDR, MCR, READY};
+    use crate::interrupt::{INTERRUPT_TABLE, PRIVILEGE_VECTOR};
+
+    const HALT: u16 = 0b01100 << 11 | 0x25;
+    const TEXT: u16 = 0x4000;
+
+    // a machine with program at 0x3000 followed by HALT and the text words at TEXT
+    fn load(program: &[u16], text: &[u16], input: &[u8]) -> Machine<BufferConsole> {
+        let mut mem = [0u16; 65536];
+        mem[0x3000..0x3000 + program.len()].copy_from_slice(program);
+        mem[0x3000 + program.len()] = HALT;
+        mem[TEXT as usize..TEXT as usize + text.len()].copy_from_slice(text);
+        Machine::new(Some(mem), BufferConsole::new(input))
+    }
+
+    // ST R0, addr
+    fn store_r0(addr: u16) -> [u16; 2] {
+        [0b01001 << 11 | 1 << 10, addr]
+    }
+
+    // runs a trap followed by HALT with R0 set beforehand
+    fn run_trap(vector: u16, r0: i16, text: &[u16], input: &[u8]) -> Machine<BufferConsole> {
+        let mut machine = load(&[0b01100 << 11 | vector], text, input);
+        machine.set_register(0, r0);
+        machine.run_machine().unwrap();
+        assert!(machine.is_halted());
+        machine
+    }
+
+    #[test]
+    fn getc_reads_a_key_into_r0() {
+        let machine = run_trap(0x20, 0, &[], b"ab");
+        assert_eq!(machine.register(0), b'a' as i16);
+        assert_eq!(machine.console().output(), b"");
+        assert_eq!(machine.console().remaining_input(), b"b");
+    }
+
+    #[test]
+    fn out_writes_the_low_byte_of_r0() {
+        let machine = run_trap(0x21, 0x1248, &[], b"");
+        assert_eq!(machine.register(0), 0x1248);
+        assert_eq!(machine.console().output(), b"H");
+    }
+
+    #[test]
+    fn puts_writes_one_character_per_word() {
+        let text: Vec<u16> = b"Hello\0".iter().map(|&c| c as u16).collect();
+        let machine = run_trap(0x22, TEXT as i16, &text, b"");
+        assert_eq!(machine.register(0), TEXT as i16);
+        assert_eq!(machine.console().output(), b"Hello");
+    }
+
+    #[test]
+    fn in_prompts_and_echoes_the_key() {
+        let machine = run_trap(0x23, 0, &[], b"z");
+        assert_eq!(machine.register(0), b'z' as i16);
+        assert_eq!(machine.console().output(), b"\ninput: z\n");
+    }
+
+    #[test]
+    fn putsp_writes_two_characters_per_word() {
+        let text = [u16::from_be_bytes(*b"He"), u16::from_be_bytes(*b"y!"), 0];
+        let machine = run_trap(0x24, TEXT as i16, &text, b"");
+        assert_eq!(machine.register(0), TEXT as i16);
+        assert_eq!(machine.console().output(), b"Hey!");
+    }
+
+    #[test]
+    fn user_mode_can_store_between_x3000_and_xfdff() {
+        for addr in [0x3000, TEXT, 0xFDFF] {
+            let mut machine = load(&store_r0(addr), &[], b"");
+            machine.set_register(0, 0x1234);
+            machine.run_machine().unwrap();
+            assert_eq!(machine.read_memory(addr), 0x1234);
+        }
+    }
+
+    #[test]
+    fn user_mode_stores_outside_user_memory_are_violations() {
+        for addr in [0x0000, 0x2FFF, DDR, MCR, 0xFFFF] {
+            let mut machine = load(&store_r0(addr), &[], b"");
+            machine.set_register(0, b'A' as i16);
+            match machine.run_machine() {
+                Err(SimError::AccessViolation { address, .. }) => assert_eq!(address, addr),
+                other => panic!("0x{:04x}: {:?}", addr, other),
+            }
+            assert_eq!(machine.console().output(), b"");
+        }
+    }
+
+    #[test]
+    fn user_mode_str16_outside_user_memory_is_a_violation() {
+        // STR16 R0, 'A' stores to the address held in R0
+        let mut machine = load(&[0b00111 << 11 | 1 << 10, b'A' as u16], &[], b"");
+        machine.set_register(0, DDR as i16);
+        match machine.run_machine() {
+            Err(SimError::AccessViolation { address, .. }) => assert_eq!(address, DDR),
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(machine.console().output(), b"");
+    }
+
+    #[test]
+    fn supervisor_mode_can_store_to_the_device_page() {
+        let mut machine = load(&store_r0(DDR), &[], b"");
+        machine.set_psr(PSR {
+            supervisor: true,
+            ..machine.psr().clone()
+        });
+        machine.set_register(0, b'A' as i16);
+        machine.run_machine().unwrap();
+        assert_eq!(machine.console().output(), b"A");
+    }
+
+    // LDa R0, addr
+    fn load_r0(addr: u16) -> [u16; 2] {
+        [0b01000 << 11 | 1 << 10, addr]
+    }
+
+    #[test]
+    fn user_mode_loads_outside_user_memory_are_violations() {
+        let mut machine = load(&load_r0(KBDR), &[], b"k");
+        match machine.run_machine() {
+            Err(SimError::AccessViolation { address, .. }) => assert_eq!(address, KBDR),
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(machine.register(0), 0);
+    }
+
+    #[test]
+    fn user_mode_memory_operands_outside_user_memory_are_violations() {
+        // ADDa, ANDa and XORa R0, R0, KBDR
+        for op in [0b00001, 0b00010, 0b00011] {
+            let program = [op << 11 | 1 << 10, KBDR];
+            let mut machine = load(&program, &[], b"k");
+            match machine.run_machine() {
+                Err(SimError::AccessViolation { address, .. }) => assert_eq!(address, KBDR),
+                other => panic!("{:05b}: {:?}", op, other),
+            }
+            assert_eq!(machine.register(0), 0);
+            assert_eq!(machine.console().remaining_input(), b"k");
+        }
+    }
+
+    #[test]
+    fn supervisor_mode_can_load_from_the_device_page() {
+        let mut machine = load(&load_r0(DSR), &[], b"");
+        machine.set_psr(PSR {
+            supervisor: true,
+            ..machine.psr().clone()
+        });
+        machine.run_machine().unwrap();
+        assert_eq!(machine.register(0) as u16 & READY, READY);
+    }
+
+    // OUT goes to the routine at x0200 installed in the trap table, HALT has no entry
+    const TRAP_PROGRAM: [&str; 2] = [
+        ".ORIG x3000\nAND R0, R0, #0\nADD R0, R0, #7\nOUT\nHALT\n.END\n",
+        ".ORIG x0200\nADD R1, R1, #1\nRTI\n.END\n",
+    ];
+
+    #[test]
+    fn traps_use_the_trap_table_before_the_built_in_routines() {
+        let mut mem = assemble_memory(&TRAP_PROGRAM);
+        mem[0x21] = 0x0200;
+        let mut machine = Machine::new(Some(mem), BufferConsole::new(b""));
+        machine.run_machine().unwrap();
+        assert!(machine.is_halted());
+        assert_eq!(machine.register(1), 1);
+        assert_eq!(machine.console().output(), b"");
+        assert!(!machine.psr().supervisor);
+    }
+
+    #[test]
+    fn traps_without_a_handler_fall_back_to_the_built_in_routines() {
+        let mem = assemble_memory(&TRAP_PROGRAM);
+        let mut machine = Machine::new(Some(mem), BufferConsole::new(b""));
+        machine.run_machine().unwrap();
+        assert_eq!(machine.register(1), 0);
+        assert_eq!(machine.console().output(), b"\x07");
+
+        let mut machine = Machine::new(Some(mem), BufferConsole::new(b""));
+        machine.set_builtin_traps(false);
+        match machine.run_machine() {
+            Err(SimError::UnhandledTrap { vector, .. }) => assert_eq!(vector, 0x21),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    // RTI in user mode, with the privilege exception handler at x4000 counting in R2
+    fn privilege_exception() -> Machine<BufferConsole> {
+        let mut mem = assemble_memory(&[
+            ".ORIG x3000\nRTI\nADD R1, R1, #1\nHALT\n.END\n",
+            ".ORIG x4000\nADD R2, R2, #1\nRTI\n.END\n",
+        ]);
+        mem[(INTERRUPT_TABLE + PRIVILEGE_VECTOR as u16) as usize] = 0x4000;
+        Machine::new(Some(mem), BufferConsole::new(b""))
+    }
+
+    #[test]
+    fn exceptions_enter_their_handler_and_return_after_the_instruction() {
+        let mut machine = privilege_exception();
+        machine.step().unwrap();
+        assert_eq!(machine.pc(), 0x4000);
+        assert!(machine.psr().supervisor);
+        assert_eq!(machine.read_memory(0x2FFD), 0x3001);
+        machine.run_machine().unwrap();
+        assert!(machine.is_halted());
+        assert_eq!(machine.handler_entries(), 1);
+        assert_eq!(machine.register(1), 1);
+        assert_eq!(machine.register(2), 1);
+    }
+
+    #[test]
+    fn exceptions_stop_the_machine_when_they_are_not_vectored() {
+        let mut machine = privilege_exception();
+        machine.set_vector_exceptions(false);
+        match machine.run_machine() {
+            Err(SimError::PrivilegeViolation { fault }) => assert_eq!(fault.pc, 0x3000),
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(machine.handler_entries(), 0);
+        assert_eq!(machine.register(2), 0);
+
+        // an empty vector table entry stops it the same way
+        let mut machine = privilege_exception();
+        machine.write_memory(INTERRUPT_TABLE + PRIVILEGE_VECTOR as u16, 0);
+        assert!(matches!(
+            machine.run_machine(),
+            Err(SimError::PrivilegeViolation { .. })
+        ));
+    }
+}
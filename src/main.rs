@@ -1,80 +1,688 @@
-#![allow(dead_code)]
-use termion::raw::IntoRawMode;
-mod log;
-mod machine;
-mod prng;
-mod reader;
-mod tokenizer;
-use crate::log::log;
-use clap::command;
-use machine::Machine;
-use reader::read_input_files;
-use std::fs::File;
-use std::io::{stdin, stdout, Read, Write};
-use std::path::PathBuf;
+use clap::{command, ArgMatches, Command};
+use lc_4_simulator::asm::assemble;
+use lc_4_simulator::debugger::{run_repl, Debugger};
+use lc_4_simulator::disasm::listing;
+use lc_4_simulator::dump::{dump, parse_symbolic_range, DumpFormat};
+use lc_4_simulator::history::HistoryConfig;
+use lc_4_simulator::reader::{load_images, load_map, read_images, ImageFormat, Region};
+use lc_4_simulator::tui::{run_tui, TuiConsole};
+use lc_4_simulator::{
+    Console, EndOfInput, Limits, LineMap, Machine, SharedReader, Snapshot, StreamConsole, Symbols,
+    TerminalConsole, Trace, TraceFormat,
+};
+use std::fs::{self, File};
+use std::io::{stdin, stdout, BufReader, Cursor, Read};
+use std::path::{Path, PathBuf};
+use std::process::exit;
 use std::thread::sleep;
 use std::time::Duration;
 
-fn main() -> Result<(), String> {
-    let mut files: Vec<PathBuf> = Vec::new();
+fn input_arg() -> clap::Arg {
+    clap::Arg::new("input")
+        .num_args(1..)
+        .default_value("./examples/out.bin")
+        .value_parser(clap::value_parser!(PathBuf))
+        .help("binary images to load, in order")
+}
+
+fn load_map_arg() -> clap::Arg {
+    clap::Arg::new("load-map")
+        .long("load-map")
+        .action(clap::ArgAction::SetTrue)
+        .help("print the address range each image was loaded into")
+}
+
+fn no_builtin_traps_arg() -> clap::Arg {
+    clap::Arg::new("no-builtin-traps")
+        .long("no-builtin-traps")
+        .action(clap::ArgAction::SetTrue)
+        .help("stop with an error on a TRAP with no handler in the trap vector table instead of running the built-in routine")
+}
+
+fn stop_on_exception_arg() -> clap::Arg {
+    clap::Arg::new("stop-on-exception")
+        .long("stop-on-exception")
+        .action(clap::ArgAction::SetTrue)
+        .help("stop and report ACV, privilege and illegal opcode exceptions instead of entering their handlers")
+}
+
+fn on_eof_arg() -> clap::Arg {
+    clap::Arg::new("on-eof")
+        .long("on-eof")
+        .value_name("ACTION")
+        .value_parser(["null", "halt", "error"])
+        .default_value("null")
+        .help("what GETC, IN and the keyboard do at the end of input: read a null character, halt the machine or stop with an error")
+}
+
+fn trace_arg() -> clap::Arg {
+    clap::Arg::new("trace")
+        .long("trace")
+        .value_name("FILE")
+        .value_parser(clap::value_parser!(PathBuf))
+        .help("write a record of every executed instruction to FILE")
+}
+
+fn trace_format_arg() -> clap::Arg {
+    clap::Arg::new("trace-format")
+        .long("trace-format")
+        .value_name("FORMAT")
+        .value_parser(["jsonl", "csv"])
+        .requires("trace")
+        .help("format of the trace file [default: csv for a .csv file, jsonl otherwise]")
+}
+
+fn load_state_arg() -> clap::Arg {
+    clap::Arg::new("load-state")
+        .long("load-state")
+        .value_name("FILE")
+        .value_parser(clap::value_parser!(PathBuf))
+        .conflicts_with("input")
+        .help("start from a snapshot saved with --save-state or the debugger's save-state instead of loading images")
+}
+
+fn image_format_arg() -> clap::Arg {
+    clap::Arg::new("image-format")
+        .long("image-format")
+        .value_name("FORMAT")
+        .value_parser(["binary", "obj", "hex", "bits", "ihex"])
+        .help("format of the images: big endian words (LC-3 style .obj files are the same), hex or 0/1 text with one word per line, or Intel HEX [default: detected from each file]")
+}
+
+fn symbols_arg() -> clap::Arg {
+    clap::Arg::new("symbols")
+        .long("symbols")
+        .value_name("FILE")
+        .action(clap::ArgAction::Append)
+        .value_parser(clap::value_parser!(PathBuf))
+        .help("load a symbol table, one LABEL ADDRESS per line, a .sym file next to an image is loaded without it")
+}
+
+// the symbol tables given on the command line and the .sym files next to the images,
+// an address is only named after a label in the same image
+fn load_symbols(
+    given: &[PathBuf],
+    files: &[PathBuf],
+    regions: &[Region],
+) -> Result<Symbols, String> {
+    let beside = files
+        .iter()
+        .map(|path| path.with_extension("sym"))
+        .filter(|path| path.is_file());
+    let mut symbols = Symbols::default();
+    for path in given.iter().cloned().chain(beside) {
+        let table = Symbols::read(&path).map_err(|e| e.to_string())?;
+        symbols
+            .extend(&table)
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+    }
+    symbols.set_regions(regions);
+    Ok(symbols)
+}
+
+fn lines_arg() -> clap::Arg {
+    clap::Arg::new("lines")
+        .long("lines")
+        .value_name("FILE")
+        .action(clap::ArgAction::Append)
+        .value_parser(clap::value_parser!(PathBuf))
+        .help("load a line map, one ADDRESS FILE:LINE per line, a .lines file next to an image is loaded without it")
+}
+
+// the line maps given on the command line and the .lines files next to the images
+fn load_line_map(given: &[PathBuf], files: &[PathBuf]) -> Result<LineMap, String> {
+    let beside = files
+        .iter()
+        .map(|path| path.with_extension("lines"))
+        .filter(|path| path.is_file());
+    let mut lines = LineMap::default();
+    for path in given.iter().cloned().chain(beside) {
+        lines.extend(&LineMap::read(&path).map_err(|e| e.to_string())?);
+    }
+    Ok(lines)
+}
+
+fn paths(matches: &ArgMatches, id: &str) -> Vec<PathBuf> {
+    matches
+        .get_many::<PathBuf>(id)
+        .into_iter()
+        .flatten()
+        .cloned()
+        .collect()
+}
+
+fn image_format(matches: &ArgMatches) -> Option<ImageFormat> {
+    matches
+        .get_one::<String>("image-format")
+        .and_then(|name| ImageFormat::from_name(name))
+}
+
+fn input_files(matches: &ArgMatches) -> Vec<PathBuf> {
+    matches
+        .get_many::<PathBuf>("input")
+        .expect("could not parse input file path")
+        .cloned()
+        .collect()
+}
+
+// settings shared by running a program and debugging it
+struct Options {
+    load_map: bool,
+    image_format: Option<ImageFormat>,
+    builtin_traps: bool,
+    vector_exceptions: bool,
+    end_of_input: EndOfInput,
+    trace: Option<(PathBuf, TraceFormat)>,
+    load_state: Option<PathBuf>,
+    symbols: Vec<PathBuf>,
+    lines: Vec<PathBuf>,
+}
+
+// what the debugger, traces and errors know about the program's source
+struct DebugInfo {
+    symbols: Symbols,
+    lines: LineMap,
+}
+
+impl Options {
+    // the memory image and where each image went, nothing is loaded when starting from a snapshot
+    fn images(&self, files: &[PathBuf]) -> Result<([u16; 65536], Vec<Region>), String> {
+        match self.load_state {
+            Some(_) => Ok(([0; 65536], Vec::new())),
+            None => load_images(files, self.image_format).map_err(|e| e.to_string()),
+        }
+    }
+
+    // the symbol tables and line maps for the loaded images, only the ones given when starting from
+    // a snapshot
+    fn debug_info(&self, files: &[PathBuf], regions: &[Region]) -> Result<DebugInfo, String> {
+        let files = match self.load_state {
+            Some(_) => &[],
+            None => files,
+        };
+        Ok(DebugInfo {
+            symbols: load_symbols(&self.symbols, files, regions)?,
+            lines: load_line_map(&self.lines, files)?,
+        })
+    }
+
+    // the machine with these settings, ready to run the memory image or the snapshot
+    fn machine<C: Console>(
+        &self,
+        out: [u16; 65536],
+        info: DebugInfo,
+        console: C,
+    ) -> Result<Machine<C>, String> {
+        let mut lc4 = Machine::new(Some(out), console);
+        lc4.set_symbols(info.symbols);
+        lc4.set_line_map(info.lines);
+        if let Some(path) = &self.load_state {
+            let snapshot = Snapshot::read(path).map_err(|e| e.to_string())?;
+            lc4.restore_snapshot(&snapshot)
+                .map_err(|e| format!("{}: {}", path.display(), e))?;
+        }
+        lc4.set_builtin_traps(self.builtin_traps);
+        lc4.set_vector_exceptions(self.vector_exceptions);
+        lc4.set_end_of_input(self.end_of_input);
+        if let Some((path, format)) = &self.trace {
+            let trace =
+                Trace::create(path, *format).map_err(|e| format!("{}: {}", path.display(), e))?;
+            lc4.set_trace(Some(trace));
+        }
+        Ok(lc4)
+    }
+}
+
+impl From<&ArgMatches> for Options {
+    fn from(matches: &ArgMatches) -> Options {
+        Options {
+            load_map: matches.get_flag("load-map"),
+            image_format: image_format(matches),
+            builtin_traps: !matches.get_flag("no-builtin-traps"),
+            vector_exceptions: !matches.get_flag("stop-on-exception"),
+            end_of_input: match matches.get_one::<String>("on-eof").map(String::as_str) {
+                Some("halt") => EndOfInput::Halt,
+                Some("error") => EndOfInput::Error,
+                _ => EndOfInput::Null,
+            },
+            trace: matches.get_one::<PathBuf>("trace").map(|path| {
+                let format = match matches
+                    .get_one::<String>("trace-format")
+                    .map(String::as_str)
+                {
+                    Some("jsonl") => TraceFormat::JsonLines,
+                    Some("csv") => TraceFormat::Csv,
+                    _ => TraceFormat::from_path(path),
+                };
+                (path.to_owned(), format)
+            }),
+            load_state: matches.get_one::<PathBuf>("load-state").cloned(),
+            symbols: paths(matches, "symbols"),
+            lines: paths(matches, "lines"),
+        }
+    }
+}
+
+// errors can span several lines, so they are printed with Display rather than returned from main
+fn main() {
+    if let Err(error) = cli() {
+        eprintln!("Error: {}", error);
+        exit(1);
+    }
+}
+
+fn cli() -> Result<(), String> {
     let matches = command!()
         .about("Simulator for the LC-4 architecture.")
+        .args_conflicts_with_subcommands(true)
+        .arg(input_arg())
+        .arg(image_format_arg())
+        .arg(load_map_arg())
+        .arg(no_builtin_traps_arg())
+        .arg(stop_on_exception_arg())
+        .arg(on_eof_arg())
+        .arg(trace_arg())
+        .arg(trace_format_arg())
+        .arg(load_state_arg())
+        .arg(symbols_arg())
+        .arg(lines_arg())
         .arg(
-            clap::Arg::new("input")
-                .default_value("./examples/out.bin")
+            clap::Arg::new("save-state")
+                .long("save-state")
+                .value_name("FILE")
                 .value_parser(clap::value_parser!(PathBuf))
-                .help("assembly input file"),
+                .help("save a snapshot of the machine to FILE when the program stops, whether it halted, failed or hit --max-steps or --timeout"),
+        )
+        .arg(
+            clap::Arg::new("dump")
+                .long("dump")
+                .value_name("RANGE")
+                .help("print the memory from START to END, like x4000-x40ff or BUFFER-BUFFER+31, when the program stops"),
+        )
+        .arg(
+            clap::Arg::new("dump-format")
+                .long("dump-format")
+                .value_name("FORMAT")
+                .value_parser(["hex", "disasm", "image"])
+                .default_value("hex")
+                .requires("dump")
+                .requires_if("image", "dump-file")
+                .help("hex words with their characters, an annotated disassembly or a loadable image"),
+        )
+        .arg(
+            clap::Arg::new("dump-file")
+                .long("dump-file")
+                .value_name("FILE")
+                .value_parser(clap::value_parser!(PathBuf))
+                .requires("dump")
+                .help("write the dump to FILE instead of the program's output"),
+        )
+        .arg(
+            clap::Arg::new("batch")
+                .long("batch")
+                .action(clap::ArgAction::SetTrue)
+                .help("run without taking over the terminal: program input from stdin, output plainly to stdout and exit status 1 if the program stops with an error, the default when stdout is not a terminal"),
+        )
+        .arg(
+            clap::Arg::new("input-text")
+                .long("input")
+                .value_name("TEXT")
+                .conflicts_with("input-file")
+                .help("program input for batch mode instead of stdin, implies --batch"),
+        )
+        .arg(
+            clap::Arg::new("input-file")
+                .long("input-file")
+                .value_name("FILE")
+                .value_parser(clap::value_parser!(PathBuf))
+                .help("read program input for batch mode from a file instead of stdin, implies --batch"),
+        )
+        .arg(
+            clap::Arg::new("max-steps")
+                .long("max-steps")
+                .value_name("N")
+                .value_parser(clap::value_parser!(u64))
+                .help("stop with an error after executing N instructions"),
+        )
+        .arg(
+            clap::Arg::new("timeout")
+                .long("timeout")
+                .value_name("SECONDS")
+                .value_parser(clap::value_parser!(f64))
+                .help("stop with an error once the program has run for SECONDS, a program blocked in GETC or IN is only stopped after its next key"),
+        )
+        .arg(
+            clap::Arg::new("detect-loops")
+                .long("detect-loops")
+                .action(clap::ArgAction::SetTrue)
+                .help("stop with an error when the program is stuck, repeating the same instructions without changing registers, memory or devices"),
+        )
+        .subcommand(
+            Command::new("debug")
+                .about("Run a program in the interactive debugger")
+                .arg(input_arg())
+                .arg(image_format_arg())
+                .arg(load_map_arg())
+                .arg(no_builtin_traps_arg())
+                .arg(stop_on_exception_arg())
+                .arg(on_eof_arg())
+                .arg(trace_arg())
+                .arg(trace_format_arg())
+                .arg(load_state_arg())
+                .arg(symbols_arg())
+                .arg(lines_arg())
+                .arg(
+                    clap::Arg::new("tui")
+                        .long("tui")
+                        .action(clap::ArgAction::SetTrue)
+                        .help("use the full-screen interface instead of the command line"),
+                ),
+        )
+        .subcommand(
+            Command::new("disasm")
+                .about("Print an assembly listing of a binary image")
+                .arg(input_arg())
+                .arg(image_format_arg())
+                .arg(symbols_arg()),
+        )
+        .subcommand(
+            Command::new("asm")
+                .about("Assemble LC-4 source into a binary image and a symbol table")
+                .arg(
+                    clap::Arg::new("source")
+                        .required(true)
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .help("assembly source file"),
+                )
+                .arg(
+                    clap::Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .help("binary image to write [default: the source with a .bin extension]"),
+                ),
         )
         .get_matches();
-    files.push(
-        matches
-            .get_one::<PathBuf>("input")
-            .expect("could not parse input file path")
-            .to_owned(),
-    );
 
-    File::create("debug.log").unwrap();
+    match matches.subcommand() {
+        Some(("debug", matches)) => debug(
+            &input_files(matches),
+            &Options::from(matches),
+            matches.get_flag("tui"),
+        ),
+        Some(("disasm", matches)) => disasm(
+            &input_files(matches),
+            image_format(matches),
+            &paths(matches, "symbols"),
+        ),
+        Some(("asm", matches)) => {
+            let source = matches
+                .get_one::<PathBuf>("source")
+                .expect("could not parse source file path");
+            let output = match matches.get_one::<PathBuf>("output") {
+                Some(output) => output.to_owned(),
+                None => source.with_extension("bin"),
+            };
+            asm(source, &output)
+        }
+        _ => {
+            let files = input_files(&matches);
+            let options = Options::from(&matches);
+            let until = Until {
+                limits: limits(&matches)?,
+                save_state: matches.get_one::<PathBuf>("save-state").cloned(),
+                dump: matches.get_one::<String>("dump").map(|range| Dump {
+                    range: range.to_owned(),
+                    format: matches
+                        .get_one::<String>("dump-format")
+                        .and_then(|name| DumpFormat::from_name(name))
+                        .unwrap_or(DumpFormat::Hex),
+                    file: matches.get_one::<PathBuf>("dump-file").cloned(),
+                }),
+            };
+            match batch_input(&matches) {
+                Some(input) => batch(&files, &options, &until, input),
+                None => run(&files, &options, &until),
+            }
+        }
+    }
+}
+
+// how far a plain or batch run goes and what is kept of the machine when it stops
+struct Until {
+    limits: Limits,
+    save_state: Option<PathBuf>,
+    dump: Option<Dump>,
+}
 
-    let out = read_input_files(&files);
+// memory to write out at the end of a run, to the program's output unless there is a file,
+// the range can use labels so it is only parsed once the symbols are loaded
+struct Dump {
+    range: String,
+    format: DumpFormat,
+    file: Option<PathBuf>,
+}
 
-    // log the binary that image that was read
-    for (i, val) in out.iter().enumerate().filter(|(_i, x)| **x != 0) {
-        log(&format!("{}: {:016b}\n", i, val));
+impl Until {
+    fn run<C: Console>(&self, lc4: &mut Machine<C>) -> Result<(), String> {
+        let range = match &self.dump {
+            Some(request) => Some(
+                parse_symbolic_range(&request.range, lc4.symbols())
+                    .map_err(|e| format!("--dump: {}", e))?,
+            ),
+            None => None,
+        };
+        lc4.set_limits(self.limits);
+        let result = lc4.run_machine();
+        if let Some(path) = &self.save_state {
+            lc4.snapshot().write(path).map_err(|e| e.to_string())?;
+        }
+        if let (Some(request), Some(range)) = (&self.dump, range) {
+            let words: Vec<u16> = range.clone().map(|addr| lc4.read_memory(addr)).collect();
+            let out = dump(&words, *range.start(), request.format, lc4.symbols());
+            match &request.file {
+                Some(path) => {
+                    fs::write(path, out).map_err(|e| format!("{}: {}", path.display(), e))?
+                }
+                None => {
+                    let console = lc4.console_mut();
+                    console
+                        .write_bytes(&out)
+                        .and_then(|_| console.flush())
+                        .map_err(|e| e.to_string())?;
+                }
+            }
+        }
+        result.map_err(|e| e.describe(lc4.symbols()))
     }
-    log(&"\n");
+}
 
-    // Switch to raw mode and use an alternate screen
-    let mut screen = stdout().into_raw_mode().unwrap();
+fn limits(matches: &ArgMatches) -> Result<Limits, String> {
+    let timeout = match matches.get_one::<f64>("timeout") {
+        Some(seconds) => Some(
+            Duration::try_from_secs_f64(*seconds)
+                .map_err(|_| format!("invalid timeout {}", seconds))?,
+        ),
+        None => None,
+    };
+    Ok(Limits {
+        max_steps: matches.get_one::<u64>("max-steps").copied(),
+        timeout,
+        detect_loops: matches.get_flag("detect-loops"),
+    })
+}
 
-    // Create a handle for standard input
-    let input = stdin().lock();
+// where a batch run reads program input from
+enum BatchInput {
+    Stdin,
+    Text(String),
+    File(PathBuf),
+}
 
-    write!(screen, "test").unwrap();
-    screen.flush().unwrap();
+// None to run in the terminal
+fn batch_input(matches: &ArgMatches) -> Option<BatchInput> {
+    if let Some(text) = matches.get_one::<String>("input-text") {
+        Some(BatchInput::Text(text.to_owned()))
+    } else if let Some(path) = matches.get_one::<PathBuf>("input-file") {
+        Some(BatchInput::File(path.to_owned()))
+    } else if matches.get_flag("batch") || !termion::is_tty(&stdout()) {
+        Some(BatchInput::Stdin)
+    } else {
+        None
+    }
+}
 
-    let input = input.bytes();
+// run the program in the raw mode terminal until it halts
+fn run(files: &[PathBuf], options: &Options, until: &Until) -> Result<(), String> {
+    let (out, regions) = options.images(files)?;
+    let info = options.debug_info(files, &regions)?;
 
-    write!(
-        screen,
+    // Switch the terminal to raw mode
+    let mut console = TerminalConsole::new().map_err(|e| e.to_string())?;
+
+    let header = format!(
         "{}{}{}LC-4 simulation.{}{}",
         termion::clear::All,
         termion::cursor::Goto(1, 1),
         termion::style::Bold,
         termion::style::Reset,
         termion::cursor::Goto(1, 2)
-    )
-    .unwrap();
-    screen.flush().unwrap();
+    );
+    console.write_bytes(header.as_bytes()).unwrap();
+    if options.load_map {
+        console.write_bytes(load_map(&regions).as_bytes()).unwrap();
+    }
+    console.flush().unwrap();
 
-    let mut lc4 = Machine::new(Some(out), input, screen);
+    let mut lc4 = options.machine(out, info, console)?;
+    until.run(&mut lc4)?;
+    let console = lc4.console_mut();
+    console.write_bytes(b"\n\n Halted execution").unwrap();
+    console.flush().unwrap();
 
-    let mut screen = stdout().into_raw_mode().unwrap();
+    sleep(Duration::from_secs(2));
 
-    lc4.run_machine()?;
-    write!(screen, "{}{}{} Halted execution", '\r', '\n', '\n').unwrap();
-    screen.flush().unwrap();
+    Ok(())
+}
 
-    sleep(Duration::from_secs(2));
+// run the program without the terminal interface, for pipelines and scripts,
+// the load map goes to stderr so stdout only has the program's output
+fn batch(
+    files: &[PathBuf],
+    options: &Options,
+    until: &Until,
+    input: BatchInput,
+) -> Result<(), String> {
+    let (out, regions) = options.images(files)?;
+    let info = options.debug_info(files, &regions)?;
+    if options.load_map {
+        eprint!("{}", load_map(&regions));
+    }
+    let input: Box<dyn Read> = match input {
+        BatchInput::Stdin => Box::new(stdin()),
+        BatchInput::Text(text) => Box::new(Cursor::new(text.into_bytes())),
+        BatchInput::File(path) => Box::new(BufReader::new(
+            File::open(&path).map_err(|e| format!("{}: {}", path.display(), e))?,
+        )),
+    };
+    let mut lc4 = options.machine(out, info, StreamConsole::new(input, stdout()))?;
+    until.run(&mut lc4)
+}
 
+// run the program under the debugger, program I/O shares the normal terminal with the REPL
+// unless the full-screen interface gives it a pane of its own
+fn debug(files: &[PathBuf], options: &Options, tui: bool) -> Result<(), String> {
+    let (out, regions) = options.images(files)?;
+    let info = options.debug_info(files, &regions)?;
+    let map = if options.load_map {
+        load_map(&regions)
+    } else {
+        String::new()
+    };
+    if tui {
+        let console = TuiConsole::new().map_err(|e| e.to_string())?;
+        let mut debugger = Debugger::new(debug_machine(out, info, console, options)?);
+        return run_tui(&mut debugger, &map);
+    }
+    print!("{}", map);
+    // commands and program input share stdin, a program reading a key gets what follows the command
+    let input = SharedReader::new(stdin().lock());
+    let console = StreamConsole::new(input.clone(), stdout());
+    let mut debugger = Debugger::new(debug_machine(out, info, console, options)?);
+    run_repl(&mut debugger, input, stdout())
+}
+
+fn debug_machine<C: Console>(
+    out: [u16; 65536],
+    info: DebugInfo,
+    console: C,
+    options: &Options,
+) -> Result<Machine<C>, String> {
+    let mut lc4 = options.machine(out, info, console)?;
+    lc4.enable_history(HistoryConfig::default());
+    Ok(lc4)
+}
+
+// print the listing of every image, in the order they are loaded, a file with several blocks gets a
+// listing for each, addresses are named after the symbols of all the images
+fn disasm(files: &[PathBuf], format: Option<ImageFormat>, given: &[PathBuf]) -> Result<(), String> {
+    let mut images = Vec::new();
+    for path in files {
+        images.extend(read_images(path, format).map_err(|e| e.to_string())?);
+    }
+    let regions: Vec<Region> = images
+        .iter()
+        .map(|image| Region {
+            path: image.path.to_owned(),
+            start: image.origin,
+            len: image.words.len(),
+        })
+        .collect();
+    let symbols = load_symbols(given, files, &regions)?;
+    for image in images {
+        println!(
+            "; {} (origin 0x{:04x}, {} words)",
+            image.path.display(),
+            image.origin,
+            image.words.len()
+        );
+        print!("{}", listing(&image.words, image.origin, &symbols));
+    }
     Ok(())
 }
+
+// assemble source into an image, with the symbol table next to it in a .sym file and the line map in
+// a .lines file
+fn asm(source: &PathBuf, output: &PathBuf) -> Result<(), String> {
+    let text = fs::read_to_string(source).map_err(|e| format!("{}: {}", source.display(), e))?;
+    let program = assemble(&text).map_err(|e| format!("{}: {}", source.display(), e))?;
+    fs::write(output, program.to_image_bytes())
+        .map_err(|e| format!("{}: {}", output.display(), e))?;
+    let symbols = output.with_extension("sym");
+    fs::write(&symbols, program.symbol_table())
+        .map_err(|e| format!("{}: {}", symbols.display(), e))?;
+    let lines = output.with_extension("lines");
+    fs::write(&lines, program.line_map(&source_for(source, output)))
+        .map_err(|e| format!("{}: {}", lines.display(), e))?;
+    println!(
+        "wrote {} words at 0x{:04x} to {}, {} symbols to {} and the line map to {}",
+        program.words.len(),
+        program.origin,
+        output.display(),
+        program.symbols.len(),
+        symbols.display(),
+        lines.display()
+    );
+    Ok(())
+}
+
+// the source as the line map next to output refers to it, line maps find relative paths from their
+// own directory
+fn source_for(source: &Path, output: &Path) -> String {
+    let dir = |path: &Path| path.parent().map(Path::to_owned).unwrap_or_default();
+    let path = match dir(source) == dir(output) {
+        true => PathBuf::from(source.file_name().unwrap_or_default()),
+        false => fs::canonicalize(source).unwrap_or_else(|_| source.to_owned()),
+    };
+    path.display().to_string()
+}
@@ -0,0 +1,23 @@
+// parse an address or number written as 0x3000, x3000 or 12288
+pub fn parse_address(text: &str) -> Result<u16, String> {
+    let hex = text
+        .strip_prefix("0x")
+        .or_else(|| text.strip_prefix("x"))
+        .or_else(|| text.strip_prefix("X"));
+    match hex {
+        Some(digits) => u16::from_str_radix(digits, 16),
+        None => text.parse(),
+    }
+    .map_err(|_| format!("invalid address: {}", text))
+}
+
+// a name made of letters, digits and underscores that doesn't start with a digit
+pub fn is_label(text: &str) -> bool {
+    let mut chars = text.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+            chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }
+        _ => false,
+    }
+}
@@ -1,4 +1,4 @@
-#[derive(PartialEq)]
+#[derive(PartialEq, Default)]
 pub struct LFSR {
     // holds the state of the 16 bit lfsr
     pub state: u16,
@@ -25,7 +25,7 @@ impl LFSR {
     }
 }
 
-#[derive(PartialEq)]
+#[derive(PartialEq, Default)]
 pub struct ASG {
     // three LFSRs that represent a physical ASG
     pub clock: LFSR,
@@ -1,56 +1,567 @@
-use std::fs::File;
-use std::io::BufReader;
-use std::io::Read;
-use std::path::PathBuf;
-
-fn read_all_u16_values_from_file(path: &PathBuf) -> std::io::Result<Vec<u16>> {
-    // Open the file in read-only mode
-    let file = File::open(path)?;
-    let mut reader = BufReader::new(file);
-
-    // Read all bytes from the file
-    let mut buffer = Vec::new();
-    reader.read_to_end(&mut buffer)?;
-
-    // Check that the number of bytes is even (u16 is 2 bytes)
-    if buffer.len() % 2 != 0 {
-        return Err(std::io::Error::new(
-            std::io::ErrorKind::InvalidData,
-            "File size is not a multiple of 2 (u16 size)",
+use crate::error::SimError;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// a program image as stored on disk, the first word of the file is the address it is loaded at
+pub struct Image {
+    pub path: PathBuf,
+    pub origin: u16,
+    pub words: Vec<u16>,
+}
+
+// the file formats a program can be loaded from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImageFormat {
+    // big endian words, the first one is the origin, LC-3 style .obj files are laid out the same way
+    Binary,
+    // text, one hex word per line starting with the origin
+    Hex,
+    // text, one word per line as 16 ASCII 0s and 1s starting with the origin
+    Bits,
+    // Intel HEX records, byte addresses are twice the word address and words are big endian
+    IntelHex,
+}
+
+impl ImageFormat {
+    pub fn from_name(name: &str) -> Option<ImageFormat> {
+        match name {
+            "binary" | "obj" => Some(ImageFormat::Binary),
+            "hex" => Some(ImageFormat::Hex),
+            "bits" => Some(ImageFormat::Bits),
+            "ihex" => Some(ImageFormat::IntelHex),
+            _ => None,
+        }
+    }
+
+    // text files are told apart by their first word or record, anything else is binary
+    pub fn detect(bytes: &[u8]) -> ImageFormat {
+        let text = match std::str::from_utf8(bytes) {
+            Ok(text) if text.chars().all(|c| !c.is_control() || c.is_whitespace()) => text,
+            _ => return ImageFormat::Binary,
+        };
+        match text
+            .lines()
+            .map(strip_comment)
+            .find(|line| !line.is_empty())
+        {
+            Some(line) if line.starts_with(':') => ImageFormat::IntelHex,
+            Some(line) if is_bits(line.split_whitespace().next().unwrap_or("")) => {
+                ImageFormat::Bits
+            }
+            Some(_) => ImageFormat::Hex,
+            None => ImageFormat::Binary,
+        }
+    }
+}
+
+// read an image that is loaded as one block, see read_images for formats that can hold several
+pub fn read_image(path: &PathBuf) -> Result<Image, SimError> {
+    let mut images = read_images(path, None)?;
+    match images.len() {
+        1 => Ok(images.remove(0)),
+        0 => Err(loader_error(path, "no words to load")),
+        n => Err(loader_error(
+            path,
+            format!("{} separate blocks, expected one", n),
+        )),
+    }
+}
+
+// read the blocks of a file in the given format, or the one its contents look like
+pub fn read_images(path: &PathBuf, format: Option<ImageFormat>) -> Result<Vec<Image>, SimError> {
+    let bytes = fs::read(path).map_err(|e| loader_error(path, e))?;
+    let format = format.unwrap_or_else(|| ImageFormat::detect(&bytes));
+    let blocks = match format {
+        ImageFormat::Binary => parse_binary(&bytes).and_then(with_origin),
+        ImageFormat::IntelHex => text(&bytes).and_then(parse_intel_hex),
+        ImageFormat::Hex => text(&bytes)
+            .and_then(|text| parse_words(text, "hex word", parse_hex_word))
+            .and_then(with_origin),
+        ImageFormat::Bits => text(&bytes)
+            .and_then(|text| parse_words(text, "16 bit binary word", parse_bits))
+            .and_then(with_origin),
+    }
+    .map_err(|reason| loader_error(path, reason))?;
+    Ok(blocks
+        .into_iter()
+        .map(|(origin, words)| Image {
+            path: path.to_owned(),
+            origin,
+            words,
+        })
+        .collect())
+}
+
+fn loader_error<E: ToString>(path: &Path, reason: E) -> SimError {
+    SimError::Loader {
+        path: path.to_owned(),
+        reason: reason.to_string(),
+    }
+}
+
+fn text(bytes: &[u8]) -> Result<&str, String> {
+    std::str::from_utf8(bytes).map_err(|e| {
+        format!(
+            "not a text file, invalid UTF-8 at offset {}",
+            e.valid_up_to()
+        )
+    })
+}
+
+// the first word is where the rest are loaded
+fn with_origin(values: Vec<u16>) -> Result<Vec<(u16, Vec<u16>)>, String> {
+    match values.split_first() {
+        Some((origin, words)) => Ok(vec![(*origin, words.to_vec())]),
+        None => Err("File is empty, expected an origin".to_owned()),
+    }
+}
+
+fn parse_binary(bytes: &[u8]) -> Result<Vec<u16>, String> {
+    if bytes.len() % 2 != 0 {
+        return Err(format!(
+            "odd number of bytes ({}), the word at offset {} is incomplete",
+            bytes.len(),
+            bytes.len() - 1
         ));
     }
+    Ok(bytes
+        .chunks(2)
+        .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+        .collect())
+}
 
-    // Convert the bytes into u16 values
-    let mut result = Vec::new();
-    let mut index = 0;
-    while index < buffer.len() {
-        let value_bytes: [u8; 2] = [buffer[index], buffer[index + 1]];
-        let value = u16::from_be_bytes(value_bytes);
-        result.push(value);
-        index += 2;
+// comments run from ; or # to the end of the line
+fn strip_comment(line: &str) -> &str {
+    match line.find([';', '#']) {
+        Some(at) => line[..at].trim(),
+        None => line.trim(),
     }
+}
 
-    Ok(result)
+// every whitespace separated word of a text image, in order
+fn parse_words<F: Fn(&str) -> Option<u16>>(
+    text: &str,
+    expected: &str,
+    parse: F,
+) -> Result<Vec<u16>, String> {
+    let mut values = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        for word in strip_comment(line).split_whitespace() {
+            let value = parse(word).ok_or_else(|| {
+                format!(
+                    "line {}: expected a {}, found '{}'",
+                    number + 1,
+                    expected,
+                    word
+                )
+            })?;
+            values.push(value);
+        }
+    }
+    Ok(values)
 }
 
-// panic if PC writes into priveleged memory
-fn check_pc(pc: u16) {
-    if !(0x3000..=0xFDFF).contains(&pc) {
-        panic!("bad binary images, PC: {}", pc)
+// 3000, 0x3000 or x3000
+fn parse_hex_word(word: &str) -> Option<u16> {
+    let digits = word
+        .strip_prefix("0x")
+        .or_else(|| word.strip_prefix('x'))
+        .unwrap_or(word);
+    match digits.len() {
+        1..=4 if digits.chars().all(|c| c.is_ascii_hexdigit()) => {
+            u16::from_str_radix(digits, 16).ok()
+        }
+        _ => None,
     }
 }
 
-pub fn read_input_files(paths: &Vec<PathBuf>) -> [u16; 65536] {
+fn is_bits(word: &str) -> bool {
+    word.len() == 16 && word.chars().all(|c| c == '0' || c == '1')
+}
+
+fn parse_bits(word: &str) -> Option<u16> {
+    match is_bits(word) {
+        true => u16::from_str_radix(word, 2).ok(),
+        false => None,
+    }
+}
+
+// data records fill in bytes, extended segment and linear address records move the base address,
+// contiguous words become one block each
+fn parse_intel_hex(text: &str) -> Result<Vec<(u16, Vec<u16>)>, String> {
+    let mut bytes: BTreeMap<u32, u8> = BTreeMap::new();
+    let mut base = 0u32;
+    let mut ended = false;
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let error = |reason: String| format!("line {}: {}", number + 1, reason);
+        if ended {
+            return Err(error("record after the end of file record".to_owned()));
+        }
+        let record = line
+            .strip_prefix(':')
+            .ok_or_else(|| error("expected a record starting with ':'".to_owned()))?;
+        if record.len() % 2 != 0 || !record.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(error("a record is an even number of hex digits".to_owned()));
+        }
+        let record: Vec<u8> = (0..record.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&record[i..i + 2], 16).unwrap_or(0))
+            .collect();
+        if record.len() < 5 || record.len() != record[0] as usize + 5 {
+            return Err(error(
+                "the record length doesn't match its byte count".to_owned(),
+            ));
+        }
+        if record.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) != 0 {
+            return Err(error("checksum mismatch".to_owned()));
+        }
+        let offset = u16::from_be_bytes([record[1], record[2]]) as u32;
+        let data = &record[4..record.len() - 1];
+        match record[3] {
+            0x00 => {
+                for (i, byte) in data.iter().enumerate() {
+                    let addr = base + ((offset + i as u32) & 0xFFFF);
+                    if bytes.insert(addr, *byte).is_some() {
+                        return Err(error(format!("byte address 0x{:x} written twice", addr)));
+                    }
+                }
+            }
+            0x01 => ended = true,
+            0x02 if data.len() == 2 => base = (u16::from_be_bytes([data[0], data[1]]) as u32) << 4,
+            0x04 if data.len() == 2 => base = (u16::from_be_bytes([data[0], data[1]]) as u32) << 16,
+            // start addresses mean nothing to the simulator
+            0x03 | 0x05 => (),
+            kind => return Err(error(format!("unsupported record type {:02x}", kind))),
+        }
+    }
+    if !ended {
+        return Err("missing the end of file record".to_owned());
+    }
+    let mut blocks: Vec<(u16, Vec<u16>)> = Vec::new();
+    let mut words = bytes.iter();
+    while let Some((&addr, &high)) = words.next() {
+        let word_addr = addr / 2;
+        let low = match words.next() {
+            Some((&next, &low)) if addr % 2 == 0 && next == addr + 1 => low,
+            _ => {
+                return Err(format!(
+                    "byte address 0x{:x} holds half of word 0x{:x}, both bytes are needed",
+                    addr, word_addr
+                ))
+            }
+        };
+        if word_addr > 0xFFFF {
+            return Err(format!(
+                "byte address 0x{:x} is past the end of memory",
+                addr
+            ));
+        }
+        let word = u16::from_be_bytes([high, low]);
+        match blocks.last_mut() {
+            Some((origin, block)) if *origin as u32 + block.len() as u32 == word_addr => {
+                block.push(word)
+            }
+            _ => blocks.push((word_addr as u16, vec![word])),
+        }
+    }
+    Ok(blocks)
+}
+
+// the addresses an image occupies once loaded
+#[derive(Debug, Clone, PartialEq)]
+pub struct Region {
+    pub path: PathBuf,
+    pub start: u16,
+    pub len: usize,
+}
+
+impl Region {
+    // last address written, None for an image with no words
+    pub fn end(&self) -> Option<u16> {
+        self.len
+            .checked_sub(1)
+            .map(|last| self.start.wrapping_add(last as u16))
+    }
+
+    fn overlaps(&self, other: &Region) -> bool {
+        match (self.end(), other.end()) {
+            (Some(end), Some(other_end)) => self.start <= other_end && other.start <= end,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let range = match self.end() {
+            Some(end) => format!("0x{:04x}-0x{:04x}", self.start, end),
+            None => format!("0x{:04x}", self.start),
+        };
+        write!(
+            f,
+            "{:<13}  {:>5} words  {}",
+            range,
+            self.len,
+            self.path.display()
+        )
+    }
+}
+
+// the regions of every image in the order they were loaded
+pub fn load_map(regions: &[Region]) -> String {
+    regions
+        .iter()
+        .map(|region| format!("{}\n", region))
+        .collect()
+}
+
+// load every image into one memory, images may not overlap or run past the end of memory,
+// the format of each file is detected from its contents unless one is given
+pub fn load_images(
+    paths: &[PathBuf],
+    format: Option<ImageFormat>,
+) -> Result<([u16; 65536], Vec<Region>), SimError> {
     let mut out = [0u16; 65536];
-    for path in paths {
-        let values: Vec<u16> = read_all_u16_values_from_file(path).expect("error reading file");
-        let mut pc = values[0];
-        check_pc(pc);
-        for value in values[1..].to_vec() {
-            out[pc as usize] = value;
-            pc += 1;
-            check_pc(pc);
+    let mut regions: Vec<Region> = Vec::new();
+    for image in paths
+        .iter()
+        .map(|path| read_images(path, format))
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .flatten()
+    {
+        let path = &image.path;
+        if image.origin as usize + image.words.len() > out.len() {
+            return Err(loader_error(
+                path,
+                format!(
+                    "{} words at 0x{:04x} run past the end of memory",
+                    image.words.len(),
+                    image.origin
+                ),
+            ));
+        }
+        let region = Region {
+            path: path.to_owned(),
+            start: image.origin,
+            len: image.words.len(),
+        };
+        if let Some(other) = regions.iter().find(|other| other.overlaps(&region)) {
+            return Err(loader_error(
+                path,
+                format!(
+                    "0x{:04x}-0x{:04x} overlaps {} at 0x{:04x}-0x{:04x}",
+                    region.start,
+                    region.end().unwrap_or(region.start),
+                    other.path.display(),
+                    other.start,
+                    other.end().unwrap_or(other.start)
+                ),
+            ));
+        }
+        let start = image.origin as usize;
+        out[start..start + image.words.len()].copy_from_slice(&image.words);
+        regions.push(region);
+    }
+    Ok((out, regions))
+}
+
+pub fn read_input_files(paths: &[PathBuf]) -> Result<[u16; 65536], SimError> {
+    load_images(paths, None).map(|(memory, _)| memory)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a file under a directory of the system's temp dir that belongs to this test run
+    fn write_file(name: &str, bytes: &[u8]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("lc4-reader-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        fs::write(&path, bytes).unwrap();
+        path
+    }
+
+    // the binary image format, the origin followed by the words
+    fn binary(origin: u16, words: &[u16]) -> Vec<u8> {
+        std::iter::once(origin)
+            .chain(words.iter().copied())
+            .flat_map(u16::to_be_bytes)
+            .collect()
+    }
+
+    #[test]
+    fn overlapping_images_are_refused() {
+        let first = write_file("overlap-first.bin", &binary(0x3000, &[1, 2, 3, 4]));
+        let second = write_file("overlap-second.bin", &binary(0x3003, &[5, 6]));
+        let error = match load_images(&[first.clone(), second.clone()], None) {
+            Err(error) => error.to_string(),
+            Ok(_) => panic!("overlapping images were loaded"),
+        };
+        assert!(error.contains(&second.display().to_string()), "{}", error);
+        assert!(
+            error.contains(&format!(
+                "0x3003-0x3004 overlaps {} at 0x3000-0x3003",
+                first.display()
+            )),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn images_past_the_end_of_memory_are_refused() {
+        let path = write_file("past-the-end.bin", &binary(0xFFFF, &[1, 2]));
+        let error = load_images(&[path], None).err().unwrap().to_string();
+        assert!(
+            error.contains("2 words at 0xffff run past the end of memory"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn the_load_map_lists_every_image_in_load_order() {
+        let program = write_file("map-program.bin", &binary(0x3000, &[1, 2, 3]));
+        let data = write_file("map-data.bin", &binary(0x4000, &[4]));
+        let (memory, regions) = load_images(&[program.clone(), data.clone()], None).unwrap();
+        assert_eq!(memory[0x3000..0x3003], [1, 2, 3]);
+        assert_eq!(memory[0x4000], 4);
+        assert_eq!(
+            load_map(&regions),
+            format!(
+                "0x3000-0x3002      3 words  {}\n0x4000-0x4000      1 words  {}\n",
+                program.display(),
+                data.display()
+            )
+        );
+    }
+
+    fn words(path: &PathBuf, format: Option<ImageFormat>) -> Vec<(u16, Vec<u16>)> {
+        read_images(path, format)
+            .unwrap()
+            .into_iter()
+            .map(|image| (image.origin, image.words))
+            .collect()
+    }
+
+    fn reason(path: &PathBuf, format: Option<ImageFormat>) -> String {
+        match read_images(path, format) {
+            Err(SimError::Loader { reason, .. }) => reason,
+            other => panic!("expected a loader error, got {:?}", other.map(|_| ())),
         }
     }
-    out
+
+    #[test]
+    fn every_format_reads_the_same_image() {
+        let image = vec![(0x3000, vec![0x1234, 0xABCD])];
+        let files = [
+            ("read.obj", binary(0x3000, &[0x1234, 0xABCD])),
+            ("read.hex", b"x3000 ; origin\n0x1234\nABCD\n".to_vec()),
+            (
+                "read.bits",
+                b"0011000000000000\n0001001000110100 # add\n1010101111001101\n".to_vec(),
+            ),
+            ("read.ihex", b":046000001234ABCDDE\n:00000001FF\n".to_vec()),
+        ];
+        for (name, bytes) in files {
+            let path = write_file(name, &bytes);
+            assert_eq!(words(&path, None), image, "{}", name);
+        }
+    }
+
+    #[test]
+    fn intel_hex_records_that_are_not_contiguous_are_separate_blocks() {
+        let path = write_file(
+            "blocks.ihex",
+            b":02600000123458\n:02800000ABCD06\n:00000001FF\n",
+        );
+        assert_eq!(
+            words(&path, None),
+            vec![(0x3000, vec![0x1234]), (0x4000, vec![0xABCD])]
+        );
+        assert!(read_image(&path).is_err());
+    }
+
+    #[test]
+    fn intel_hex_checksums_are_checked() {
+        let path = write_file("checksum.ihex", b":046000001234ABCDDF\n:00000001FF\n");
+        assert_eq!(reason(&path, None), "line 1: checksum mismatch");
+    }
+
+    #[test]
+    fn intel_hex_words_need_both_bytes() {
+        let path = write_file("half.ihex", b":01600000128D\n:00000001FF\n");
+        assert_eq!(
+            reason(&path, None),
+            "byte address 0x6000 holds half of word 0x3000, both bytes are needed"
+        );
+    }
+
+    #[test]
+    fn binary_images_need_whole_words() {
+        let path = write_file("odd.obj", &[0x30, 0x00, 0x12]);
+        assert_eq!(
+            reason(&path, None),
+            "odd number of bytes (3), the word at offset 2 is incomplete"
+        );
+    }
+
+    #[test]
+    fn bad_words_are_reported_with_their_line() {
+        let hex = write_file("bad.hex", b"x3000\n1234\n; comment\nx12G4\n");
+        assert_eq!(
+            reason(&hex, None),
+            "line 4: expected a hex word, found 'x12G4'"
+        );
+        let bits = write_file("bad.bits", b"0011000000000000\n000100100011010\n");
+        assert_eq!(
+            reason(&bits, Some(ImageFormat::Bits)),
+            "line 2: expected a 16 bit binary word, found '000100100011010'"
+        );
+    }
+
+    #[test]
+    fn empty_files_have_no_origin() {
+        let path = write_file("empty.obj", b"");
+        assert_eq!(reason(&path, None), "File is empty, expected an origin");
+    }
+
+    #[test]
+    fn detection_goes_by_the_first_word() {
+        assert_eq!(ImageFormat::detect(b""), ImageFormat::Binary);
+        assert_eq!(ImageFormat::detect(&[0x30, 0x00]), ImageFormat::Binary);
+        assert_eq!(
+            ImageFormat::detect(b"; header\n:00000001FF\n"),
+            ImageFormat::IntelHex
+        );
+        assert_eq!(
+            ImageFormat::detect(b"0011000000000000\n"),
+            ImageFormat::Bits
+        );
+        // binary digits that aren't a whole word are hex
+        assert_eq!(ImageFormat::detect(b"1010\n"), ImageFormat::Hex);
+        assert_eq!(
+            ImageFormat::detect(b"0011000000000000 1\n"),
+            ImageFormat::Bits
+        );
+    }
+
+    #[test]
+    fn binary_images_that_look_like_text_need_their_format_named() {
+        // x3030 x3030 in binary is the text "0000", a hex origin with no words
+        let path = write_file("ambiguous.obj", &binary(0x3030, &[0x3030]));
+        assert_eq!(words(&path, None), vec![(0x0000, vec![])]);
+        assert_eq!(
+            words(&path, Some(ImageFormat::Binary)),
+            vec![(0x3030, vec![0x3030])]
+        );
+    }
 }
@@ -0,0 +1,299 @@
+use crate::error::SimError;
+use crate::history::CpuState;
+use crate::interrupt::InterruptController;
+use crate::machine::PSR;
+use std::fs;
+use std::path::Path;
+use ux::u3;
+
+// every snapshot file starts with the magic and the version of the format it is written in
+pub const SNAPSHOT_MAGIC: &[u8; 4] = b"LC4S";
+pub const SNAPSHOT_VERSION: u16 = 1;
+
+// everything needed to resume a machine where it was, see Machine::snapshot
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    pub cpu: CpuState,
+    // number of instructions executed so far
+    pub steps: u64,
+    // console input the program will read before any new input, with the step that reads it
+    pub input: Vec<(u64, Option<u8>)>,
+    pub memory: Box<[u16; 65536]>,
+}
+
+impl Snapshot {
+    // version 1, all numbers big endian like program images:
+    // magic, version, PC, R0-R7, USP, SSP, PSR word, the clock, first and second LFSR states,
+    // halted byte, steps (u64), pending interrupts (u16 count of priority and vector bytes),
+    // devices (u16 count of u16 length prefixed register lists), input (u32 count of u64 step,
+    // present byte and key byte) and the 65536 words of memory
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let cpu = &self.cpu;
+        let mut out = SNAPSHOT_MAGIC.to_vec();
+        let mut words = vec![SNAPSHOT_VERSION, cpu.pc];
+        words.extend(cpu.register.iter().map(|&reg| reg as u16));
+        words.extend([cpu.usp, cpu.ssp, cpu.psr.to_word()]);
+        words.extend(cpu.asg);
+        for word in words {
+            out.extend(word.to_be_bytes());
+        }
+        out.push(!cpu.halt_flag as u8);
+        out.extend(self.steps.to_be_bytes());
+        let pending = cpu.interrupts.pending();
+        out.extend((pending.len() as u16).to_be_bytes());
+        for request in pending {
+            out.extend([u8::from(request.priority), request.vector]);
+        }
+        out.extend((cpu.devices.len() as u16).to_be_bytes());
+        for device in &cpu.devices {
+            out.extend((device.len() as u16).to_be_bytes());
+            for word in device {
+                out.extend(word.to_be_bytes());
+            }
+        }
+        out.extend((self.input.len() as u32).to_be_bytes());
+        for (step, key) in &self.input {
+            out.extend(step.to_be_bytes());
+            out.extend([key.is_some() as u8, key.unwrap_or(0)]);
+        }
+        for word in self.memory.iter() {
+            out.extend(word.to_be_bytes());
+        }
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Snapshot, String> {
+        let mut reader = Reader { bytes, at: 0 };
+        if reader.take(4)? != SNAPSHOT_MAGIC {
+            return Err("not a snapshot, the file doesn't start with LC4S".to_owned());
+        }
+        let version = reader.u16()?;
+        if version != SNAPSHOT_VERSION {
+            return Err(format!(
+                "snapshot version {} is not supported, expected version {}",
+                version, SNAPSHOT_VERSION
+            ));
+        }
+        let pc = reader.u16()?;
+        let mut register = [0i16; 8];
+        for reg in register.iter_mut() {
+            *reg = reader.u16()? as i16;
+        }
+        let usp = reader.u16()?;
+        let ssp = reader.u16()?;
+        let psr = reader.u16()?;
+        let asg = [reader.u16()?, reader.u16()?, reader.u16()?];
+        let halt_flag = reader.u8()? == 0;
+        let steps = reader.u64()?;
+        let mut interrupts = InterruptController::new();
+        for _ in 0..reader.u16()? {
+            let priority = reader.u8()?;
+            let vector = reader.u8()?;
+            if priority > 7 {
+                return Err(format!("invalid interrupt priority {}", priority));
+            }
+            interrupts.raise(u3::new(priority), vector);
+        }
+        let mut devices = Vec::new();
+        for _ in 0..reader.u16()? {
+            let len = reader.u16()?;
+            devices.push((0..len).map(|_| reader.u16()).collect::<Result<_, _>>()?);
+        }
+        let mut input = Vec::new();
+        for _ in 0..reader.u32()? {
+            let step = reader.u64()?;
+            let present = reader.u8()?;
+            let key = reader.u8()?;
+            input.push((step, if present != 0 { Some(key) } else { None }));
+        }
+        let mut memory = Box::new([0u16; 65536]);
+        for word in memory.iter_mut() {
+            *word = reader.u16()?;
+        }
+        if reader.at != bytes.len() {
+            return Err(format!(
+                "{} bytes left over after the snapshot",
+                bytes.len() - reader.at
+            ));
+        }
+        Ok(Snapshot {
+            cpu: CpuState {
+                pc,
+                register,
+                psr: PSR::from_word(psr),
+                usp,
+                ssp,
+                asg,
+                halt_flag,
+                interrupts,
+                devices,
+            },
+            steps,
+            input,
+            memory,
+        })
+    }
+
+    pub fn read(path: &Path) -> Result<Snapshot, SimError> {
+        let loader_error = |reason: String| SimError::Loader {
+            path: path.to_owned(),
+            reason,
+        };
+        let bytes = fs::read(path).map_err(|e| loader_error(e.to_string()))?;
+        Snapshot::from_bytes(&bytes).map_err(loader_error)
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), SimError> {
+        fs::write(path, self.to_bytes()).map_err(|e| SimError::Io {
+            fault: None,
+            reason: format!("couldn't write {}: {}", path.display(), e),
+        })
+    }
+}
+
+// reads big endian numbers from the front of a snapshot
+struct Reader<'a> {
+    bytes: &'a [u8],
+    at: usize,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, len: usize) -> Result<&[u8], String> {
+        let bytes = self
+            .bytes
+            .get(self.at..self.at + len)
+            .ok_or("the snapshot is truncated")?;
+        self.at += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        let mut word = [0; 2];
+        word.copy_from_slice(self.take(2)?);
+        Ok(u16::from_be_bytes(word))
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        let mut word = [0; 4];
+        word.copy_from_slice(self.take(4)?);
+        Ok(u32::from_be_bytes(word))
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        let mut word = [0; 8];
+        word.copy_from_slice(self.take(8)?);
+        Ok(u64::from_be_bytes(word))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::console::BufferConsole;
+    use crate::machine::Machine;
+
+    // a snapshot where every field is set to something other than its default
+    fn snapshot() -> Snapshot {
+        let mut memory = Box::new([0u16; 65536]);
+        memory[0x0000] = 0x1234;
+        memory[0x3000] = 0x5678;
+        memory[0xFFFF] = 0x9ABC;
+        let mut interrupts = InterruptController::new();
+        interrupts.raise(u3::new(4), 0x80);
+        interrupts.raise(u3::new(6), 0x81);
+        Snapshot {
+            cpu: CpuState {
+                pc: 0x3005,
+                register: [1, -2, 3, -4, 5, -6, 0x2FF0, i16::MIN],
+                psr: PSR {
+                    priority: u3::new(5),
+                    supervisor: true,
+                    n: true,
+                    z: false,
+                    p: false,
+                },
+                usp: 0xFDF0,
+                ssp: 0x2FF0,
+                asg: [0xACE1, 0x1D87, 0xBEEF],
+                halt_flag: false,
+                interrupts,
+                devices: vec![vec![0x8000, b'k' as u16], vec![], vec![1, 2, 3]],
+            },
+            steps: 123_456_789_012,
+            input: vec![(7, Some(b'a')), (9, None)],
+            memory,
+        }
+    }
+
+    #[test]
+    fn every_field_survives_a_round_trip() {
+        let snapshot = snapshot();
+        let bytes = snapshot.to_bytes();
+        assert_eq!(&bytes[..4], SNAPSHOT_MAGIC);
+        assert_eq!(Snapshot::from_bytes(&bytes), Ok(snapshot));
+    }
+
+    #[test]
+    fn a_restored_machine_takes_the_same_snapshot() {
+        let mut mem = [0u16; 65536];
+        // ADD R1, R1, #3 then HALT
+        mem[0x3000] = 0b00001 << 11 | 1 << 7 | 1 << 4 | 1 << 3 | 3;
+        mem[0x3001] = 0b01100 << 11 | 0x25;
+        let mut machine = Machine::new(Some(mem), BufferConsole::new(b""));
+        machine.run_machine().unwrap();
+        let snapshot = Snapshot::from_bytes(&machine.snapshot().to_bytes()).unwrap();
+
+        let mut restored = Machine::new(None, BufferConsole::new(b""));
+        restored.restore_snapshot(&snapshot).unwrap();
+        assert_eq!(restored.snapshot(), machine.snapshot());
+        assert!(restored.is_halted());
+        assert_eq!(restored.register(1), 3);
+    }
+
+    #[test]
+    fn other_files_are_refused() {
+        let mut bytes = snapshot().to_bytes();
+        bytes[..4].copy_from_slice(b"LC4O");
+        assert_eq!(
+            Snapshot::from_bytes(&bytes),
+            Err("not a snapshot, the file doesn't start with LC4S".to_owned())
+        );
+    }
+
+    #[test]
+    fn unknown_versions_are_refused() {
+        let mut bytes = snapshot().to_bytes();
+        bytes[4..6].copy_from_slice(&(SNAPSHOT_VERSION + 1).to_be_bytes());
+        assert_eq!(
+            Snapshot::from_bytes(&bytes),
+            Err(format!(
+                "snapshot version {} is not supported, expected version {}",
+                SNAPSHOT_VERSION + 1,
+                SNAPSHOT_VERSION
+            ))
+        );
+    }
+
+    #[test]
+    fn truncated_and_padded_files_are_refused() {
+        let bytes = snapshot().to_bytes();
+        for len in [0, 3, 40, bytes.len() - 1] {
+            assert_eq!(
+                Snapshot::from_bytes(&bytes[..len]),
+                Err("the snapshot is truncated".to_owned()),
+                "{} bytes",
+                len
+            );
+        }
+        let mut padded = bytes.clone();
+        padded.push(0);
+        assert_eq!(
+            Snapshot::from_bytes(&padded),
+            Err("1 bytes left over after the snapshot".to_owned())
+        );
+    }
+}
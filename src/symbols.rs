@@ -0,0 +1,282 @@
+use crate::error::SimError;
+use crate::parse::{is_label, parse_address};
+use crate::reader::Region;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+// labels of the loaded programs, read from the symbol tables the assembler writes, see Machine::set_symbols
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Symbols {
+    // the first label defined at each address
+    labels: BTreeMap<u16, String>,
+    addresses: BTreeMap<String, u16>,
+    // where the images were loaded, an address is only named after a label in the same image
+    regions: Vec<(u16, u16)>,
+}
+
+impl Symbols {
+    // `LABEL 0x3000` lines as written by asm, or the `//	LABEL  3000` lines of an LC-3 style .sym file
+    pub fn parse(text: &str) -> Result<Symbols, String> {
+        let mut symbols = Symbols::default();
+        for (number, line) in text.lines().enumerate() {
+            let error = |reason: String| format!("line {}: {}", number + 1, reason);
+            let (line, lc3) = match line.trim().strip_prefix("//") {
+                Some(rest) => (rest, true),
+                None => (line, false),
+            };
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let entry = match words[..] {
+                [] => continue,
+                [name, addr] if is_label(name) && lc3 => {
+                    u16::from_str_radix(addr, 16).ok().map(|addr| (name, addr))
+                }
+                [name, addr] if is_label(name) => parse_address(addr).ok().map(|addr| (name, addr)),
+                _ => None,
+            };
+            match entry {
+                Some((name, addr)) => symbols.insert(name, addr).map_err(error)?,
+                // the headings of an LC-3 table are comments too
+                None if lc3 => continue,
+                None => {
+                    return Err(error(format!(
+                        "expected LABEL ADDRESS, found '{}'",
+                        line.trim()
+                    )))
+                }
+            }
+        }
+        Ok(symbols)
+    }
+
+    pub fn read(path: &Path) -> Result<Symbols, SimError> {
+        let loader_error = |reason: String| SimError::Loader {
+            path: path.to_owned(),
+            reason,
+        };
+        let text = fs::read_to_string(path).map_err(|e| loader_error(e.to_string()))?;
+        Symbols::parse(&text).map_err(loader_error)
+    }
+
+    // a label may be defined again at the same address, as when a table is loaded twice
+    pub fn insert(&mut self, name: &str, addr: u16) -> Result<(), String> {
+        match self.addresses.get(name) {
+            Some(&other) if other != addr => Err(format!(
+                "{} is defined at both 0x{:04x} and 0x{:04x}",
+                name, other, addr
+            )),
+            Some(_) => Ok(()),
+            None => {
+                self.addresses.insert(name.to_owned(), addr);
+                self.labels.entry(addr).or_insert_with(|| name.to_owned());
+                Ok(())
+            }
+        }
+    }
+
+    pub fn extend(&mut self, other: &Symbols) -> Result<(), String> {
+        for (name, addr) in &other.addresses {
+            self.insert(name, *addr)?;
+        }
+        Ok(())
+    }
+
+    // without regions any address after a label is named after it
+    pub fn set_regions(&mut self, regions: &[Region]) {
+        self.regions = regions
+            .iter()
+            .filter_map(|region| region.end().map(|end| (region.start, end)))
+            .collect();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.addresses.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.addresses.len()
+    }
+
+    pub fn address(&self, name: &str) -> Option<u16> {
+        self.addresses.get(name).copied()
+    }
+
+    // the label at addr
+    pub fn label(&self, addr: u16) -> Option<&str> {
+        self.labels.get(&addr).map(String::as_str)
+    }
+
+    // the nearest label at or before addr with the offset from it, like LOOP or STORE_KEY+2
+    pub fn name(&self, addr: u16) -> Option<String> {
+        let (&start, label) = self.labels.range(..=addr).next_back()?;
+        let same_image = self.regions.is_empty()
+            || self
+                .regions
+                .iter()
+                .any(|&(first, last)| (first..=last).contains(&start) && addr <= last);
+        match addr - start {
+            0 => Some(label.to_owned()),
+            offset if same_image => Some(format!("{}+{}", label, offset)),
+            _ => None,
+        }
+    }
+
+    // the address in hex followed by its name when it has one, like 0x3005 <STORE_KEY+2>
+    pub fn describe(&self, addr: u16) -> String {
+        match self.name(addr) {
+            Some(name) => format!("0x{:04x} <{}>", addr, name),
+            None => format!("0x{:04x}", addr),
+        }
+    }
+
+    // an address written as a label, a label with an offset (LOOP+2, LOOP-1) or a number
+    pub fn resolve(&self, text: &str) -> Result<u16, String> {
+        if let Some(addr) = self.address(text) {
+            return Ok(addr);
+        }
+        if let Some(at) = text.rfind(['+', '-']).filter(|&at| at > 0) {
+            if let Some(addr) = self.address(&text[..at]) {
+                let offset = parse_address(&text[at + 1..])?;
+                return Ok(match &text[at..at + 1] {
+                    "+" => addr.wrapping_add(offset),
+                    _ => addr.wrapping_sub(offset),
+                });
+            }
+        }
+        parse_address(text).map_err(|error| match is_label(text) {
+            true => format!("unknown label: {}", text),
+            false => error,
+        })
+    }
+
+    // every label with its address, in name order
+    pub fn iter(&self) -> impl Iterator<Item = (&str, u16)> {
+        self.addresses
+            .iter()
+            .map(|(name, addr)| (name.as_str(), *addr))
+    }
+}
+
+// things that mention addresses and can show them by name, their Display impls show plain addresses
+pub trait Symbolic {
+    fn fmt_with(&self, f: &mut fmt::Formatter<'_>, symbols: &Symbols) -> fmt::Result;
+
+    fn with<'a>(&'a self, symbols: &'a Symbols) -> WithSymbols<'a, Self> {
+        WithSymbols {
+            item: self,
+            symbols,
+        }
+    }
+}
+
+// displays an item with its addresses named, see Symbolic::with
+pub struct WithSymbols<'a, T: ?Sized> {
+    item: &'a T,
+    symbols: &'a Symbols,
+}
+
+impl<T: Symbolic + ?Sized> fmt::Display for WithSymbols<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.item.fmt_with(f, self.symbols)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::debugger::Command;
+    use crate::lines::LineMap;
+
+    const TABLE: &str = "START 0x3000\nSTORE_KEY x3007\n\nBUFFER 16384\n";
+
+    #[test]
+    fn tables_written_by_asm_are_read() {
+        let symbols = Symbols::parse(TABLE).unwrap();
+        assert_eq!(symbols.len(), 3);
+        assert_eq!(symbols.address("START"), Some(0x3000));
+        assert_eq!(symbols.address("STORE_KEY"), Some(0x3007));
+        assert_eq!(symbols.address("BUFFER"), Some(0x4000));
+        assert_eq!(symbols.label(0x3007), Some("STORE_KEY"));
+    }
+
+    #[test]
+    fn lc3_style_tables_are_read() {
+        let text = "// Symbol table\n// Scope level 0:\n//\tSymbol Name       Page Address\n\
+                    //\t----------------  ------------\n//\tSTART             3000\n\
+                    //\tSTORE_KEY         3007\n";
+        let symbols = Symbols::parse(text).unwrap();
+        assert_eq!(
+            symbols.iter().collect::<Vec<_>>(),
+            [("START", 0x3000), ("STORE_KEY", 0x3007)]
+        );
+    }
+
+    #[test]
+    fn bad_lines_and_clashing_labels_are_refused() {
+        assert_eq!(
+            Symbols::parse("START 0x3000\n1START 0x3001\n"),
+            Err("line 2: expected LABEL ADDRESS, found '1START 0x3001'".to_owned())
+        );
+        assert_eq!(
+            Symbols::parse("START 0x3000\nSTART 0x3001\n"),
+            Err("line 2: START is defined at both 0x3000 and 0x3001".to_owned())
+        );
+    }
+
+    #[test]
+    fn addresses_are_named_after_the_nearest_label_before_them() {
+        let symbols = Symbols::parse(TABLE).unwrap();
+        assert_eq!(symbols.name(0x2FFF), None);
+        assert_eq!(symbols.name(0x3000), Some("START".to_owned()));
+        assert_eq!(symbols.name(0x3006), Some("START+6".to_owned()));
+        assert_eq!(symbols.describe(0x3009), "0x3009 <STORE_KEY+2>");
+        assert_eq!(symbols.describe(0x2000), "0x2000");
+    }
+
+    #[test]
+    fn addresses_are_only_named_after_labels_in_their_image() {
+        let mut symbols = Symbols::parse(TABLE).unwrap();
+        symbols.set_regions(&[Region {
+            path: "prog.obj".into(),
+            start: 0x3000,
+            len: 10,
+        }]);
+        assert_eq!(symbols.name(0x3009), Some("STORE_KEY+2".to_owned()));
+        assert_eq!(symbols.name(0x300A), None);
+        assert_eq!(symbols.name(0x4000), Some("BUFFER".to_owned()));
+        assert_eq!(symbols.name(0x4001), None);
+    }
+
+    #[test]
+    fn labels_resolve_with_offsets() {
+        let symbols = Symbols::parse(TABLE).unwrap();
+        assert_eq!(symbols.resolve("STORE_KEY"), Ok(0x3007));
+        assert_eq!(symbols.resolve("STORE_KEY+2"), Ok(0x3009));
+        assert_eq!(symbols.resolve("BUFFER-1"), Ok(0x3FFF));
+        assert_eq!(symbols.resolve("BUFFER+x10"), Ok(0x4010));
+        assert_eq!(symbols.resolve("x3000"), Ok(0x3000));
+        assert_eq!(
+            symbols.resolve("LOOP"),
+            Err("unknown label: LOOP".to_owned())
+        );
+    }
+
+    #[test]
+    fn breakpoints_can_be_set_on_labels() {
+        let symbols = Symbols::parse(TABLE).unwrap();
+        let lines = LineMap::default();
+        assert_eq!(
+            Command::parse_with("break STORE_KEY", &symbols, &lines),
+            Ok(Command::Break(0x3007))
+        );
+        assert_eq!(
+            Command::parse_with("b STORE_KEY+2", &symbols, &lines),
+            Ok(Command::Break(0x3009))
+        );
+        assert_eq!(
+            Command::parse_with("break LOOP", &symbols, &lines),
+            Err("unknown label: LOOP".to_owned())
+        );
+    }
+}
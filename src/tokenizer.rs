@@ -1,6 +1,7 @@
+use std::fmt;
 use ux::{i3, i7, u3};
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum Operation {
     ADD,
     ADDi,
@@ -50,20 +51,20 @@ pub enum Operation {
 
 impl Operation {
     fn is_double(&self) -> bool {
-        match self {
-            Operation::ADDi16 => true,
-            Operation::ADDa => true,
-            Operation::ANDi16 => true,
-            Operation::ANDa => true,
-            Operation::XORi16 => true,
-            Operation::XORa => true,
-            Operation::BR => true,
-            Operation::JSR => true,
-            Operation::LDa => true,
-            Operation::ST => true,
-            Operation::STR16 => true,
-            _ => false,
-        }
+        matches!(
+            self,
+            Operation::ADDi16
+                | Operation::ADDa
+                | Operation::ANDi16
+                | Operation::ANDa
+                | Operation::XORi16
+                | Operation::XORa
+                | Operation::BR
+                | Operation::JSR
+                | Operation::LDa
+                | Operation::ST
+                | Operation::STR16
+        )
     }
 }
 #[derive(Debug, PartialEq, Clone)]
@@ -73,10 +74,10 @@ pub enum Operand {
     Imm16(i16),
     Imm7(i7),
     Imm3(i3),
-    //TrapVect(u8),
+    TrapVect(u8),
     Register(u3),
 }
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct Instruction {
     pub operation: Operation,
     pub dr: Option<Operand>,
@@ -90,17 +91,50 @@ pub struct Flags {
     pub p: bool,
 }
 
+// why a word or pair of words is not an instruction
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum DecodeError {
+    // the opcode field, bits 15-11, is not assigned to any instruction
+    IllegalOpcode(u8),
+    // a double length instruction decoded without its second word
+    MissingWord(Operation),
+    // the length bit disagrees with the instruction, BR and ST are always double length
+    // and the register only instructions always single length
+    LengthMismatch(Operation),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::IllegalOpcode(opcode) => write!(f, "illegal opcode 0b{:05b}", opcode),
+            DecodeError::MissingWord(operation) => {
+                write!(f, "{} is missing its second word", operation.mnemonic())
+            }
+            DecodeError::LengthMismatch(operation) => write!(
+                f,
+                "the length bit does not match the length of {}",
+                operation.mnemonic()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+// decode an instruction, any pair of words either decodes or gives an error, this never panics
 pub fn tokenize(
     encoded_instruction: u16,
     second_operand: Option<u16>,
-) -> Result<Instruction, String> {
-    let operation = match_opcode(encoded_instruction);
+) -> Result<Instruction, DecodeError> {
+    let operation = match_opcode(encoded_instruction)?;
+    if check_instruction_double(encoded_instruction) != operation.is_double() {
+        return Err(DecodeError::LengthMismatch(operation));
+    }
     if operation.is_double() {
-        parse_double(
-            operation,
-            encoded_instruction,
-            second_operand.expect("32 bit operand parse error"),
-        )
+        match second_operand {
+            Some(operand) => parse_double(operation, encoded_instruction, operand),
+            None => Err(DecodeError::MissingWord(operation)),
+        }
     } else {
         parse_single(operation, encoded_instruction)
     }
@@ -108,8 +142,9 @@ pub fn tokenize(
 
 // extract operation from instruction
 
-fn match_opcode(instruction: u16) -> Operation {
-    match instruction >> 11 & 0b11111 {
+fn match_opcode(instruction: u16) -> Result<Operation, DecodeError> {
+    let opcode = (instruction >> 11 & 0b11111) as u8;
+    Ok(match opcode {
         0b00001 => parse_add(instruction),
         0b00010 => parse_and(instruction),
         0b00011 => parse_xor(instruction),
@@ -146,15 +181,39 @@ fn match_opcode(instruction: u16) -> Operation {
         0b01010 => Operation::NOT,
         0b01100 => parse_trap(instruction),
         0b01101 => Operation::RTI,
-        _ => panic!("invalid instruction"),
-    }
+        _ => return Err(DecodeError::IllegalOpcode(opcode)),
+    })
+}
+
+// false for opcodes that are not assigned to any instruction
+pub fn opcode_defined(instruction: u16) -> bool {
+    match_opcode(instruction).is_ok()
 }
 
 pub fn check_instruction_double(instruction: u16) -> bool {
     instruction >> 10 & 0b1 == 1
 }
 
-fn parse_trap(instruction: u16) -> Operation {
+impl Instruction {
+    // the trap vector of a TRAP, including the ones decoded to a named service routine
+    pub fn trap_vector(&self) -> Option<u8> {
+        match (self.operation, &self.operand1) {
+            (Operation::TRAP, Some(Operand::TrapVect(vect))) => Some(*vect),
+            (Operation::GETC, _) => Some(0x20),
+            (Operation::OUT, _) => Some(0x21),
+            (Operation::PUTS, _) => Some(0x22),
+            (Operation::IN, _) => Some(0x23),
+            (Operation::PUTSP, _) => Some(0x24),
+            (Operation::HALT, _) => Some(0x25),
+            (Operation::LSD, _) => Some(0x26),
+            (Operation::LPN, _) => Some(0x27),
+            (Operation::CLRP, _) => Some(0x28),
+            _ => None,
+        }
+    }
+}
+
+pub(crate) fn parse_trap(instruction: u16) -> Operation {
     let instruction = instruction & 0b11111111;
     match instruction {
         0x20 => Operation::GETC,
@@ -213,7 +272,7 @@ fn parse_xor(instruction: u16) -> Operation {
 // match parsed operations and create instructions
 
 // parse single length insntructions
-fn parse_single(operation: Operation, instruction: u16) -> Result<Instruction, String> {
+fn parse_single(operation: Operation, instruction: u16) -> Result<Instruction, DecodeError> {
     match operation {
         Operation::ADD => Ok(parse_def(operation, instruction)),
         Operation::ADDi => Ok(parse_i(operation, instruction)),
@@ -242,7 +301,12 @@ fn parse_single(operation: Operation, instruction: u16) -> Result<Instruction, S
             operand1: Some(get_sr(instruction)),
             operand2: None,
         }),
-        Operation::TRAP => Err("unexpected TRAP instruction not supported".to_string()),
+        Operation::TRAP => Ok(Instruction {
+            operation,
+            dr: None,
+            operand1: Some(Operand::TrapVect(instruction as u8)),
+            operand2: None,
+        }),
         Operation::RTI => Ok(operation_to_instruction(operation)),
         Operation::LSD => Ok(operation_to_instruction(operation)),
         Operation::LPN => Ok(operation_to_instruction(operation)),
@@ -253,7 +317,7 @@ fn parse_single(operation: Operation, instruction: u16) -> Result<Instruction, S
         Operation::OUT => Ok(operation_to_instruction(operation)),
         Operation::IN => Ok(operation_to_instruction(operation)),
         Operation::PUTSP => Ok(operation_to_instruction(operation)),
-        _ => Err("long instruction in parse_single".to_string()),
+        _ => Err(DecodeError::MissingWord(operation)),
     }
 }
 //helpers for single length instruction parsing
@@ -295,7 +359,7 @@ fn parse_double(
     operation: Operation,
     instruction: u16,
     operand: u16,
-) -> Result<Instruction, String> {
+) -> Result<Instruction, DecodeError> {
     match operation {
         Operation::ADDi16 => Ok(parse_i16(operation, instruction, operand)),
         Operation::ADDa => Ok(parse_a(operation, instruction, operand)),
@@ -328,7 +392,7 @@ fn parse_double(
             operand1: None,
             operand2: Some(get_imm16(operand)),
         }),
-        _ => Err("short instruction in parse_double".to_string()),
+        _ => Err(DecodeError::LengthMismatch(operation)),
     }
 }
 // helpers for double length instruction parsing
@@ -379,24 +443,114 @@ fn get_sr2(instruction: u16) -> Operand {
     let out: u3 = u3::new((instruction & 0b111u16) as u8);
     Operand::Register(out)
 }
+// the immediates are sign extended by shifting their sign bit into bit 7 of an i8 and back,
+// so the result is always in range
 fn get_imm3(instruction: u16) -> Operand {
-    Operand::Imm3(if instruction >> 2 & 0b1 == 1 {
-        let num = instruction | 0b1111_1111_1111_1100;
-        i3::new(num as i8)
-    } else {
-        let num = instruction & 0b0000_0000_0000_0011;
-        i3::new(num as i8)
-    })
+    Operand::Imm3(i3::new((instruction as i8) << 5 >> 5))
 }
 fn get_imm7(instruction: u16) -> Operand {
-    Operand::Imm7(if instruction >> 6 & 0b1 == 1 {
-        let num = instruction | 0b1111_1111_1100_0000;
-        i7::new(num as i8)
-    } else {
-        let num = instruction & 0b0000_0000_0011_1111;
-        i7::new(num as i8)
-    })
+    Operand::Imm7(i7::new((instruction as i8) << 1 >> 1))
 }
 fn get_imm16(operand: u16) -> Operand {
     Operand::Imm16(operand as i16)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // second words with every bit pattern the double length fields care about
+    const SECOND_WORDS: [u16; 5] = [0x0000, 0x0001, 0x7fff, 0x8000, 0xffff];
+
+    // the opcodes in bits 15-11 that belong to an instruction
+    fn assigned(word: u16) -> bool {
+        matches!(word >> 11, 0b00001..=0b01010 | 0b01100 | 0b01101)
+    }
+
+    #[test]
+    fn unused_opcodes_are_illegal() {
+        for word in 0..=u16::MAX {
+            if assigned(word) {
+                continue;
+            }
+            let opcode = (word >> 11) as u8;
+            assert_eq!(
+                tokenize(word, None),
+                Err(DecodeError::IllegalOpcode(opcode))
+            );
+            for second in SECOND_WORDS {
+                assert_eq!(
+                    tokenize(word, Some(second)),
+                    Err(DecodeError::IllegalOpcode(opcode))
+                );
+            }
+        }
+    }
+
+    // every word decodes or gives the error its fields call for, with or without a second word
+    #[test]
+    fn every_word_decodes_or_gives_the_right_error() {
+        for word in (0..=u16::MAX).filter(|&word| assigned(word)) {
+            let operation = match_opcode(word).unwrap();
+            let length_bit = check_instruction_double(word);
+            let without = tokenize(word, None);
+            if length_bit != operation.is_double() {
+                assert_eq!(
+                    without,
+                    Err(DecodeError::LengthMismatch(operation)),
+                    "{:04x}",
+                    word
+                );
+                for second in SECOND_WORDS {
+                    assert_eq!(
+                        tokenize(word, Some(second)),
+                        Err(DecodeError::LengthMismatch(operation)),
+                        "{:04x} {:04x}",
+                        word,
+                        second
+                    );
+                }
+            } else if operation.is_double() {
+                assert_eq!(
+                    without,
+                    Err(DecodeError::MissingWord(operation)),
+                    "{:04x}",
+                    word
+                );
+                for second in SECOND_WORDS {
+                    let decoded = tokenize(word, Some(second)).unwrap();
+                    assert_eq!(decoded.operation, operation);
+                }
+            } else {
+                // a one word instruction ignores the word after it
+                let decoded = without.unwrap();
+                assert_eq!(decoded.operation, operation);
+                for second in SECOND_WORDS {
+                    assert_eq!(tokenize(word, Some(second)), Ok(decoded.clone()));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn one_word_instructions_with_the_length_bit_are_mismatched() {
+        // NOT R1, R2 and RTI with bit 10 set
+        assert_eq!(
+            tokenize(0b01010 << 11 | 1 << 10 | 1 << 7 | 2 << 4, Some(0x1234)),
+            Err(DecodeError::LengthMismatch(Operation::NOT))
+        );
+        assert_eq!(
+            tokenize(0b01101 << 11 | 1 << 10, Some(0)),
+            Err(DecodeError::LengthMismatch(Operation::RTI))
+        );
+        // BR and ST are always two words
+        assert_eq!(
+            tokenize(0b00100 << 11 | 0b111 << 7, None),
+            Err(DecodeError::LengthMismatch(Operation::BR))
+        );
+        assert_eq!(
+            tokenize(0b01001 << 11 | 1 << 10 | 1 << 7, None),
+            Err(DecodeError::MissingWord(Operation::ST))
+        );
+    }
+}
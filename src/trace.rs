@@ -0,0 +1,287 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TraceFormat {
+    // one JSON object per line, numbers are plain JSON numbers
+    JsonLines,
+    // a header line and one row per instruction, numbers are hex
+    Csv,
+}
+
+impl TraceFormat {
+    // CSV for a .csv file, JSON Lines for anything else
+    pub fn from_path(path: &Path) -> TraceFormat {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("csv") => TraceFormat::Csv,
+            _ => TraceFormat::JsonLines,
+        }
+    }
+}
+
+// what one executed instruction did, including entering an interrupt or exception handler on the way
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceRecord {
+    // number of instructions executed before this one
+    pub step: u64,
+    pub pc: u16,
+    // the PC named after the nearest label, like LOOP+2, see Machine::set_symbols
+    pub symbol: Option<String>,
+    // the assembly source line of the instruction, like prog.asm:12, see Machine::set_line_map
+    pub source: Option<String>,
+    pub words: Vec<u16>,
+    pub disassembly: String,
+    // (register, new value) for every register the instruction changed
+    pub registers: Vec<(usize, u16)>,
+    // (address, value) for every write, in the order they happened, including device registers
+    pub memory: Vec<(u16, u16)>,
+    // the PSR after the instruction, see PSR::to_word
+    pub psr: u16,
+    pub n: bool,
+    pub z: bool,
+    pub p: bool,
+}
+
+// writes a record for every instruction the machine executes, see Machine::set_trace
+pub struct Trace {
+    out: Box<dyn Write>,
+    format: TraceFormat,
+    // the CSV header goes out with the first record
+    started: bool,
+}
+
+impl Trace {
+    pub fn new(out: Box<dyn Write>, format: TraceFormat) -> Trace {
+        Trace {
+            out,
+            format,
+            started: false,
+        }
+    }
+
+    // trace into a new file at path, replacing one that is already there
+    pub fn create(path: &Path, format: TraceFormat) -> io::Result<Trace> {
+        let file = File::create(path)?;
+        Ok(Trace::new(Box::new(BufWriter::new(file)), format))
+    }
+
+    pub fn record(&mut self, record: &TraceRecord) -> io::Result<()> {
+        let line = match self.format {
+            TraceFormat::JsonLines => json_line(record),
+            TraceFormat::Csv => {
+                if !self.started {
+                    writeln!(
+                        self.out,
+                        "step,pc,symbol,source,words,disassembly,registers,memory,psr,n,z,p"
+                    )?;
+                }
+                csv_line(record)
+            }
+        };
+        self.started = true;
+        writeln!(self.out, "{}", line)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+fn json_line(record: &TraceRecord) -> String {
+    let words: Vec<String> = record.words.iter().map(|w| w.to_string()).collect();
+    let registers: Vec<String> = record
+        .registers
+        .iter()
+        .map(|(reg, value)| format!("\"R{}\":{}", reg, value))
+        .collect();
+    let memory: Vec<String> = record
+        .memory
+        .iter()
+        .map(|(addr, value)| format!("{{\"address\":{},\"value\":{}}}", addr, value))
+        .collect();
+    let string = |text: &Option<String>| match text {
+        Some(text) => format!("\"{}\"", json_escape(text)),
+        None => "null".to_owned(),
+    };
+    format!(
+        "{{\"step\":{},\"pc\":{},\"symbol\":{},\"source\":{},\"words\":[{}],\"disassembly\":\"{}\",\"registers\":{{{}}},\"memory\":[{}],\"psr\":{},\"n\":{},\"z\":{},\"p\":{}}}",
+        record.step,
+        record.pc,
+        string(&record.symbol),
+        string(&record.source),
+        words.join(","),
+        json_escape(&record.disassembly),
+        registers.join(","),
+        memory.join(","),
+        record.psr,
+        record.n,
+        record.z,
+        record.p
+    )
+}
+
+fn json_escape(text: &str) -> String {
+    let mut out = String::new();
+    for c in text.chars() {
+        match c {
+            '"' => out += "\\\"",
+            '\\' => out += "\\\\",
+            c if (c as u32) < 0x20 => out += &format!("\\u{:04x}", c as u32),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+// lists are space separated within their field, the disassembly and source are quoted since they can
+// have commas
+fn csv_line(record: &TraceRecord) -> String {
+    let words: Vec<String> = record
+        .words
+        .iter()
+        .map(|w| format!("0x{:04x}", w))
+        .collect();
+    let registers: Vec<String> = record
+        .registers
+        .iter()
+        .map(|(reg, value)| format!("R{}=0x{:04x}", reg, value))
+        .collect();
+    let memory: Vec<String> = record
+        .memory
+        .iter()
+        .map(|(addr, value)| format!("0x{:04x}=0x{:04x}", addr, value))
+        .collect();
+    format!(
+        "{},0x{:04x},{},\"{}\",{},\"{}\",{},{},0x{:04x},{},{},{}",
+        record.step,
+        record.pc,
+        record.symbol.as_deref().unwrap_or(""),
+        record.source.as_deref().unwrap_or("").replace('"', "\"\""),
+        words.join(" "),
+        record.disassembly.replace('"', "\"\""),
+        registers.join(" "),
+        memory.join(" "),
+        record.psr,
+        record.n as u8,
+        record.z as u8,
+        record.p as u8
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble_memory;
+    use crate::console::BufferConsole;
+    use crate::lines::LineMap;
+    use crate::machine::Machine;
+    use crate::reader::Region;
+    use crate::symbols::Symbols;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // a writer the test can read back after the machine has written to it
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // the lines traced while running a short program with its labels and line map
+    fn trace(format: TraceFormat) -> Vec<String> {
+        let mem = assemble_memory(&[
+            ".ORIG x3000\nSTART ADD R1, R1, #3\nST R1, x4000\nAND R3, R2, #0\nHALT\n.END\n",
+        ]);
+        let mut machine = Machine::new(Some(mem), BufferConsole::new(b""));
+        let mut symbols = Symbols::parse("START 0x3000").unwrap();
+        symbols.set_regions(&[Region {
+            path: "prog.obj".into(),
+            start: 0x3000,
+            len: 5,
+        }]);
+        machine.set_symbols(symbols);
+        machine.set_line_map(
+            LineMap::parse(
+                "0x3000 prog.asm:2\n0x3001 prog.asm:3\n0x3003 prog.asm:4\n0x3004 prog.asm:5\n",
+                Path::new(""),
+            )
+            .unwrap(),
+        );
+        let out = Shared::default();
+        machine.set_trace(Some(Trace::new(Box::new(out.clone()), format)));
+        machine.run_machine().unwrap();
+        let text = String::from_utf8(out.0.borrow().clone()).unwrap();
+        text.lines().map(str::to_owned).collect()
+    }
+
+    // splits a CSV row at the commas outside quotes, unquoting the fields
+    fn csv_fields(row: &str) -> Vec<String> {
+        let mut fields = vec![String::new()];
+        let mut quoted = false;
+        let mut chars = row.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '"' if quoted && chars.peek() == Some(&'"') => {
+                    chars.next();
+                    fields.last_mut().unwrap().push('"');
+                }
+                '"' => quoted = !quoted,
+                ',' if !quoted => fields.push(String::new()),
+                c => fields.last_mut().unwrap().push(c),
+            }
+        }
+        fields
+    }
+
+    #[test]
+    fn json_lines_have_one_object_per_instruction() {
+        assert_eq!(
+            trace(TraceFormat::JsonLines),
+            [
+                r#"{"step":0,"pc":12288,"symbol":"START","source":"prog.asm:2","words":[2203],"disassembly":"ADD R1, R1, #3","registers":{"R1":3},"memory":[],"psr":32769,"n":false,"z":false,"p":true}"#,
+                r#"{"step":1,"pc":12289,"symbol":"START+1","source":"prog.asm:3","words":[19584,16384],"disassembly":"ST R1, 0x4000","registers":{},"memory":[{"address":16384,"value":3}],"psr":32769,"n":false,"z":false,"p":true}"#,
+                r#"{"step":2,"pc":12291,"symbol":"START+3","source":"prog.asm:4","words":[4520],"disassembly":"AND R3, R2, #0","registers":{},"memory":[],"psr":32770,"n":false,"z":true,"p":false}"#,
+                r#"{"step":3,"pc":12292,"symbol":"START+4","source":"prog.asm:5","words":[24613],"disassembly":"HALT","registers":{},"memory":[],"psr":32770,"n":false,"z":true,"p":false}"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn csv_rows_follow_the_header() {
+        let lines = trace(TraceFormat::Csv);
+        let header = csv_fields(&lines[0]);
+        let rows: Vec<Vec<String>> = lines[1..].iter().map(|row| csv_fields(row)).collect();
+        assert_eq!(rows.len(), 4);
+        assert!(rows.iter().all(|row| row.len() == header.len()));
+        let field = |row: usize, name: &str| {
+            let column = header.iter().position(|h| h == name).unwrap();
+            rows[row][column].as_str()
+        };
+        assert_eq!(field(0, "pc"), "0x3000");
+        assert_eq!(field(0, "symbol"), "START");
+        assert_eq!(field(0, "source"), "prog.asm:2");
+        assert_eq!(field(0, "disassembly"), "ADD R1, R1, #3");
+        assert_eq!(field(0, "registers"), "R1=0x0003");
+        assert_eq!(field(1, "words"), "0x4c80 0x4000");
+        assert_eq!(field(1, "registers"), "");
+        assert_eq!(field(1, "memory"), "0x4000=0x0003");
+        // AND leaves R3 at 0, so only the flags change
+        assert_eq!(field(2, "registers"), "");
+        assert_eq!(field(2, "psr"), "0x8002");
+        assert_eq!(
+            [field(2, "n"), field(2, "z"), field(2, "p")],
+            ["0", "1", "0"]
+        );
+        assert_eq!(field(3, "symbol"), "START+4");
+        assert_eq!(field(3, "disassembly"), "HALT");
+    }
+}
@@ -0,0 +1,652 @@
+use crate::console::Console;
+use crate::debugger::{Command, Debugger};
+use crate::disasm::disassemble;
+use crate::symbols::Symbolic;
+use std::collections::VecDeque;
+use std::io::{self, stdin, stdout, Read, Stdout, Write};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+use termion::event::{parse_event, Event, Key};
+use termion::raw::{IntoRawMode, RawTerminal};
+use termion::screen::{AlternateScreen, IntoAlternateScreen};
+use termion::{clear, cursor, style, terminal_size};
+
+const KEYS: &str =
+    "s step  n next  f finish  c continue  r/R reverse  b break  i input  : command  ? help  q quit";
+
+const HELP: &str = "\
+s           step one instruction
+n           step over JSR, JSRR and traps with a handler
+f           run until the current subroutine or trap handler returns
+c           continue until a breakpoint or HALT, Esc or Ctrl-C stops the program
+r / R       reverse step / reverse continue
+b           toggle a breakpoint at the PC
+i           type program input, Esc when done
+:           run a debugger command, such as :break x3010 or :watch x4000, :x ADDR moves the memory pane
+up / down   scroll the memory pane, page up / page down by a page
+q           quit
+";
+
+const ESC: u8 = 0x1b;
+const CTRL_C: u8 = 0x03;
+// instructions executed between checks for keys while the program runs
+const RUN_CHUNK: usize = 2000;
+// how often the screen is redrawn while the program runs
+const RUN_REDRAW: Duration = Duration::from_millis(50);
+// how long to wait for keys before checking if the terminal was resized
+const IDLE_WAIT: Duration = Duration::from_millis(200);
+// program output kept for the console pane
+const OUTPUT_LIMIT: usize = 1 << 16;
+
+// the full-screen terminal, program output is kept for the console pane
+// and program input is typed ahead into a queue
+pub struct TuiConsole {
+    bytes: Receiver<io::Result<u8>>,
+    input: VecDeque<u8>,
+    output: Vec<u8>,
+    screen: AlternateScreen<RawTerminal<Stdout>>,
+    // Esc or Ctrl-C was typed while the program waited for a key
+    interrupted: bool,
+}
+
+impl TuiConsole {
+    pub fn new() -> io::Result<TuiConsole> {
+        let mut screen = stdout().into_raw_mode()?.into_alternate_screen()?;
+        write!(screen, "{}{}", clear::All, cursor::Hide)?;
+        let (send, bytes) = channel();
+        thread::spawn(move || {
+            for byte in stdin().lock().bytes() {
+                if send.send(byte).is_err() {
+                    return;
+                }
+            }
+        });
+        Ok(TuiConsole {
+            bytes,
+            input: VecDeque::new(),
+            output: Vec::new(),
+            screen,
+            interrupted: false,
+        })
+    }
+
+    pub fn push_input(&mut self, bytes: &[u8]) {
+        self.input
+            .extend(bytes.iter().map(|b| if *b == b'\r' { b'\n' } else { *b }));
+    }
+
+    // input that was typed ahead and not read by the program yet
+    pub fn queued_input(&self) -> &VecDeque<u8> {
+        &self.input
+    }
+
+    pub fn output(&self) -> &[u8] {
+        &self.output
+    }
+
+    // true once after a read was stopped with Esc or Ctrl-C
+    pub fn take_interrupted(&mut self) -> bool {
+        std::mem::take(&mut self.interrupted)
+    }
+
+    // the bytes typed so far, waiting up to `timeout` for the first one, None once stdin is closed
+    fn read_keys(&mut self, timeout: Duration) -> io::Result<Option<Vec<u8>>> {
+        let mut bytes = Vec::new();
+        match self.bytes.recv_timeout(timeout) {
+            Ok(byte) => bytes.push(byte?),
+            Err(RecvTimeoutError::Timeout) => return Ok(Some(bytes)),
+            Err(RecvTimeoutError::Disconnected) => return Ok(None),
+        }
+        loop {
+            match self.bytes.try_recv() {
+                Ok(byte) => bytes.push(byte?),
+                // the rest of an escape sequence may still be on its way
+                Err(TryRecvError::Empty) if bytes.last() == Some(&ESC) => {
+                    match self.bytes.recv_timeout(Duration::from_millis(20)) {
+                        Ok(byte) => bytes.push(byte?),
+                        Err(_) => return Ok(Some(bytes)),
+                    }
+                }
+                Err(_) => return Ok(Some(bytes)),
+            }
+        }
+    }
+
+    fn draw(&mut self, frame: &str) -> io::Result<()> {
+        self.screen.write_all(frame.as_bytes())?;
+        self.screen.flush()
+    }
+
+    // replace the bottom line of the screen
+    fn status(&mut self, text: &str) -> io::Result<()> {
+        let (width, height) = terminal_size()?;
+        let line = format!(
+            "{}{}{}{}",
+            cursor::Goto(1, height),
+            style::Invert,
+            fit(text, width as usize),
+            style::Reset
+        );
+        self.draw(&line)
+    }
+}
+
+impl Drop for TuiConsole {
+    fn drop(&mut self) {
+        let _ = write!(self.screen, "{}", cursor::Show);
+    }
+}
+
+impl Console for TuiConsole {
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        if let Some(byte) = self.input.pop_front() {
+            return Ok(Some(byte));
+        }
+        // nothing was typed ahead, so the program gets the next key
+        self.status(
+            " program waiting for input, the next key goes to the program, Esc or Ctrl-C stops",
+        )?;
+        let byte = match self.bytes.recv() {
+            Ok(byte) => byte?,
+            Err(_) => return Ok(None),
+        };
+        let stop = match byte {
+            CTRL_C => true,
+            // a lone Esc, escape sequences go to the program like other keys
+            ESC => match self.bytes.recv_timeout(Duration::from_millis(20)) {
+                Ok(next) => {
+                    self.input.push_back(next?);
+                    false
+                }
+                Err(_) => true,
+            },
+            _ => false,
+        };
+        if stop {
+            self.interrupted = true;
+            return Err(io::Error::new(
+                io::ErrorKind::Interrupted,
+                "stopped while waiting for input",
+            ));
+        }
+        Ok(Some(if byte == b'\r' { b'\n' } else { byte }))
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.output.extend_from_slice(bytes);
+        if self.output.len() > OUTPUT_LIMIT {
+            let excess = self.output.len() - OUTPUT_LIMIT;
+            self.output.drain(..excess);
+        }
+        Ok(())
+    }
+
+    // the screen is redrawn by the debugger
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn poll(&mut self) -> io::Result<bool> {
+        Ok(!self.input.is_empty())
+    }
+}
+
+#[derive(PartialEq)]
+enum Mode {
+    Normal,
+    // editing a debugger command after :
+    Command(String),
+    // keys are queued as program input
+    Input,
+    // the program runs until a breakpoint, HALT or Esc
+    Running,
+}
+
+struct Tui<'a> {
+    debugger: &'a mut Debugger<TuiConsole>,
+    mode: Mode,
+    // output of the last command
+    message: String,
+    // first address and shape of the memory pane, the shape is set when it is drawn
+    memory: u16,
+    memory_rows: u16,
+    memory_row_words: u16,
+    quit: bool,
+}
+
+// run the full-screen debugger until q or the end of input, `message` is shown at the start
+pub fn run_tui(debugger: &mut Debugger<TuiConsole>, message: &str) -> Result<(), String> {
+    let memory = debugger.machine().pc() & !0x7;
+    let message = message.to_owned() + &debugger.location();
+    let mut tui = Tui {
+        debugger,
+        mode: Mode::Normal,
+        message,
+        memory,
+        memory_rows: 1,
+        memory_row_words: 8,
+        quit: false,
+    };
+    tui.run().map_err(|e| e.to_string())
+}
+
+impl Tui<'_> {
+    fn console(&mut self) -> &mut TuiConsole {
+        self.debugger.machine_mut().console_mut()
+    }
+
+    fn run(&mut self) -> io::Result<()> {
+        let mut size = terminal_size()?;
+        let mut drawn = Instant::now();
+        self.draw()?;
+        while !self.quit {
+            let wait = if self.mode == Mode::Running {
+                Duration::ZERO
+            } else {
+                IDLE_WAIT
+            };
+            let bytes = match self.console().read_keys(wait)? {
+                Some(bytes) => bytes,
+                None => return Ok(()),
+            };
+            let typed = !bytes.is_empty();
+            if typed {
+                self.handle_bytes(&bytes);
+            }
+            if self.mode == Mode::Running {
+                self.run_chunk();
+            }
+            if terminal_size()? != size {
+                size = terminal_size()?;
+                self.console().draw(clear::All.as_ref())?;
+            } else if !typed && self.mode != Mode::Running {
+                continue;
+            }
+            if self.mode != Mode::Running || drawn.elapsed() >= RUN_REDRAW {
+                self.draw()?;
+                drawn = Instant::now();
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_bytes(&mut self, bytes: &[u8]) {
+        match self.mode {
+            // everything but Esc and Ctrl-C is input for the running program
+            Mode::Running => {
+                if bytes == [ESC] || bytes.contains(&CTRL_C) {
+                    self.mode = Mode::Normal;
+                    self.message = "stopped\n".to_owned() + &self.debugger.stop_report();
+                } else {
+                    self.console().push_input(bytes);
+                }
+            }
+            _ => {
+                for key in parse_keys(bytes) {
+                    self.handle_key(key);
+                }
+            }
+        }
+    }
+
+    fn handle_key(&mut self, key: Key) {
+        match &mut self.mode {
+            Mode::Command(line) => match key {
+                Key::Char('\n') => {
+                    let line = std::mem::take(line);
+                    self.mode = Mode::Normal;
+                    self.command_line(&line);
+                }
+                Key::Char(c) => line.push(c),
+                Key::Backspace if !line.is_empty() => {
+                    line.pop();
+                }
+                Key::Backspace | Key::Esc | Key::Ctrl('c') => self.mode = Mode::Normal,
+                _ => (),
+            },
+            Mode::Input => match key {
+                Key::Esc | Key::Ctrl('c') => self.mode = Mode::Normal,
+                Key::Backspace => {
+                    self.console().input.pop_back();
+                }
+                Key::Char(c) if c.is_ascii() => self.console().push_input(&[c as u8]),
+                _ => (),
+            },
+            _ => match key {
+                Key::Char('s') => self.command(&Command::Step(1)),
+                Key::Char('n') => self.command(&Command::Next),
+                Key::Char('f') => self.command(&Command::Finish),
+                Key::Char('r') => self.command(&Command::ReverseStep(1)),
+                Key::Char('R') => self.command(&Command::ReverseContinue),
+                Key::Char('c') => self.start_running(),
+                Key::Char('b') => {
+                    let pc = self.debugger.machine().pc();
+                    if self.debugger.breakpoints().contains(&pc) {
+                        self.command(&Command::Delete(Some(pc)));
+                    } else {
+                        self.command(&Command::Break(pc));
+                    }
+                }
+                Key::Char('i') => self.mode = Mode::Input,
+                Key::Char(':') => self.mode = Mode::Command(String::new()),
+                Key::Char('?') | Key::Char('h') => self.message = HELP.to_owned(),
+                Key::Char('q') | Key::Ctrl('c') => self.quit = true,
+                Key::Up => self.scroll_memory(-1),
+                Key::Down => self.scroll_memory(1),
+                Key::PageUp => self.scroll_memory(-(self.memory_rows as i32)),
+                Key::PageDown => self.scroll_memory(self.memory_rows as i32),
+                _ => (),
+            },
+        }
+    }
+
+    fn command_line(&mut self, line: &str) {
+        if line.trim().is_empty() {
+            return;
+        }
+        match self.debugger.parse(line) {
+            Ok(Command::Examine(addr, _)) => {
+                self.memory = addr;
+                self.message = format!("memory at 0x{:04x}\n", addr);
+            }
+            // continuing runs in chunks so it can be stopped
+            Ok(Command::Continue) => self.start_running(),
+            Ok(Command::Quit) => self.quit = true,
+            Ok(command) => self.command(&command),
+            Err(error) => self.message = error + "\n",
+        }
+    }
+
+    fn command(&mut self, command: &Command) {
+        self.message = match self.debugger.execute(command) {
+            Ok(text) => text,
+            Err(_) if self.stopped_for_input() => return,
+            Err(error) => format!("error: {}\n", error),
+        };
+    }
+
+    // stopping the program while it waits for a key fails the instruction reading it, which is undone
+    // so the program reads again when it continues
+    fn stopped_for_input(&mut self) -> bool {
+        if !self.console().take_interrupted() {
+            return false;
+        }
+        self.mode = Mode::Normal;
+        self.message = match self.debugger.machine_mut().reverse_step() {
+            Ok(_) => "stopped\n".to_owned() + &self.debugger.stop_report(),
+            Err(error) => format!("stopped, but couldn't undo the read: {}\n", error),
+        };
+        true
+    }
+
+    // like the continue command, execute one instruction to get off a breakpoint and then run in chunks
+    fn start_running(&mut self) {
+        if self.debugger.machine().is_halted() {
+            self.message = "halted\n".to_owned();
+            return;
+        }
+        match self.debugger.machine_mut().step() {
+            Ok(()) => self.mode = Mode::Running,
+            Err(_) if self.stopped_for_input() => (),
+            Err(error) => self.message = format!("error: {}\n", error),
+        }
+    }
+
+    fn run_chunk(&mut self) {
+        match self.debugger.run_for(RUN_CHUNK) {
+            Ok(false) => (),
+            Ok(true) => {
+                self.mode = Mode::Normal;
+                self.message = self.debugger.stop_report();
+            }
+            Err(_) if self.stopped_for_input() => (),
+            Err(error) => {
+                self.mode = Mode::Normal;
+                self.message = format!("error: {}\n", error);
+            }
+        }
+    }
+
+    fn scroll_memory(&mut self, rows: i32) {
+        let words = rows * self.memory_row_words as i32;
+        self.memory = self.memory.wrapping_add(words as u16);
+    }
+
+    fn draw(&mut self) -> io::Result<()> {
+        let (width, height) = terminal_size()?;
+        let (width, height) = (width as usize, height as usize);
+        // header, three pane titles and the status line take a row each
+        let rows = height.saturating_sub(5);
+        let top = (rows * 2 / 5).max(10).min(rows);
+        let memory = (rows - top) / 3;
+        let output = (rows - top - memory) / 2;
+        let console = rows - top - memory - output;
+        let left = width / 2;
+        let right = width.saturating_sub(left + 1);
+
+        let machine = self.debugger.machine();
+        let state = if self.mode == Mode::Running {
+            "running"
+        } else if machine.is_halted() {
+            "halted"
+        } else {
+            "stopped"
+        };
+        let header = format!(
+            " LC-4 debugger   PC 0x{:04x}   steps {}   {}",
+            machine.pc(),
+            machine.steps(),
+            state
+        );
+        let mut lines = vec![inverted(&header, width)];
+        let disassembly = self.disassembly(top);
+        let registers = self.registers();
+        for i in 0..top {
+            let text = |pane: &[String]| pane.get(i).cloned().unwrap_or_default();
+            lines.push(format!(
+                "{}|{}",
+                fit(&text(&disassembly), left),
+                fit(&text(&registers), right)
+            ));
+        }
+
+        lines.push(title("memory", width));
+        self.memory_rows = memory.max(1) as u16;
+        self.memory_row_words = if width >= 70 { 8 } else { 4 };
+        for row in 0..memory as u16 {
+            let addr = self
+                .memory
+                .wrapping_add(row.wrapping_mul(self.memory_row_words));
+            lines.push(fit(&self.memory_row(addr), width));
+        }
+
+        let queued = self.debugger.machine().console().queued_input().len();
+        let console_title = match queued {
+            0 => "console".to_owned(),
+            queued => format!("console ({} bytes of input queued)", queued),
+        };
+        lines.push(title(&console_title, width));
+        let text = printable(self.debugger.machine().console().output());
+        let text_lines: Vec<&str> = text.split('\n').collect();
+        let skip = text_lines.len().saturating_sub(console);
+        for i in 0..console {
+            lines.push(fit(text_lines.get(skip + i).unwrap_or(&""), width));
+        }
+
+        lines.push(title("output", width));
+        let message: Vec<&str> = self.message.lines().collect();
+        for i in 0..output {
+            let line = match message.get(i) {
+                Some(_) if i + 1 == output && message.len() > output => {
+                    format!("({} more lines)", message.len() - i)
+                }
+                Some(line) => line.to_string(),
+                None => String::new(),
+            };
+            lines.push(fit(&line, width));
+        }
+
+        let status = match &self.mode {
+            Mode::Normal => format!(" {}", KEYS),
+            Mode::Command(line) => format!(":{}_", line),
+            Mode::Input => {
+                let queued = self.debugger.machine().console().queued_input();
+                let queued: Vec<u8> = queued.iter().copied().collect();
+                format!(" input, Esc when done: {}_", printable(&queued))
+            }
+            Mode::Running => {
+                " running, Esc or Ctrl-C to stop, other keys go to the program".to_owned()
+            }
+        };
+        lines.push(inverted(&status, width));
+
+        let mut frame = String::new();
+        for (row, line) in lines.iter().take(height).enumerate() {
+            frame += &format!("{}{}", cursor::Goto(1, row as u16 + 1), line);
+        }
+        self.console().draw(&frame)
+    }
+
+    // the instructions around the PC, with breakpoints marked by * and the PC by >
+    fn disassembly(&self, rows: usize) -> Vec<String> {
+        let machine = self.debugger.machine();
+        let pc = machine.pc();
+        let before = rows / 3;
+        let words = |start: u16| -> Vec<u16> {
+            (0..rows * 2 + before)
+                .map(|i| machine.read_memory(start.wrapping_add(i as u16)))
+                .collect()
+        };
+        // instructions are one or two words long, so look for the earliest start that decodes onto the PC
+        let lines = (0..=before as u16 * 2)
+            .rev()
+            .map(|back| disassemble(&words(pc.wrapping_sub(back)), pc.wrapping_sub(back)))
+            .find(|lines| lines.iter().any(|line| line.addr == pc))
+            .unwrap_or_default();
+        let at_pc = lines.iter().position(|line| line.addr == pc).unwrap_or(0);
+        lines
+            .iter()
+            .skip(at_pc.saturating_sub(before))
+            .take(rows)
+            .map(|line| {
+                let breakpoint = self.debugger.breakpoints().contains(&line.addr);
+                let machine = self.debugger.machine();
+                // the source line goes after the disassembly as a comment
+                let source = match machine.line_map().location(line.addr) {
+                    Some(location) => format!("  ; {}", location),
+                    None => String::new(),
+                };
+                format!(
+                    "{}{} {}{}",
+                    if breakpoint { '*' } else { ' ' },
+                    if line.addr == pc { '>' } else { ' ' },
+                    line.with(machine.symbols()),
+                    source
+                )
+            })
+            .collect()
+    }
+
+    // registers, PSR, NZP flags, ASG states and pending interrupts
+    fn registers(&self) -> Vec<String> {
+        let machine = self.debugger.machine();
+        let psr = machine.psr();
+        let mut lines = vec![
+            format!(" PC  0x{:04x}   PSR 0x{:04x}", machine.pc(), psr.to_word()),
+            format!(
+                " {} mode, priority {}",
+                if psr.supervisor { "supervisor" } else { "user" },
+                psr.priority
+            ),
+        ];
+        let registers = machine.registers();
+        for i in 0..4 {
+            lines.push(format!(
+                " R{} 0x{:04x} {:>6}   R{} 0x{:04x} {:>6}",
+                i,
+                registers[i] as u16,
+                registers[i],
+                i + 4,
+                registers[i + 4] as u16,
+                registers[i + 4]
+            ));
+        }
+        lines.push(format!(" {}", machine.print_nzp().trim_end()));
+        let asg = machine.asg();
+        lines.push(format!(" ASG clock  0x{:04x}", asg.clock.state));
+        lines.push(format!("     first  0x{:04x}", asg.first.state));
+        lines.push(format!("     second 0x{:04x}", asg.second.state));
+        let pending: Vec<String> = machine
+            .interrupts()
+            .pending()
+            .iter()
+            .map(|r| format!("x{:02x} at {}", r.vector, r.priority))
+            .collect();
+        if !pending.is_empty() {
+            lines.push(format!(" pending {}", pending.join(", ")));
+        }
+        lines
+    }
+
+    // words in hex followed by their low bytes as text
+    fn memory_row(&self, addr: u16) -> String {
+        let machine = self.debugger.machine();
+        let words: Vec<u16> = (0..self.memory_row_words)
+            .map(|i| machine.read_memory(addr.wrapping_add(i)))
+            .collect();
+        let mut line = format!("0x{:04x} ", addr);
+        for word in &words {
+            line += &format!(" {:04x}", word);
+        }
+        line += "  ";
+        for word in &words {
+            let byte = *word as u8;
+            line.push(if byte.is_ascii_graphic() || byte == b' ' {
+                byte as char
+            } else {
+                '.'
+            });
+        }
+        line
+    }
+}
+
+// split typed bytes into keys, a lone escape is the Esc key rather than the start of a sequence
+fn parse_keys(bytes: &[u8]) -> Vec<Key> {
+    let mut keys = Vec::new();
+    let mut iter = bytes.iter().map(|b| Ok::<u8, io::Error>(*b)).peekable();
+    while let Some(Ok(byte)) = iter.next() {
+        if byte == ESC && iter.peek().is_none() {
+            keys.push(Key::Esc);
+        } else if let Ok(Event::Key(key)) = parse_event(byte, &mut iter) {
+            keys.push(key);
+        }
+    }
+    keys
+}
+
+// program output without control characters, which would move the cursor out of the pane
+fn printable(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .filter(|b| **b == b'\n' || **b == b'\t' || !b.is_ascii_control())
+        .map(|b| if *b == b'\t' { ' ' } else { *b as char })
+        .collect()
+}
+
+// cut or pad text to exactly `width` columns
+fn fit(text: &str, width: usize) -> String {
+    let mut out: String = text.chars().take(width).collect();
+    let len = out.chars().count();
+    out.extend(std::iter::repeat(' ').take(width - len));
+    out
+}
+
+fn inverted(text: &str, width: usize) -> String {
+    format!("{}{}{}", style::Invert, fit(text, width), style::Reset)
+}
+
+fn title(text: &str, width: usize) -> String {
+    fit(&format!("-- {} {}", text, "-".repeat(width)), width)
+}